@@ -0,0 +1,98 @@
+//! Client side of the firmware's serial console (see src/console.rs in the firmware)
+//!
+//! Commands are sent as a line of text and the firmware answers with whatever the command
+//! prints followed by a "> " prompt, which is how we know the answer is complete.
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// What the firmware prints once it's ready for the next command
+pub const PROMPT: &str = "> ";
+/// How long to wait for an answer
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+pub struct Console {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+impl Console {
+    /// Opens the serial port at *path* (e.g. /dev/ttyACM0 or a pty) and waits for a prompt
+    pub fn open(path: &str) -> io::Result<Console> {
+        let port = serialport::new(path, 115_200)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+        let mut console = Console { port };
+        // Whatever was left over from before (e.g. a half-typed line) gets flushed out by an
+        // empty line which just gets us a prompt
+        console.command("")?;
+        Ok(console)
+    }
+
+    /// Runs a command and returns what it printed (minus the prompt, with plain \n line endings)
+    pub fn command(&mut self, line: &str) -> io::Result<String> {
+        self.send(line)?;
+        let mut answer = self.read_until(line, PROMPT.as_bytes())?;
+        answer.truncate(answer.len() - PROMPT.len());
+        Ok(String::from_utf8_lossy(&answer).replace('\r', ""))
+    }
+
+    /// Sends a command without waiting for the answer
+    pub fn send(&mut self, line: &str) -> io::Result<()> {
+        self.port.write_all(line.as_bytes())?;
+        self.port.write_all(b"\n")?;
+        self.port.flush()
+    }
+
+    /// Reads everything that comes in until it ends with *end* (which is included).  *line* is
+    /// the command being answered (for the error message).
+    pub fn read_until(&mut self, line: &str, end: &[u8]) -> io::Result<Vec<u8>> {
+        let mut answer = Vec::new();
+        let started = Instant::now();
+        let mut buf = [0; 256];
+        while !answer.ends_with(end) {
+            if started.elapsed() > TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no answer to '{}'", line),
+                ));
+            }
+            match self.port.read(&mut buf) {
+                Ok(n) => answer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(answer)
+    }
+
+    /// Reads (raw) everything that comes in for *duration*
+    pub fn read_for(&mut self, duration: Duration) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let started = Instant::now();
+        let mut buf = [0; 4096];
+        while started.elapsed() < duration {
+            match self.port.read(&mut buf) {
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Runs a command that prints "Invalid..."/"Unknown..." when it fails
+    pub fn checked(&mut self, line: &str) -> io::Result<String> {
+        let answer = self.command(line)?;
+        let failed = answer.lines().any(|l| {
+            l.starts_with("Invalid") || l.starts_with("Unknown") || l.starts_with("Missing")
+        });
+        if failed {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}': {}", line, answer.trim()),
+            ))
+        } else {
+            Ok(answer)
+        }
+    }
+}
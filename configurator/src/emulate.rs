@@ -0,0 +1,532 @@
+//! A stand-in for the keyboard's serial console so the configurator can be tried out (and
+//! scripted against) without any hardware.  Hook it up to one end of a pty pair:
+//!
+//! ```sh
+//! socat -d -d pty,raw,echo=0,link=/tmp/board pty,raw,echo=0,link=/tmp/host &
+//! configurator emulate /tmp/board &
+//! configurator --port /tmp/host dump
+//! ```
+//!
+//! It answers the same commands (in the same format) as the firmware's console using the
+//! compiled-in config from userconfig.rs.  Channel values are made up, except for `capture`
+//! and `calibrate` which use the readings of a pretend keyboard (see mock.rs in hall-core) that
+//! has one key (multiplexer 1, channel 3) getting tapped over and over.
+
+use crate::config;
+use crate::config_structs::Config;
+use crate::keycodes::{
+    KC_DEFAULT, QK_BOOT, QK_CALIBRATE, QK_DKS, QK_GAMEPAD, QK_MOD_TAP, QK_MOD_TAP_MAX,
+    QK_MOMENTARY, QK_TAP_DANCE, QK_TAP_DANCE_MAX, QK_TO,
+};
+use crate::keymap::{NUM_COLUMNS, NUM_LAYERS};
+use crate::userconfig::{MAX_CHANNELS, NUM_MULTIPLEXERS};
+use hall_core::calibrate::{Report, TravelCalibration};
+use hall_core::dks::{self, Dks};
+use hall_core::mock::{MockInputs, MockKeyboard, MockMultiplexers};
+use hall_core::multiplexers::TriggerMode;
+use hall_core::socd::{self, Socd};
+use hall_core::thresholds::{KeyThresholds, ThresholdTable};
+use hall_core::trace::{self, Capture, Snapshot};
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// Made-up resting value (in millivolts) for every channel
+const RESTING_MV: u16 = 1500;
+/// How often the pretend keyboard sweeps its channels (and how long the console waits for input)
+const SWEEP_RATE_HZ: u32 = 100;
+/// The key that gets tapped while capturing or calibrating, how far it goes down (mV) and when
+/// (sweeps)
+const TAPPED_KEY: (usize, usize) = (1, 3);
+const TAP_DEPTH_MV: u16 = 400;
+const TAP_PERIOD: u32 = 50;
+const TAP_LENGTH: u32 = 10;
+
+const CALIBRATION_STARTED: &str =
+    "Calibrating: press every key all the way down once (or 'calibrate done' to stop)\r\n";
+
+struct Board {
+    config: Config,
+    keymap: Vec<Vec<Vec<u16>>>,
+    /// Bumped on every read so the values wobble a little like the real thing
+    ticks: u16,
+    capture: Capture,
+    calibration: TravelCalibration,
+    socd: Socd,
+    dks: Dks,
+    keyboard: MockKeyboard,
+    /// Sweeps of the pretend keyboard so far
+    sweeps: u32,
+}
+
+/// Same check as the firmware's keymap::to_action() (minus the keyberon bits)
+fn valid_code(code: u16) -> bool {
+    matches!(code, 0x0000..=0x00A4 | 0x00E0..=0x00E7 | QK_BOOT | QK_CALIBRATE | KC_DEFAULT)
+        || (QK_TO..QK_TO + NUM_LAYERS as u16).contains(&code)
+        || (QK_MOMENTARY..QK_MOMENTARY + NUM_LAYERS as u16).contains(&code)
+        || (QK_GAMEPAD..QK_GAMEPAD + hall_core::gamepad::Input::COUNT as u16).contains(&code)
+        || (QK_DKS..QK_DKS + dks::MAX_SLOTS as u16).contains(&code)
+        || ((QK_MOD_TAP..=QK_MOD_TAP_MAX).contains(&code)
+            && code & 0x0F00 != 0
+            && matches!(code & 0xFF, 0x04..=0xA4 | 0xE0..=0xE7))
+        // Whichever tap dances layers.rs has (there's no knowing how many from here)
+        || (QK_TAP_DANCE..=QK_TAP_DANCE_MAX).contains(&code)
+}
+
+impl Board {
+    fn values(&mut self, mux: usize) -> Vec<u16> {
+        self.ticks = self.ticks.wrapping_add(1);
+        (0..NUM_COLUMNS)
+            .map(|chan| RESTING_MV + (mux * NUM_COLUMNS + chan) as u16 + self.ticks % 3)
+            .collect()
+    }
+
+    fn get(&self, name: &str, out: &mut String) {
+        for section in Config::SECTIONS {
+            for field in Config::field_names(section) {
+                let full = format!("{}.{}", section, field);
+                if name.is_empty() || name == *section || name == full {
+                    if let Some((_, _, field_type, value)) = self.config.get(&full) {
+                        out.push_str(&format!(
+                            "{} = {} ({})\r\n",
+                            full,
+                            value.as_str(),
+                            field_type
+                        ));
+                    }
+                }
+            }
+        }
+        if out.is_empty() {
+            out.push_str("Unknown section/field\r\n");
+        }
+    }
+
+    /// Same text as the firmware's write_calibration_report()
+    fn calibration_report(report: &Report, out: &mut String) {
+        out.push_str(&format!(
+            "Calibration finished: {} key(s) calibrated\r\n",
+            report.calibrated_count()
+        ));
+        let failed: Vec<String> = report
+            .failed_keys()
+            .map(|(multi, chan)| format!(" {}/{}", multi, chan))
+            .collect();
+        if !failed.is_empty() {
+            out.push_str("Not calibrated (didn't move far enough; mux/chan):");
+            out.push_str(&failed.concat());
+            out.push_str("\r\n");
+        }
+    }
+
+    /// Same text as the firmware's write_threshold()
+    fn threshold(mux: usize, chan: usize, thresholds: &ThresholdTable, out: &mut String) {
+        out.push_str(&format!(
+            "Threshold {} {} = {} {}",
+            mux,
+            chan,
+            thresholds.actuation(mux, chan),
+            thresholds.release(mux, chan)
+        ));
+        if thresholds.get(mux, chan) == Some(&KeyThresholds::default()) {
+            out.push_str(" (global)");
+        }
+        out.push_str("\r\n");
+    }
+
+    /// Same text as the firmware's write_dks_slot()
+    fn dks_slot(slot: usize, steps: &dks::Slot, out: &mut String) {
+        out.push_str(&format!("DKS {}:", slot));
+        for point in dks::Point::all() {
+            out.push_str(&format!(" {}={}", point.name(), steps[point as usize]));
+        }
+        out.push_str("\r\n");
+    }
+
+    /// Runs a line of input and returns what the firmware would print (prompt included)
+    fn run(&mut self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |i: usize| words.get(i).and_then(|w| w.parse::<usize>().ok());
+        let mut out = String::new();
+        match words.first().copied() {
+            None => {}
+            Some("help") | Some("?") => out.push_str(concat!(
+                "Commands: states values travel get set recal layer key threshold trigger mode socd ",
+                "dks calibrate ",
+                "capture bootloader\r\n"
+            )),
+            Some("travel") => {
+                // The made-up values never go anywhere
+                for mux in 0..NUM_MULTIPLEXERS {
+                    if number(1).is_none_or(|m| m == mux) {
+                        out.push_str(&mux.to_string());
+                        out.push_str(&" 0".repeat(NUM_COLUMNS));
+                        out.push_str("\r\n");
+                    }
+                }
+            }
+            Some(command @ ("states" | "values")) => {
+                for mux in 0..NUM_MULTIPLEXERS {
+                    if number(1).is_none_or(|m| m == mux) {
+                        let values = self.values(mux);
+                        if command == "values" {
+                            out.push_str(&mux.to_string());
+                            for value in values {
+                                out.push_str(&format!(" {}", value));
+                            }
+                            out.push_str("\r\n");
+                        } else {
+                            out.push_str(&format!("Multiplexer {}:\r\n", mux));
+                            for (chan, value) in values.iter().enumerate() {
+                                out.push_str(&format!("  {:2}: {}\r\n", chan, value));
+                            }
+                        }
+                    }
+                }
+            }
+            Some("get") => self.get(words.get(1).copied().unwrap_or(""), &mut out),
+            Some("set") => match (words.get(1), words.get(2)) {
+                (Some(name), Some(value)) => match self.config.set(name, value) {
+                    Ok(()) => out.push_str(&format!("{} = {}\r\n", name, value)),
+                    Err(crate::config_structs::ConfigError::UnknownField) => {
+                        out.push_str("Unknown field\r\n")
+                    }
+                    Err(crate::config_structs::ConfigError::InvalidValue) => {
+                        out.push_str("Invalid value\r\n")
+                    }
+                    Err(crate::config_structs::ConfigError::OutOfRange) => {
+                        let (min, max) = config::limits(name).unwrap_or_default();
+                        out.push_str(&format!("Out of range ({} to {})\r\n", min, max))
+                    }
+                },
+                _ => out.push_str("Missing argument (try 'help')\r\n"),
+            },
+            Some("recal") => out.push_str("Recalibrated\r\n"),
+            Some("layer") => match number(1) {
+                Some(layer) => out.push_str(&format!("Default layer: {}\r\n", layer)),
+                None => out.push_str("Invalid argument (try 'help')\r\n"),
+            },
+            Some("key") => match (number(1), number(2), number(3)) {
+                (Some(layer), Some(mux), Some(chan)) => {
+                    let code = words.get(4).map(|w| match w.strip_prefix("0x") {
+                        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+                        None => w.parse().ok(),
+                    });
+                    let key = self
+                        .keymap
+                        .get_mut(layer)
+                        .and_then(|rows| rows.get_mut(mux))
+                        .and_then(|row| row.get_mut(chan));
+                    match (key, code) {
+                        (Some(key), Some(Some(code))) if valid_code(code) => {
+                            *key = code;
+                            out.push_str(&format!(
+                                "Key {} {} {} = 0x{:04X}\r\n",
+                                layer, mux, chan, code
+                            ));
+                        }
+                        (Some(key), None) => {
+                            out.push_str(&format!(
+                                "Key {} {} {} = 0x{:04X}\r\n",
+                                layer, mux, chan, key
+                            ));
+                        }
+                        (Some(_), Some(_)) => out.push_str("Invalid key or code\r\n"),
+                        (None, _) => out.push_str("Invalid key\r\n"),
+                    }
+                }
+                _ => out.push_str("Invalid argument (try 'help')\r\n"),
+            },
+            Some("threshold") => {
+                let thresholds = &mut self.keyboard.thresholds;
+                let change = match (words.get(3).copied(), number(3), number(4)) {
+                    (None, ..) => Some(None),
+                    (Some("off"), ..) => Some(Some(KeyThresholds::default())),
+                    (_, Some(actuation), Some(release)) if release < actuation => {
+                        u16::try_from(actuation).ok().map(|actuation| {
+                            Some(KeyThresholds {
+                                actuation: Some(actuation),
+                                release: Some(release as u16),
+                            })
+                        })
+                    }
+                    _ => None,
+                };
+                match (number(1), number(2), change) {
+                    (Some(mux), Some(chan), Some(change)) => {
+                        let valid = match change {
+                            Some(change) => thresholds.set(mux, chan, change),
+                            None => thresholds.get(mux, chan).is_some(),
+                        };
+                        if valid {
+                            Board::threshold(mux, chan, thresholds, &mut out);
+                        } else {
+                            out.push_str("Invalid key\r\n");
+                        }
+                    }
+                    _ if words.len() < 3 || (words.len() == 4 && number(3).is_some()) => {
+                        out.push_str("Missing argument (try 'help')\r\n")
+                    }
+                    _ => out.push_str("Invalid argument (try 'help')\r\n"),
+                }
+            }
+            Some("trigger") => {
+                let change = match words.get(3) {
+                    None => Some(None),
+                    Some(name) => TriggerMode::from_name(name).map(Some),
+                };
+                match (number(1), number(2), change) {
+                    (Some(mux), Some(chan), Some(change)) => {
+                        match self.keyboard.ch_states.get_mut(mux) {
+                            Some(states) if chan < MAX_CHANNELS => {
+                                if let Some(mode) = change {
+                                    states.update_mode_by_index(chan, mode);
+                                }
+                                out.push_str(&format!(
+                                    "Trigger {} {} = {}\r\n",
+                                    mux,
+                                    chan,
+                                    states.states[chan].mode.name()
+                                ));
+                            }
+                            _ => out.push_str("Invalid key\r\n"),
+                        }
+                    }
+                    _ if words.len() < 3 => out.push_str("Missing argument (try 'help')\r\n"),
+                    _ => out.push_str("Invalid argument (try 'help')\r\n"),
+                }
+            }
+            Some("mode") => match words.get(1).copied() {
+                Some("6kro") => {
+                    self.config.keyboard.report_mode = 0;
+                    out.push_str("Report mode: Boot6Kro\r\n");
+                }
+                Some("nkro") => {
+                    self.config.keyboard.report_mode = 1;
+                    out.push_str("Report mode: Nkro\r\n");
+                }
+                _ => out.push_str("Invalid argument (try 'help')\r\n"),
+            },
+            Some("capture") => match words.get(1).copied() {
+                Some("off") => {
+                    self.capture.stop();
+                    out.push_str("Capture stopped\r\n");
+                }
+                Some(_) => match number(1).filter(|every| *every > 0) {
+                    Some(every) => {
+                        self.capture.start(every as u32);
+                        out.push_str(&format!("Capturing every {} sweep(s)\r\n", every));
+                    }
+                    None => out.push_str("Invalid argument (try 'help')\r\n"),
+                },
+                None => out.push_str("Missing argument (try 'help')\r\n"),
+            },
+            Some("socd") if words.len() == 1 => {
+                for pair in self.socd.pairs().iter().flatten() {
+                    let [(mux1, chan1), (mux2, chan2)] = pair.keys;
+                    out.push_str(&format!(
+                        "SOCD {} {} {} {} = {}\r\n",
+                        mux1,
+                        chan1,
+                        mux2,
+                        chan2,
+                        pair.mode.name()
+                    ));
+                }
+                if out.is_empty() {
+                    out.push_str("No SOCD pairs\r\n");
+                }
+            }
+            Some("socd") => match (number(1), number(2), number(3), number(4), words.get(5)) {
+                (Some(mux1), Some(chan1), Some(mux2), Some(chan2), Some(&mode)) => {
+                    let (first, second) = ((mux1, chan1), (mux2, chan2));
+                    let result = match (mode, socd::Mode::from_name(mode)) {
+                        ("off", _) if self.socd.remove(first) | self.socd.remove(second) => Ok(()),
+                        ("off", _) => Err("Invalid pair (those keys aren't paired)"),
+                        (_, Some(mode)) => {
+                            let pair = socd::Pair {
+                                keys: [first, second],
+                                mode,
+                            };
+                            self.socd
+                                .set(pair, &self.keyboard.ch_states)
+                                .map_err(|e| match e {
+                                    socd::SocdError::InvalidKey => "Invalid key",
+                                    socd::SocdError::Full => "Invalid pair (no room for any more)",
+                                })
+                        }
+                        (_, None) => Err("Invalid argument (try 'help')"),
+                    };
+                    match result {
+                        Ok(()) => out.push_str(&format!(
+                            "SOCD {} {} {} {} = {}\r\n",
+                            mux1, chan1, mux2, chan2, mode
+                        )),
+                        Err(message) => out.push_str(&format!("{}\r\n", message)),
+                    }
+                }
+                _ if words.len() < 6 => out.push_str("Missing argument (try 'help')\r\n"),
+                _ => out.push_str("Invalid argument (try 'help')\r\n"),
+            },
+            Some("dks") if words.len() == 1 => {
+                for (slot, steps) in self.dks.slots().iter().enumerate() {
+                    if steps.iter().any(|step| step.op != dks::Op::None) {
+                        Board::dks_slot(slot, steps, &mut out);
+                    }
+                }
+                if out.is_empty() {
+                    out.push_str("No DKS slots set up\r\n");
+                }
+            }
+            Some("dks") => {
+                let point = words.get(2).and_then(|w| dks::Point::from_name(w));
+                let op = words.get(3).and_then(|w| dks::Op::from_name(w));
+                let key = match (op, words.get(4)) {
+                    (Some(dks::Op::None), _) => Some(0),
+                    (_, Some(w)) => match w.strip_prefix("0x") {
+                        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+                        None => w.parse().ok(),
+                    }
+                    .filter(|key| dks::valid_key(*key)),
+                    (_, None) => None,
+                };
+                match (number(1), point, op, key) {
+                    (Some(slot), Some(point), Some(op), Some(key)) => {
+                        let step = dks::Step { op, key: key as u8 };
+                        if self.dks.set(slot, point, step) {
+                            Board::dks_slot(slot, &self.dks.slots()[slot], &mut out);
+                        } else {
+                            out.push_str("Invalid slot\r\n");
+                        }
+                    }
+                    _ if words.len() < 4 => out.push_str("Missing argument (try 'help')\r\n"),
+                    _ => out.push_str("Invalid argument (try 'help')\r\n"),
+                }
+            }
+            Some("calibrate") => match words.get(1).copied() {
+                None => {
+                    self.calibration.start(&mut self.keyboard.ch_states);
+                    out.push_str(CALIBRATION_STARTED);
+                }
+                Some("done" | "cancel") if !self.calibration.is_running() => {
+                    out.push_str("No calibration running\r\n")
+                }
+                Some("done") => {
+                    let min_swing = self.config.keyboard.calibration_min_swing;
+                    let report = self
+                        .calibration
+                        .finish(&mut self.keyboard.ch_states, min_swing);
+                    Board::calibration_report(&report, &mut out);
+                }
+                Some("cancel") => {
+                    self.calibration.cancel();
+                    out.push_str("Calibration cancelled\r\n");
+                }
+                Some(_) => out.push_str("Invalid argument (try 'help')\r\n"),
+            },
+            Some("bootloader") => out.push_str("Rebooting into the bootloader...\r\n"),
+            Some(_) => out.push_str("Unknown command (try 'help')\r\n"),
+        }
+        out.push_str("> ");
+        if self.capture.is_running() {
+            // Whatever the command was it might've changed something
+            self.capture.request_snapshot();
+        }
+        out
+    }
+
+    /// Sweeps the pretend keyboard (if anything needs it) and returns what the firmware would
+    /// send for it: the records of a capture and/or the end of a calibration
+    fn sweep(&mut self, mux: &mut MockMultiplexers, adc: &mut MockInputs) -> Vec<u8> {
+        let mut out = Vec::new();
+        let capture = self.capture.sweep();
+        if !self.capture.is_running() && !self.calibration.is_running() {
+            return out;
+        }
+        let keyboard = &mut self.keyboard;
+        if let Some((tick, true)) = capture {
+            let snapshot = Snapshot::capture(
+                tick,
+                SWEEP_RATE_HZ,
+                self.capture.every(),
+                &keyboard.ch_states,
+                &keyboard.thresholds,
+                &keyboard.config.keyboard,
+                config::ENCODER_PRESS_THRESHOLD,
+            );
+            let mut buf = [0; trace::SNAPSHOT_SIZE];
+            let len = snapshot.encode(&mut buf);
+            out.extend_from_slice(&buf[..len]);
+            self.capture.snapshot_sent();
+        }
+        let (multi, chan) = TAPPED_KEY;
+        let tapped = self.sweeps % TAP_PERIOD < TAP_LENGTH;
+        self.sweeps = self.sweeps.wrapping_add(1);
+        adc.set(
+            multi,
+            chan,
+            RESTING_MV - if tapped { TAP_DEPTH_MV } else { 0 },
+        );
+        keyboard.scan(mux, adc, |_| {});
+        if let Some((tick, _)) = capture {
+            let mut buf = [0; trace::FRAME_SIZE];
+            let len = trace::encode_frame(tick, &keyboard.frame, &mut buf);
+            out.extend_from_slice(&buf[..len]);
+        }
+        let (min_swing, timeout) = (
+            self.config.keyboard.calibration_min_swing,
+            self.config.keyboard.calibration_timeout,
+        );
+        if self
+            .calibration
+            .sweep(&keyboard.ch_states, min_swing, timeout * SWEEP_RATE_HZ)
+        {
+            let report = self.calibration.finish(&mut keyboard.ch_states, min_swing);
+            let mut text = String::new();
+            Board::calibration_report(&report, &mut text);
+            out.extend_from_slice(text.as_bytes());
+        }
+        out
+    }
+}
+
+/// Pretends to be the keyboard on the serial port (or pty) at *path* until it goes away
+pub fn run(path: &str) -> io::Result<()> {
+    let mut port = serialport::new(path, 115_200)
+        .timeout(Duration::from_millis(1000 / SWEEP_RATE_HZ as u64))
+        .open()?;
+    let selected = Cell::new(0);
+    let mut mux = MockMultiplexers::new(&selected);
+    let mut adc = MockInputs::new(&selected, RESTING_MV);
+    let keyboard = MockKeyboard::new(&mut mux, &mut adc);
+    let mut board = Board {
+        config: config::from_userconfig(),
+        keymap: vec![vec![vec![KC_DEFAULT; NUM_COLUMNS]; NUM_MULTIPLEXERS]; NUM_LAYERS],
+        ticks: 0,
+        capture: Capture::default(),
+        calibration: TravelCalibration::default(),
+        socd: Socd::new(socd::default_pairs(), &keyboard.ch_states),
+        dks: Dks::default(),
+        keyboard,
+        sweeps: 0,
+    };
+    let mut line = Vec::new();
+    let mut buf = [0; 256];
+    loop {
+        let n = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => 0,
+            Err(e) => return Err(e),
+        };
+        port.write_all(&board.sweep(&mut mux, &mut adc))?;
+        for &byte in &buf[..n] {
+            if byte == b'\r' || byte == b'\n' {
+                let answer = board.run(&String::from_utf8_lossy(&line));
+                port.write_all(answer.as_bytes())?;
+                line.clear();
+            } else {
+                line.push(byte);
+            }
+        }
+    }
+}
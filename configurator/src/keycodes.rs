@@ -0,0 +1,285 @@
+//! Names for the keymap codes (see src/keymap.rs in the firmware) so keymap files can say
+//! "KC_A" or "MO(1)" instead of 0x0004 or 0x5221
+
+pub const KC_NO: u16 = 0x0000;
+pub const KC_TRNS: u16 = 0x0001;
+pub const QK_MOD_TAP: u16 = 0x2000;
+pub const QK_MOD_TAP_MAX: u16 = 0x3FFF;
+pub const QK_TO: u16 = 0x5200;
+pub const QK_MOMENTARY: u16 = 0x5220;
+pub const QK_TAP_DANCE: u16 = 0x5700;
+pub const QK_TAP_DANCE_MAX: u16 = 0x57FF;
+pub const QK_BOOT: u16 = 0x7C00;
+pub const QK_CALIBRATE: u16 = 0x7E00;
+pub const QK_GAMEPAD: u16 = 0x7E10;
+pub const QK_DKS: u16 = 0x7E20;
+pub const KC_DEFAULT: u16 = 0xFFFF;
+/// Highest layer number that fits in a TO()/MO() code
+const MAX_LAYER: u16 = 0x1F;
+/// Number of DKS slots (see hall_core::dks)
+const DKS_SLOTS: u16 = hall_core::dks::MAX_SLOTS as u16;
+
+/// Names of QMK's modifier bits (as in MOD_LCTL; bit 4 makes them the right-hand ones)
+const MODS: [&str; 4] = ["CTL", "SFT", "ALT", "GUI"];
+
+/// Names of the gamepad codes (QK_GAMEPAD onwards; see hall_core::gamepad::Input)
+const GAMEPAD: &[&str] = &[
+    "GP_LS_LEFT",
+    "GP_LS_RIGHT",
+    "GP_LS_UP",
+    "GP_LS_DOWN",
+    "GP_RS_LEFT",
+    "GP_RS_RIGHT",
+    "GP_RS_UP",
+    "GP_RS_DOWN",
+    "GP_LT",
+    "GP_RT",
+];
+
+/// Names of the HID keyboard usages (QMK naming)
+const USAGES: &[(u16, &str)] = &[
+    (0x04, "KC_A"),
+    (0x05, "KC_B"),
+    (0x06, "KC_C"),
+    (0x07, "KC_D"),
+    (0x08, "KC_E"),
+    (0x09, "KC_F"),
+    (0x0A, "KC_G"),
+    (0x0B, "KC_H"),
+    (0x0C, "KC_I"),
+    (0x0D, "KC_J"),
+    (0x0E, "KC_K"),
+    (0x0F, "KC_L"),
+    (0x10, "KC_M"),
+    (0x11, "KC_N"),
+    (0x12, "KC_O"),
+    (0x13, "KC_P"),
+    (0x14, "KC_Q"),
+    (0x15, "KC_R"),
+    (0x16, "KC_S"),
+    (0x17, "KC_T"),
+    (0x18, "KC_U"),
+    (0x19, "KC_V"),
+    (0x1A, "KC_W"),
+    (0x1B, "KC_X"),
+    (0x1C, "KC_Y"),
+    (0x1D, "KC_Z"),
+    (0x1E, "KC_1"),
+    (0x1F, "KC_2"),
+    (0x20, "KC_3"),
+    (0x21, "KC_4"),
+    (0x22, "KC_5"),
+    (0x23, "KC_6"),
+    (0x24, "KC_7"),
+    (0x25, "KC_8"),
+    (0x26, "KC_9"),
+    (0x27, "KC_0"),
+    (0x28, "KC_ENTER"),
+    (0x29, "KC_ESCAPE"),
+    (0x2A, "KC_BACKSPACE"),
+    (0x2B, "KC_TAB"),
+    (0x2C, "KC_SPACE"),
+    (0x2D, "KC_MINUS"),
+    (0x2E, "KC_EQUAL"),
+    (0x2F, "KC_LEFT_BRACKET"),
+    (0x30, "KC_RIGHT_BRACKET"),
+    (0x31, "KC_BACKSLASH"),
+    (0x32, "KC_NONUS_HASH"),
+    (0x33, "KC_SEMICOLON"),
+    (0x34, "KC_QUOTE"),
+    (0x35, "KC_GRAVE"),
+    (0x36, "KC_COMMA"),
+    (0x37, "KC_DOT"),
+    (0x38, "KC_SLASH"),
+    (0x39, "KC_CAPS_LOCK"),
+    (0x3A, "KC_F1"),
+    (0x3B, "KC_F2"),
+    (0x3C, "KC_F3"),
+    (0x3D, "KC_F4"),
+    (0x3E, "KC_F5"),
+    (0x3F, "KC_F6"),
+    (0x40, "KC_F7"),
+    (0x41, "KC_F8"),
+    (0x42, "KC_F9"),
+    (0x43, "KC_F10"),
+    (0x44, "KC_F11"),
+    (0x45, "KC_F12"),
+    (0x46, "KC_PRINT_SCREEN"),
+    (0x47, "KC_SCROLL_LOCK"),
+    (0x48, "KC_PAUSE"),
+    (0x49, "KC_INSERT"),
+    (0x4A, "KC_HOME"),
+    (0x4B, "KC_PAGE_UP"),
+    (0x4C, "KC_DELETE"),
+    (0x4D, "KC_END"),
+    (0x4E, "KC_PAGE_DOWN"),
+    (0x4F, "KC_RIGHT"),
+    (0x50, "KC_LEFT"),
+    (0x51, "KC_DOWN"),
+    (0x52, "KC_UP"),
+    (0x53, "KC_NUM_LOCK"),
+    (0x54, "KC_KP_SLASH"),
+    (0x55, "KC_KP_ASTERISK"),
+    (0x56, "KC_KP_MINUS"),
+    (0x57, "KC_KP_PLUS"),
+    (0x58, "KC_KP_ENTER"),
+    (0x59, "KC_KP_1"),
+    (0x5A, "KC_KP_2"),
+    (0x5B, "KC_KP_3"),
+    (0x5C, "KC_KP_4"),
+    (0x5D, "KC_KP_5"),
+    (0x5E, "KC_KP_6"),
+    (0x5F, "KC_KP_7"),
+    (0x60, "KC_KP_8"),
+    (0x61, "KC_KP_9"),
+    (0x62, "KC_KP_0"),
+    (0x63, "KC_KP_DOT"),
+    (0x64, "KC_NONUS_BACKSLASH"),
+    (0x65, "KC_APPLICATION"),
+    (0x66, "KC_KB_POWER"),
+    (0x67, "KC_KP_EQUAL"),
+    (0x68, "KC_F13"),
+    (0x69, "KC_F14"),
+    (0x6A, "KC_F15"),
+    (0x6B, "KC_F16"),
+    (0x6C, "KC_F17"),
+    (0x6D, "KC_F18"),
+    (0x6E, "KC_F19"),
+    (0x6F, "KC_F20"),
+    (0x70, "KC_F21"),
+    (0x71, "KC_F22"),
+    (0x72, "KC_F23"),
+    (0x73, "KC_F24"),
+    (0x7F, "KC_KB_MUTE"),
+    (0x80, "KC_KB_VOLUME_UP"),
+    (0x81, "KC_KB_VOLUME_DOWN"),
+    (0xE0, "KC_LEFT_CTRL"),
+    (0xE1, "KC_LEFT_SHIFT"),
+    (0xE2, "KC_LEFT_ALT"),
+    (0xE3, "KC_LEFT_GUI"),
+    (0xE4, "KC_RIGHT_CTRL"),
+    (0xE5, "KC_RIGHT_SHIFT"),
+    (0xE6, "KC_RIGHT_ALT"),
+    (0xE7, "KC_RIGHT_GUI"),
+];
+
+/// Names the 5-bit modifiers of a mod-tap code (e.g. "MOD_LCTL | MOD_LSFT")
+fn mod_names(mods: u16) -> String {
+    let side = if mods & 0x10 != 0 { "R" } else { "L" };
+    let names: Vec<String> = (0..MODS.len())
+        .filter(|bit| mods & (1 << bit) != 0)
+        .map(|bit| format!("MOD_{}{}", side, MODS[bit]))
+        .collect();
+    names.join(" | ")
+}
+
+/// Parses modifiers named like mod_names() does
+fn parse_mods(text: &str) -> Option<u16> {
+    let mut mods = 0;
+    for name in text.split('|') {
+        let name = name.trim().strip_prefix("MOD_")?;
+        let (side, name) = name.split_at_checked(1)?;
+        let bit = MODS.iter().position(|m| *m == name)?;
+        mods |= match side {
+            "L" => 1 << bit,
+            "R" => 0x10 | 1 << bit,
+            _ => return None,
+        };
+    }
+    Some(mods)
+}
+
+/// Returns the name for *code* (or its hex value if it doesn't have one)
+pub fn name(code: u16) -> String {
+    match code {
+        KC_NO => "KC_NO".into(),
+        KC_TRNS => "KC_TRNS".into(),
+        QK_BOOT => "QK_BOOT".into(),
+        QK_CALIBRATE => "QK_CALIBRATE".into(),
+        KC_DEFAULT => "DEFAULT".into(),
+        c if (QK_TO..=QK_TO + MAX_LAYER).contains(&c) => format!("TO({})", c - QK_TO),
+        c if (QK_MOMENTARY..=QK_MOMENTARY + MAX_LAYER).contains(&c) => {
+            format!("MO({})", c - QK_MOMENTARY)
+        }
+        c if (QK_GAMEPAD..QK_GAMEPAD + GAMEPAD.len() as u16).contains(&c) => {
+            GAMEPAD[(c - QK_GAMEPAD) as usize].into()
+        }
+        c if (QK_DKS..QK_DKS + DKS_SLOTS).contains(&c) => format!("DKS_{}", c - QK_DKS),
+        c if (QK_TAP_DANCE..=QK_TAP_DANCE_MAX).contains(&c) => format!("TD({})", c - QK_TAP_DANCE),
+        c if (QK_MOD_TAP..=QK_MOD_TAP_MAX).contains(&c) && c & 0x0F00 != 0 => {
+            format!("MT({}, {})", mod_names(c >> 8 & 0x1F), name(c & 0xFF))
+        }
+        c => USAGES
+            .iter()
+            .find(|(usage, _)| *usage == c)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| format!("0x{:04X}", c)),
+    }
+}
+
+/// Parses a layer function like "MO(1)" (with *prefix* being "MO(")
+fn layer_function(text: &str, prefix: &str, base: u16) -> Option<u16> {
+    let layer: u16 = text
+        .strip_prefix(prefix)?
+        .strip_suffix(')')?
+        .trim()
+        .parse()
+        .ok()?;
+    (layer <= MAX_LAYER).then_some(base + layer)
+}
+
+/// Parses a code given by name (with or without the KC_ prefix), layer function or number
+pub fn parse(text: &str) -> Option<u16> {
+    let text = text.trim();
+    let upper = text.to_ascii_uppercase();
+    if let Some(hex) = upper.strip_prefix("0X") {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    if let Ok(code) = text.parse() {
+        return Some(code);
+    }
+    match upper.as_str() {
+        "KC_NO" | "NO" | "XXXXXXX" => return Some(KC_NO),
+        "KC_TRNS" | "TRNS" | "_______" => return Some(KC_TRNS),
+        "QK_BOOT" | "RESET" => return Some(QK_BOOT),
+        "QK_CALIBRATE" | "QK_KB_0" => return Some(QK_CALIBRATE),
+        "DEFAULT" => return Some(KC_DEFAULT),
+        _ => {}
+    }
+    if let Some(code) = layer_function(&upper, "TO(", QK_TO) {
+        return Some(code);
+    }
+    if let Some(code) = layer_function(&upper, "MO(", QK_MOMENTARY) {
+        return Some(code);
+    }
+    if let Some(index) = GAMEPAD.iter().position(|name| *name == upper) {
+        return Some(QK_GAMEPAD + index as u16);
+    }
+    if let Some(args) = upper.strip_prefix("MT(").and_then(|a| a.strip_suffix(')')) {
+        let (mods, tap) = args.rsplit_once(',')?;
+        let (mods, tap) = (parse_mods(mods)?, parse(tap)?);
+        return (mods & 0x0F != 0 && tap <= 0xFF).then_some(QK_MOD_TAP | mods << 8 | tap);
+    }
+    if let Some(index) = upper
+        .strip_prefix("TD(")
+        .and_then(|a| a.strip_suffix(')'))
+        .and_then(|n| n.trim().parse::<u16>().ok())
+    {
+        return (index <= QK_TAP_DANCE_MAX - QK_TAP_DANCE).then_some(QK_TAP_DANCE + index);
+    }
+    if let Some(slot) = upper
+        .strip_prefix("DKS_")
+        .and_then(|n| n.parse::<u16>().ok())
+    {
+        return (slot < DKS_SLOTS).then_some(QK_DKS + slot);
+    }
+    let with_prefix = if upper.starts_with("KC_") {
+        upper
+    } else {
+        format!("KC_{}", upper)
+    };
+    USAGES
+        .iter()
+        .find(|(_, name)| *name == with_prefix)
+        .map(|(usage, _)| *usage)
+}
@@ -0,0 +1,88 @@
+//! Keymap files: every key of every layer by name (see keycodes.rs), as TOML or JSON
+//!
+//! ```toml
+//! layers = [
+//!     [   # Layer 0
+//!         ["KC_ESCAPE", "KC_1", ...],   # Multiplexer 0, channels 0-15
+//!         ...
+//!     ],
+//!     ...
+//! ]
+//! ```
+
+use crate::keycodes;
+use crate::userconfig::NUM_MULTIPLEXERS;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// Same as the firmware's keymap::NUM_LAYERS
+pub const NUM_LAYERS: usize = 7;
+/// Same as the firmware's keymap::NUM_COLUMNS
+pub const NUM_COLUMNS: usize = 16;
+
+/// Every key's code by [layer][mux][channel]
+pub type Codes = Vec<Vec<Vec<u16>>>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeymapFile {
+    pub layers: Vec<Vec<Vec<String>>>,
+}
+
+impl KeymapFile {
+    pub fn from_codes(codes: &Codes) -> KeymapFile {
+        KeymapFile {
+            layers: codes
+                .iter()
+                .map(|rows| {
+                    rows.iter()
+                        .map(|row| row.iter().map(|code| keycodes::name(*code)).collect())
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// Turns the names back into codes, making sure there's a key for every position
+    pub fn to_codes(&self) -> Result<Codes, Box<dyn Error>> {
+        if self.layers.len() != NUM_LAYERS {
+            return Err(
+                format!("expected {} layers, got {}", NUM_LAYERS, self.layers.len()).into(),
+            );
+        }
+        let mut codes = Codes::new();
+        for (layer, rows) in self.layers.iter().enumerate() {
+            if rows.len() != NUM_MULTIPLEXERS {
+                return Err(format!("layer {}: expected {} rows", layer, NUM_MULTIPLEXERS).into());
+            }
+            let mut layer_codes = Vec::new();
+            for (mux, row) in rows.iter().enumerate() {
+                if row.len() != NUM_COLUMNS {
+                    return Err(format!(
+                        "layer {} row {}: expected {} keys",
+                        layer, mux, NUM_COLUMNS
+                    )
+                    .into());
+                }
+                let row_codes = row
+                    .iter()
+                    .map(|name| {
+                        keycodes::parse(name).ok_or_else(|| {
+                            format!("layer {} row {}: unknown key '{}'", layer, mux, name)
+                        })
+                    })
+                    .collect::<Result<Vec<u16>, String>>()?;
+                layer_codes.push(row_codes);
+            }
+            codes.push(layer_codes);
+        }
+        Ok(codes)
+    }
+}
+
+/// Every (layer, mux, channel) position in the keymap
+pub fn positions() -> impl Iterator<Item = (usize, usize, usize)> {
+    (0..NUM_LAYERS).flat_map(|layer| {
+        (0..NUM_MULTIPLEXERS)
+            .flat_map(move |mux| (0..NUM_COLUMNS).map(move |chan| (layer, mux, chan)))
+    })
+}
@@ -0,0 +1,422 @@
+//! Configures the keyboard from a PC: reads/writes the runtime config, uploads keymaps and
+//! watches the live channel values.  Everything goes over the firmware's serial console (see
+//! src/console.rs in the firmware) except keymaps which can also go over VIA (raw HID).
+//! Raw readings can also be captured to a file and played back later (see replay.rs).
+//!
+//! Since this runs on the host it needs to be built for it, e.g.:
+//!
+//! ```sh
+//! cargo run -p configurator --target x86_64-unknown-linux-gnu -- --port /dev/ttyACM0 dump
+//! ```
+
+mod console;
+mod emulate;
+mod keycodes;
+mod keymap;
+mod replay;
+mod via;
+
+// The firmware's config structs (and the compile-time defaults for the emulator) get used as-is
+use hall_core::{config, config_structs, userconfig};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use config_structs::Config;
+use console::Console;
+use hall_core::report::ReportMode;
+use keymap::{Codes, KeymapFile};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// The keyboard's serial console (e.g. /dev/ttyACM0)
+    #[arg(short, long, default_value = "/dev/ttyACM0")]
+    port: String,
+    /// Use the keyboard's raw HID interface (e.g. /dev/hidraw3) for keymaps
+    #[arg(long)]
+    hid: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show config values (everything, a section or a single section.field)
+    Get { name: Option<String> },
+    /// Change a config value
+    Set { name: String, value: String },
+    /// Save the whole config to a file (or print it)
+    Dump {
+        #[arg(short, long, value_enum, default_value_t = Format::Toml)]
+        format: Format,
+        file: Option<String>,
+    },
+    /// Apply a config file (TOML or JSON; only the fields it has, only the ones that differ)
+    Load { file: String },
+    /// Save the keymap to a file (or print it)
+    KeymapGet {
+        #[arg(short, long, value_enum, default_value_t = Format::Toml)]
+        format: Format,
+        file: Option<String>,
+    },
+    /// Upload a keymap file (TOML or JSON; only the keys that differ get sent)
+    KeymapSet { file: String },
+    /// Show the channel values of all (or one) multiplexer(s)
+    States { mux: Option<usize> },
+    /// Keep showing the channel values
+    Watch {
+        /// Milliseconds between updates
+        #[arg(short, long, default_value_t = 250)]
+        interval: u64,
+        #[arg(short, long)]
+        mux: Option<usize>,
+    },
+    /// Use the current values of all released keys as their resting values
+    Calibrate {
+        /// Press every key all the way down once instead (learns where they bottom out)
+        #[arg(long)]
+        full: bool,
+    },
+    /// Show a key's actuation/release thresholds, or override them (off = use the global ones)
+    Threshold {
+        /// <mux> <chan> [<actuation> <release>|off]
+        args: Vec<String>,
+    },
+    /// Show how a key triggers, or switch it between thresholds and rapid trigger
+    Trigger {
+        /// <mux> <chan> [threshold|rapid]
+        args: Vec<String>,
+    },
+    /// Show the SOCD pairs, or pair up two keys so only one of them is down at a time
+    Socd {
+        /// <mux> <chan> <mux> <chan> <last|first|neutral|deeper|off>
+        args: Vec<String>,
+    },
+    /// Show the DKS slots, or change what one does at a point of the key's travel
+    Dks {
+        /// <slot> <press|bottom|lift|release> <down|up|tap|none> [key] (e.g. KC_SPACE or 0x2C)
+        args: Vec<String>,
+    },
+    /// Record the raw readings of every key to a file (for `replay`)
+    Capture {
+        file: String,
+        /// Only record every nth sweep (anything but 1 makes playback approximate)
+        #[arg(short, long, default_value_t = 1)]
+        every: u32,
+        /// How long to record for
+        #[arg(short, long, default_value_t = 10.0)]
+        seconds: f64,
+    },
+    /// Play back a recording, showing every key press/release and HID report
+    Replay {
+        file: String,
+        /// Keymap file (as saved by keymap-get) to work out the HID reports with instead of
+        /// the compiled-in one
+        #[arg(short, long)]
+        keymap: Option<String>,
+        /// Interface to work out the HID reports for (whatever userconfig.rs says if not given)
+        #[arg(short, long, value_enum)]
+        report: Option<Report>,
+    },
+    /// Pretend to be the keyboard on the given serial port/pty (see emulate.rs)
+    Emulate { path: String },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Toml,
+    Json,
+}
+
+/// The keyboard interfaces (see report.rs in hall-core)
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Report {
+    Boot,
+    Nkro,
+}
+
+impl Format {
+    /// Picks the format by file extension (TOML unless it ends in .json)
+    fn of(path: &str) -> Format {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    fn to_string<T: serde::Serialize>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            Format::Toml => toml::to_string_pretty(value)?,
+            Format::Json => serde_json::to_string_pretty(value)? + "\n",
+        })
+    }
+}
+
+/// Reads a TOML or JSON file (by extension)
+fn read_file<T: serde::de::DeserializeOwned>(path: &str) -> Result<T> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(match Format::of(path) {
+        Format::Toml => toml::from_str(&text)?,
+        Format::Json => serde_json::from_str(&text)?,
+    })
+}
+
+/// Writes *text* to *file* (or stdout if there isn't one)
+fn write_output(file: Option<&str>, text: &str) -> Result<()> {
+    match file {
+        Some(path) => std::fs::write(path, text)?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+/// Reads every config field as { section: { field: value } }
+fn read_config(console: &mut Console) -> Result<Map<String, Value>> {
+    let mut sections = Map::new();
+    for line in console.checked("get")?.lines() {
+        // e.g. "keyboard.polling_rate = 1_000 (u32)"
+        let Some((name, rest)) = line.split_once(" = ") else {
+            continue;
+        };
+        let (section, field) = name
+            .split_once('.')
+            .ok_or(format!("bad field name: {}", name))?;
+        let value = rest.split(' ').next().unwrap_or("").replace('_', "");
+        let value: u64 = value.parse().map_err(|_| format!("bad value: {}", line))?;
+        sections
+            .entry(section)
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .unwrap()
+            .insert(field.to_string(), value.into());
+    }
+    Ok(sections)
+}
+
+/// Reads the whole keymap over the serial console
+fn read_keymap_serial(console: &mut Console) -> Result<Codes> {
+    let mut codes =
+        vec![vec![vec![0; keymap::NUM_COLUMNS]; userconfig::NUM_MULTIPLEXERS]; keymap::NUM_LAYERS];
+    for (layer, mux, chan) in keymap::positions() {
+        // e.g. "Key 0 1 2 = 0x0004"
+        let answer = console.checked(&format!("key {} {} {}", layer, mux, chan))?;
+        let hex = answer
+            .split(" = 0x")
+            .nth(1)
+            .ok_or(format!("unexpected answer: {}", answer.trim()))?;
+        codes[layer][mux][chan] = u16::from_str_radix(hex.trim(), 16)?;
+    }
+    Ok(codes)
+}
+
+/// Reads the whole keymap over VIA
+fn read_keymap_via(via: &mut via::Via) -> Result<Codes> {
+    let layers = via.layer_count()?;
+    if layers != keymap::NUM_LAYERS {
+        return Err(format!(
+            "keyboard has {} layers (expected {})",
+            layers,
+            keymap::NUM_LAYERS
+        )
+        .into());
+    }
+    let keys = keymap::positions().count();
+    let buffer = via.keymap_buffer(keys * 2)?;
+    let mut codes =
+        vec![vec![vec![0; keymap::NUM_COLUMNS]; userconfig::NUM_MULTIPLEXERS]; keymap::NUM_LAYERS];
+    for ((layer, mux, chan), code) in keymap::positions().zip(buffer.chunks_exact(2)) {
+        codes[layer][mux][chan] = u16::from_be_bytes([code[0], code[1]]);
+    }
+    Ok(codes)
+}
+
+fn print_values(console: &mut Console, mux: Option<usize>) -> Result<()> {
+    let command = match mux {
+        Some(mux) => format!("values {}", mux),
+        None => "values".to_string(),
+    };
+    for line in console.checked(&command)?.lines() {
+        let mut words = line.split_whitespace();
+        if let Some(mux) = words.next() {
+            let values: Vec<String> = words.map(|value| format!("{:>5}", value)).collect();
+            println!("{:>3}:{}", mux, values.join(""));
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    match &cli.command {
+        Command::Emulate { path } => return Ok(emulate::run(path)?),
+        Command::Replay {
+            file,
+            keymap,
+            report,
+        } => {
+            let codes = match keymap {
+                Some(path) => Some(read_file::<KeymapFile>(path)?.to_codes()?),
+                None => None,
+            };
+            let mode = match report {
+                Some(Report::Boot) => ReportMode::Boot6Kro,
+                Some(Report::Nkro) => ReportMode::Nkro,
+                None => ReportMode::from_config(config::from_userconfig().keyboard.report_mode),
+            };
+            replay::run(&std::fs::read(file)?, codes, mode);
+            return Ok(());
+        }
+        _ => {}
+    }
+    let mut console = Console::open(&cli.port)?;
+    match cli.command {
+        Command::Get { name } => print!(
+            "{}",
+            console.checked(&format!("get {}", name.unwrap_or_default()))?
+        ),
+        Command::Set { name, value } => {
+            print!("{}", console.checked(&format!("set {} {}", name, value))?)
+        }
+        Command::Dump { format, file } => {
+            // Going through Config makes sure we got every field (and nothing else)
+            let config: Config = serde_json::from_value(Value::Object(read_config(&mut console)?))?;
+            write_output(file.as_deref(), &format.to_string(&config)?)?;
+        }
+        Command::Load { file } => {
+            let current = read_config(&mut console)?;
+            let wanted: Map<String, Value> = read_file(&file)?;
+            let mut merged = current.clone();
+            for (section, fields) in &wanted {
+                let fields = fields
+                    .as_object()
+                    .ok_or(format!("[{}] isn't a section", section))?;
+                let target = merged
+                    .get_mut(section)
+                    .and_then(Value::as_object_mut)
+                    .ok_or(format!("unknown section: {}", section))?;
+                for (field, value) in fields {
+                    target.insert(field.clone(), value.clone());
+                }
+            }
+            // Make sure the result is still a valid Config before changing anything
+            let _: Config = serde_json::from_value(Value::Object(merged.clone()))?;
+            let mut changed = 0;
+            for (section, fields) in &merged {
+                for (field, value) in fields.as_object().unwrap() {
+                    if current[section].get(field) != Some(value) {
+                        print!(
+                            "{}",
+                            console.checked(&format!("set {}.{} {}", section, field, value))?
+                        );
+                        changed += 1;
+                    }
+                }
+            }
+            println!("{} field(s) changed", changed);
+        }
+        Command::KeymapGet { format, file } => {
+            let codes = match &cli.hid {
+                Some(path) => read_keymap_via(&mut via::Via::open(path)?)?,
+                None => read_keymap_serial(&mut console)?,
+            };
+            write_output(
+                file.as_deref(),
+                &format.to_string(&KeymapFile::from_codes(&codes))?,
+            )?;
+        }
+        Command::KeymapSet { file } => {
+            let wanted = read_file::<KeymapFile>(&file)?.to_codes()?;
+            let mut via = cli.hid.as_deref().map(via::Via::open).transpose()?;
+            let current = match via.as_mut() {
+                Some(via) => read_keymap_via(via)?,
+                None => read_keymap_serial(&mut console)?,
+            };
+            let mut changed = 0;
+            for (layer, mux, chan) in keymap::positions() {
+                let code = wanted[layer][mux][chan];
+                if current[layer][mux][chan] == code {
+                    continue;
+                }
+                match via.as_mut() {
+                    Some(via) => via.set_keycode(layer, mux, chan, code)?,
+                    None => {
+                        console
+                            .checked(&format!("key {} {} {} 0x{:04X}", layer, mux, chan, code))?;
+                    }
+                }
+                changed += 1;
+            }
+            println!("{} key(s) changed", changed);
+        }
+        Command::States { mux } => print_values(&mut console, mux)?,
+        Command::Watch { interval, mux } => loop {
+            // Clear the screen and start from the top
+            print!("\x1b[2J\x1b[H");
+            print_values(&mut console, mux)?;
+            std::thread::sleep(Duration::from_millis(interval));
+        },
+        Command::Calibrate { full: true } => {
+            print!("{}", console.checked("calibrate")?.trim_end_matches("> "));
+            // The keyboard says when it's done (every key went down or it gave up waiting)
+            let mut text = String::new();
+            while !(text.contains("Calibration finished") && text.ends_with("\r\n")) {
+                let data = console.read_for(Duration::from_millis(100))?;
+                let data = String::from_utf8_lossy(&data);
+                print!("{}", data);
+                std::io::stdout().flush()?;
+                text.push_str(&data);
+            }
+        }
+        Command::Calibrate { full: false } => {
+            println!("Let go of every key...");
+            std::thread::sleep(Duration::from_secs(1));
+            print!("{}", console.checked("recal")?);
+        }
+        Command::Threshold { args } => {
+            let line = format!("threshold {}", args.join(" "));
+            print!("{}", console.checked(line.trim_end())?);
+        }
+        Command::Trigger { args } => {
+            let line = format!("trigger {}", args.join(" "));
+            print!("{}", console.checked(line.trim_end())?);
+        }
+        Command::Socd { args } => {
+            let line = format!("socd {}", args.join(" "));
+            print!("{}", console.checked(line.trim_end())?);
+        }
+        Command::Dks { mut args } => {
+            if let Some(code) = args.get_mut(3).and_then(|key| keycodes::parse(key)) {
+                args[3] = format!("0x{:02X}", code);
+            }
+            let line = format!("dks {}", args.join(" "));
+            print!("{}", console.checked(line.trim_end())?);
+        }
+        Command::Capture {
+            file,
+            every,
+            seconds,
+        } => {
+            console.send(&format!("capture {}", every))?;
+            let mut trace = console.read_for(Duration::from_secs_f64(seconds))?;
+            console.send("capture off")?;
+            // Everything still in the keyboard's buffer comes before the answer
+            trace.extend(console.read_until("capture off", b"Capture stopped\r\n> ")?);
+            std::fs::write(&file, &trace)?;
+            println!("{} bytes written to {}", trace.len(), file);
+        }
+        Command::Emulate { .. } | Command::Replay { .. } => unreachable!(),
+    }
+    Ok(())
+}
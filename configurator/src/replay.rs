@@ -0,0 +1,402 @@
+//! Plays back a trace recorded with `configurator capture` (see trace.rs in hall-core) so
+//! phantom presses can be chased down at a desk instead of on the keyboard.
+//!
+//! Every Press()/Release() check_channel() comes up with gets printed along with when it
+//! happened.  The events then go through the same code the firmware's tick() runs them through
+//! (SOCD, the layout, DKS and mod-tap from hall-core) and every change to the report that would
+//! go out to the host gets printed byte for byte.  The keymap is the compiled-in one unless a
+//! keymap file (see keymap.rs) is given.  The SOCD pairs, DKS slots and the rest of the settings
+//! aren't part of a trace so the ones from userconfig.rs get used.
+
+use crate::keycodes;
+use crate::keymap::{self, Codes};
+use hall_core::config;
+use hall_core::dks::{self, Dks};
+use hall_core::key_code::KeyCode;
+use hall_core::keymap::{Bindings, Keymap, Layout};
+use hall_core::modtap::{self, ModTaps};
+use hall_core::multiplexers::{ChannelStates, Event};
+use hall_core::report::{BootReport, NkroReport, ReportMode};
+use hall_core::socd::{self, Socd};
+use hall_core::trace::{self, Record, Replay};
+use hall_core::travel::Travel;
+
+/// A report the way it goes out to the host
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct HidReport {
+    bytes: Vec<u8>,
+    /// Names of the keys in it
+    keys: Vec<String>,
+}
+
+/// Everything the firmware's tick() does with the events after check_channel()
+struct Keyboard {
+    layout: Layout,
+    bindings: Bindings,
+    socd: Socd,
+    dks: Dks,
+    mod_taps: ModTaps,
+    dks_settings: dks::Settings,
+    mod_tap_settings: modtap::Settings,
+    mode: ReportMode,
+}
+
+impl Keyboard {
+    /// *sweep_rate* being how many frames there are per second
+    fn new(
+        codes: hall_core::keymap::Codes,
+        mode: ReportMode,
+        sweep_rate: u32,
+        ch_states: &[ChannelStates],
+    ) -> Keyboard {
+        let config = config::from_userconfig();
+        let keymap = Keymap::new(codes, Default::default());
+        // The layout holds on to its actions for good (they're a static on the keyboard)
+        let mut layout = Layout::new(Box::leak(Box::new(keymap.actions())), sweep_rate);
+        layout.set_tap_dance_window(config.tap_dance.window);
+        Keyboard {
+            layout,
+            bindings: keymap.bindings(),
+            socd: Socd::new(socd::default_pairs(), ch_states),
+            dks: Dks::new(dks::default_slots()),
+            mod_taps: Default::default(),
+            dks_settings: dks::Settings::from_config(&config.dks, sweep_rate),
+            mod_tap_settings: modtap::Settings::from_config(&config.mod_tap),
+            mode,
+        }
+    }
+
+    /// Runs the events of a sweep through everything (in the same order tick() does) and
+    /// returns the report the host would get afterwards
+    fn tick(
+        &mut self,
+        events: &[Event],
+        ch_states: &[ChannelStates],
+        travel: &Travel,
+    ) -> HidReport {
+        let layout = &mut self.layout;
+        for event in events {
+            // Paired keys wait for the SOCD resolution below
+            if let Some(event) = self.socd.filter(*event) {
+                layout.event(event);
+            }
+        }
+        self.socd
+            .resolve(ch_states, travel, |event| layout.event(event));
+        layout.tick();
+
+        let bindings = &self.bindings;
+        let layer = layout.current_layer();
+        let default_layer = layout.default_layer();
+        self.dks
+            .sweep(ch_states, travel, &self.dks_settings, |mux, chan| {
+                hall_core::keymap::dks_slot(bindings, layer, default_layer, mux, chan)
+            });
+        self.mod_taps
+            .sweep(ch_states, travel, &self.mod_tap_settings, |mux, chan| {
+                hall_core::keymap::mod_tap(bindings, layer, default_layer, mux, chan)
+            });
+
+        let keycodes = || {
+            let analog = self.dks.keys().chain(self.mod_taps.keys());
+            layout
+                .keycodes()
+                .chain(analog.filter_map(KeyCode::from_usage))
+        };
+        let bytes = match self.mode {
+            ReportMode::Boot6Kro => keycodes().collect::<BootReport>().as_bytes().to_vec(),
+            ReportMode::Nkro => keycodes().collect::<NkroReport>().as_bytes().to_vec(),
+        };
+        HidReport {
+            bytes,
+            keys: keycodes().map(|kc| keycodes::name(kc as u16)).collect(),
+        }
+    }
+}
+
+/// The codes of a keymap file the way the firmware keeps them
+fn firmware_codes(codes: &Codes) -> hall_core::keymap::Codes {
+    let mut firmware = hall_core::keymap::default_codes();
+    for (layer, mux, chan) in keymap::positions() {
+        firmware[layer][mux][chan] = codes[layer][mux][chan];
+    }
+    firmware
+}
+
+/// What playing back a record came up with
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Output {
+    Snapshot {
+        tick: u32,
+    },
+    /// Frames that never made it into the trace (starting at *tick*)
+    Missing {
+        tick: u32,
+        frames: u32,
+    },
+    Event {
+        tick: u32,
+        event: Event,
+    },
+    Report {
+        tick: u32,
+        report: HidReport,
+    },
+}
+
+/// Plays back a trace one record at a time
+struct Player {
+    replay: Replay,
+    codes: hall_core::keymap::Codes,
+    mode: ReportMode,
+    /// Set up by the first snapshot (the SOCD pairs get checked against its channels)
+    keyboard: Option<Keyboard>,
+    report: HidReport,
+    next_tick: Option<u32>,
+    snapshots: u32,
+    frames: u32,
+    missing: u32,
+}
+
+impl Player {
+    fn new(codes: hall_core::keymap::Codes, mode: ReportMode) -> Player {
+        Player {
+            replay: Replay::default(),
+            codes,
+            mode,
+            keyboard: None,
+            report: HidReport::default(),
+            next_tick: None,
+            snapshots: 0,
+            frames: 0,
+            missing: 0,
+        }
+    }
+
+    /// Plays back *record*, handing whatever happened to *out*
+    fn play(&mut self, record: &Record, mut out: impl FnMut(Output)) {
+        match record {
+            Record::Snapshot(snapshot) => {
+                self.snapshots += 1;
+                out(Output::Snapshot {
+                    tick: snapshot.tick,
+                });
+                self.replay.play(record, |_, _| ());
+                if self.keyboard.is_none() {
+                    let sweep_rate = snapshot.tick_rate / snapshot.every.max(1);
+                    self.keyboard = Some(Keyboard::new(
+                        self.codes,
+                        self.mode,
+                        sweep_rate.max(1),
+                        &self.replay.ch_states,
+                    ));
+                }
+            }
+            Record::Frame(tick, _) => {
+                self.frames += 1;
+                let (Some(snapshot), Some(keyboard)) = (&self.replay.snapshot, &mut self.keyboard)
+                else {
+                    // Nothing to compare the readings to
+                    return;
+                };
+                let every = snapshot.every.max(1);
+                let travel = snapshot.travel;
+                // Frames the keyboard couldn't fit in its serial buffer
+                if let Some(expected) = self.next_tick {
+                    let skipped = tick.wrapping_sub(expected) / every;
+                    if skipped > 0 && skipped < u32::MAX / 2 {
+                        self.missing += skipped;
+                        out(Output::Missing {
+                            tick: expected,
+                            frames: skipped,
+                        });
+                    }
+                }
+                self.next_tick = Some(tick.wrapping_add(every));
+
+                let mut events = Vec::new();
+                self.replay.play(record, |tick, event| {
+                    out(Output::Event { tick, event });
+                    events.push(event);
+                });
+                let report = keyboard.tick(&events, &self.replay.ch_states, &travel);
+                if report != self.report {
+                    self.report = report.clone();
+                    out(Output::Report {
+                        tick: *tick,
+                        report,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Milliseconds since the capture started
+fn millis(tick: u32, tick_rate: u32) -> f64 {
+    tick as f64 * 1000.0 / tick_rate.max(1) as f64
+}
+
+/// Plays back every record in *bytes*, printing what happened.  The reports are worked out
+/// with *keymap* (or the compiled-in keymap) for the interface *mode* says.
+pub fn run(bytes: &[u8], keymap: Option<Codes>, mode: ReportMode) {
+    let codes = keymap
+        .as_ref()
+        .map(firmware_codes)
+        .unwrap_or_else(hall_core::keymap::default_codes);
+    let mut player = Player::new(codes, mode);
+    let mut rest = bytes;
+    while let Some((record, used)) = trace::parse(rest) {
+        rest = &rest[used..];
+        let tick_rate = match (&record, &player.replay.snapshot) {
+            (Record::Snapshot(snapshot), _) | (_, Some(snapshot)) => snapshot.tick_rate,
+            (Record::Frame(..), None) => 0,
+        };
+        player.play(&record, |output| match output {
+            Output::Snapshot { tick } => println!("{:>12.3} ms  snapshot", millis(tick, tick_rate)),
+            Output::Missing { tick, frames } => println!(
+                "{:>12.3} ms  {} frame(s) missing",
+                millis(tick, tick_rate),
+                frames
+            ),
+            Output::Event { tick, event } => {
+                let (what, mux, chan) = match event {
+                    Event::Press(mux, chan) => ("Press", mux, chan),
+                    Event::Release(mux, chan) => ("Release", mux, chan),
+                };
+                println!(
+                    "{:>12.3} ms  {:<7} mux {} chan {}",
+                    millis(tick, tick_rate),
+                    what,
+                    mux,
+                    chan
+                );
+            }
+            Output::Report { report, .. } => {
+                let bytes: Vec<String> =
+                    report.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                println!(
+                    "{:>15}  report {} [{}]",
+                    "",
+                    bytes.join(" "),
+                    report.keys.join(", ")
+                );
+            }
+        });
+    }
+    println!(
+        "{} snapshot(s), {} frame(s), {} frame(s) missing",
+        player.snapshots, player.frames, player.missing
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hall_core::mock::{MockInputs, MockKeyboard, MockMultiplexers};
+    use hall_core::trace::{Snapshot, FRAME_SIZE, SNAPSHOT_SIZE};
+    use std::cell::Cell;
+
+    const REST: u16 = 1500;
+    const A: (usize, usize) = (0, 12);
+    const LSHIFT: (usize, usize) = (0, 3);
+    /// MO(1)
+    const FUN: (usize, usize) = (1, 5);
+    /// 1 on the default layer, F1 on layer 1
+    const KB1: (usize, usize) = (0, 9);
+
+    /// Records a trace the way the firmware does: a snapshot and then a frame per sweep.  Every
+    /// step is the keys that are all the way down, held for ten sweeps.
+    fn record(steps: &[&[(usize, usize)]]) -> Vec<u8> {
+        let selected = Cell::new(0);
+        let mut mux = MockMultiplexers::new(&selected);
+        let mut adc = MockInputs::new(&selected, REST);
+        let mut keyboard = MockKeyboard::new(&mut mux, &mut adc);
+        let snapshot = Snapshot::capture(
+            0,
+            1000,
+            1,
+            &keyboard.ch_states,
+            &keyboard.thresholds,
+            &keyboard.config.keyboard,
+            config::ENCODER_PRESS_THRESHOLD,
+        );
+        let mut trace = vec![0; SNAPSHOT_SIZE];
+        snapshot.encode(&mut trace);
+        let mut tick = 0;
+        for keys in steps {
+            for (mux_index, chan) in [A, LSHIFT, FUN, KB1] {
+                let down = keys.contains(&(mux_index, chan));
+                let swing = if down { 300 } else { 0 };
+                let millivolts = if config::KEYBOARD_NORTH_DOWN > 0 {
+                    REST - swing
+                } else {
+                    REST + swing
+                };
+                adc.set(mux_index, chan, millivolts);
+            }
+            for _ in 0..10 {
+                tick += 1;
+                keyboard.scan(&mut mux, &mut adc, |_| ());
+                let mut buf = [0; FRAME_SIZE];
+                let len = trace::encode_frame(tick, &keyboard.frame, &mut buf);
+                trace.extend_from_slice(&buf[..len]);
+            }
+        }
+        trace
+    }
+
+    /// Plays back *trace* and returns every report that went out
+    fn reports(trace: &[u8], mode: ReportMode) -> Vec<Vec<u8>> {
+        let mut player = Player::new(hall_core::keymap::default_codes(), mode);
+        let mut reports = Vec::new();
+        let mut rest = trace;
+        while let Some((record, used)) = trace::parse(rest) {
+            rest = &rest[used..];
+            player.play(&record, |output| {
+                if let Output::Report { report, .. } = output {
+                    reports.push(report.bytes);
+                }
+            });
+        }
+        assert_eq!(player.missing, 0);
+        reports
+    }
+
+    #[test]
+    fn boot_reports() {
+        let trace = record(&[&[A], &[A, LSHIFT], &[LSHIFT], &[], &[FUN], &[FUN, KB1], &[]]);
+        assert_eq!(
+            reports(&trace, ReportMode::Boot6Kro),
+            [
+                vec![0, 0, 0x04, 0, 0, 0, 0, 0],
+                vec![0x02, 0, 0x04, 0, 0, 0, 0, 0],
+                vec![0x02, 0, 0, 0, 0, 0, 0, 0],
+                vec![0, 0, 0, 0, 0, 0, 0, 0],
+                vec![0, 0, 0x3A, 0, 0, 0, 0, 0],
+                vec![0, 0, 0, 0, 0, 0, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn nkro_reports() {
+        // Keys that go down on the same sweep reach the layout one tick apart
+        let trace = record(&[&[A, LSHIFT], &[]]);
+        let report = |modifiers: u8, a: bool| {
+            let mut bytes = vec![0; hall_core::report::NKRO_REPORT_SIZE];
+            bytes[0] = modifiers;
+            bytes[1] = (a as u8) << 4;
+            bytes
+        };
+        assert_eq!(
+            reports(&trace, ReportMode::Nkro),
+            [
+                report(0x02, false),
+                report(0x02, true),
+                report(0, true),
+                report(0, false)
+            ]
+        );
+    }
+}
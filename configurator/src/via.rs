@@ -0,0 +1,80 @@
+//! Client side of the VIA protocol (see src/via.rs in the firmware) over a Linux hidraw device
+//!
+//! Only the keymap commands are used here; everything else is easier over the serial console.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+
+/// Same as the firmware's hid::RAW_HID_REPORT_SIZE
+pub const REPORT_SIZE: usize = 32;
+pub const DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+pub const DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+pub const DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+pub const UNHANDLED: u8 = 0xFF;
+/// Most bytes a buffer request can carry
+const MAX_CHUNK: usize = REPORT_SIZE - 4;
+
+pub struct Via {
+    device: File,
+}
+
+impl Via {
+    /// Opens the keyboard's raw HID interface (e.g. /dev/hidraw3)
+    pub fn open(path: &str) -> io::Result<Via> {
+        let device = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Via { device })
+    }
+
+    /// Sends a request and returns the answer
+    fn request(&mut self, data: &[u8]) -> io::Result<[u8; REPORT_SIZE]> {
+        // hidraw wants the report ID first (0 since the interface doesn't use them)
+        let mut out = [0; REPORT_SIZE + 1];
+        out[1..1 + data.len()].copy_from_slice(data);
+        self.device.write_all(&out)?;
+        let mut answer = [0; REPORT_SIZE];
+        self.device.read_exact(&mut answer)?;
+        if answer[0] == UNHANDLED {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("command 0x{:02X} not handled", data[0]),
+            ));
+        }
+        Ok(answer)
+    }
+
+    pub fn layer_count(&mut self) -> io::Result<usize> {
+        Ok(self.request(&[DYNAMIC_KEYMAP_GET_LAYER_COUNT])?[1] as usize)
+    }
+
+    /// Reads *size* bytes of the dynamic keymap (two big endian bytes per key, in layer/mux/
+    /// channel order)
+    pub fn keymap_buffer(&mut self, size: usize) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(size);
+        while buffer.len() < size {
+            let chunk = (size - buffer.len()).min(MAX_CHUNK);
+            let [hi, lo] = (buffer.len() as u16).to_be_bytes();
+            let answer = self.request(&[DYNAMIC_KEYMAP_GET_BUFFER, hi, lo, chunk as u8])?;
+            buffer.extend_from_slice(&answer[4..4 + chunk]);
+        }
+        Ok(buffer)
+    }
+
+    pub fn set_keycode(
+        &mut self,
+        layer: usize,
+        mux: usize,
+        chan: usize,
+        code: u16,
+    ) -> io::Result<()> {
+        let [hi, lo] = code.to_be_bytes();
+        self.request(&[
+            DYNAMIC_KEYMAP_SET_KEYCODE,
+            layer as u8,
+            mux as u8,
+            chan as u8,
+            hi,
+            lo,
+        ])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+//! What keys do as far as the layout (see layout.rs) is concerned
+//!
+//! These work the same as keyberon's actions (and `k()`/`l()`/`d()` are there so layers.rs reads
+//! the same) minus the bits nothing here uses.  `T` is whatever the keyboard wants to do that the
+//! layout doesn't know about (see keymap::CustomAction).
+
+use crate::key_code::KeyCode;
+
+/// How a HoldTap key decides it's being held before its timeout is up
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HoldTapConfig {
+    /// Only the timeout (or releasing the key) decides
+    #[default]
+    Default,
+    /// Any other key getting pressed makes it a hold
+    HoldOnOtherKeyPress,
+    /// Another key getting pressed and released while it's down makes it a hold
+    PermissiveHold,
+}
+
+/// A key that does one thing when tapped and another when held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HoldTapAction<T: 'static> {
+    /// Milliseconds the key has to be down for to count as held
+    pub timeout: u16,
+    pub hold: Action<T>,
+    pub tap: Action<T>,
+    pub config: HoldTapConfig,
+    /// Pressing the key again within this many milliseconds of tapping it taps right away (so
+    /// it can be held down to repeat)
+    pub tap_hold_interval: u16,
+}
+
+/// What a tap-dance key does after a number of taps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapDanceStep<T: 'static> {
+    /// Done when the key has been let go of
+    pub tap: Action<T>,
+    /// Done when the key is still down
+    pub hold: Action<T>,
+}
+
+/// A key that does something different depending on how many times in a row it gets tapped
+/// (`steps[0]` for one tap, `steps[1]` for two and so on)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapDance<T: 'static> {
+    pub steps: &'static [TapDanceStep<T>],
+}
+
+/// What a key does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action<T: 'static> {
+    /// Nothing
+    NoOp,
+    /// Whatever the key does on the default layer
+    Trans,
+    KeyCode(KeyCode),
+    /// Several keys at once (e.g. a shortcut)
+    MultipleKeyCodes(&'static [KeyCode]),
+    MultipleActions(&'static [Action<T>]),
+    /// Adds to the active layer while held
+    Layer(usize),
+    /// Switches the default layer
+    DefaultLayer(usize),
+    HoldTap(&'static HoldTapAction<T>),
+    TapDance(&'static TapDance<T>),
+    Custom(T),
+}
+
+/// A key
+pub const fn k<T>(kc: KeyCode) -> Action<T> {
+    Action::KeyCode(kc)
+}
+
+/// A momentary layer key
+pub const fn l<T>(layer: usize) -> Action<T> {
+    Action::Layer(layer)
+}
+
+/// A default layer key
+pub const fn d<T>(layer: usize) -> Action<T> {
+    Action::DefaultLayer(layer)
+}
@@ -0,0 +1,129 @@
+//! Guided full-travel calibration: every key gets pressed all the way down once so its
+//! bottom-out reading (see travel.rs) doesn't have to be learned bit by bit.
+//!
+//! Starting it clears every channel's `low`/`high` (which `record_value()` keeps track of) and
+//! from then on every sweep checks which keys have swung at least `min_swing` mV from their
+//! resting (default) value.  It's over when every key has, when nothing new has happened for a
+//! while or when it's told to finish.  Finishing takes the deepest reading of every key that
+//! made it as its bottom-out reading and reports the ones that didn't.
+
+use crate::config;
+use crate::multiplexers::{self, ChannelState, ChannelStates};
+use crate::sensors::MUX_CHANNELS;
+use crate::userconfig::NUM_MULTIPLEXERS;
+
+/// Keys (by multiplexer, one bit per channel)
+pub type KeyBits = [u16; NUM_MULTIPLEXERS];
+
+/// How a calibration went
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    /// Keys that got a new bottom-out reading
+    pub calibrated: KeyBits,
+    /// Keys that never swung far enough (they keep whatever they had before)
+    pub failed: KeyBits,
+}
+
+impl Report {
+    pub fn calibrated_count(&self) -> u32 {
+        self.calibrated.iter().map(|bits| bits.count_ones()).sum()
+    }
+
+    /// The (multiplexer, channel) of every key that failed
+    pub fn failed_keys(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.failed.iter().enumerate().flat_map(|(multi, bits)| {
+            (0..MUX_CHANNELS as usize)
+                .filter(move |chan| bits & 1 << chan != 0)
+                .map(move |chan| (multi, chan))
+        })
+    }
+}
+
+/// A full-travel calibration (in progress or not)
+#[derive(Debug, Default, Clone)]
+pub struct TravelCalibration {
+    running: bool,
+    passed: KeyBits,
+    idle_ticks: u32, // Sweeps since a key last passed
+}
+
+/// True for channels that should have a key on them (connected and not part of the encoder)
+fn is_key(multi: usize, chan: usize, state: &ChannelState) -> bool {
+    chan < MUX_CHANNELS as usize
+        && state.default > config::KEYBOARD_IGNORE_BELOW
+        && !multiplexers::is_encoder_sensor(multi, chan)
+}
+
+/// Millivolts *state* has moved from its default since the calibration started
+fn swing(state: &ChannelState) -> u16 {
+    state.difference(state.deepest())
+}
+
+impl TravelCalibration {
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Starts over (forgetting the lowest/highest reading of every channel)
+    pub fn start(&mut self, ch_states: &mut [ChannelStates]) {
+        *self = TravelCalibration {
+            running: true,
+            ..Default::default()
+        };
+        for states in ch_states.iter_mut() {
+            for state in states.states.iter_mut() {
+                state.reset_extremes();
+            }
+        }
+    }
+
+    /// Stops without changing anything
+    pub fn cancel(&mut self) {
+        self.running = false;
+    }
+
+    /// Checks every key after a sweep.  Returns true once it's time to finish: every key has
+    /// swung at least *min_swing* mV or none has for *timeout* sweeps.
+    pub fn sweep(&mut self, ch_states: &[ChannelStates], min_swing: u16, timeout: u32) -> bool {
+        if !self.running {
+            return false;
+        }
+        self.idle_ticks = self.idle_ticks.saturating_add(1);
+        let mut waiting = 0;
+        for (multi, states) in ch_states.iter().enumerate() {
+            for (chan, state) in states.states.iter().enumerate() {
+                if !is_key(multi, chan, state) || self.passed[multi] & 1 << chan != 0 {
+                    continue;
+                }
+                if swing(state) >= min_swing {
+                    self.passed[multi] |= 1 << chan;
+                    self.idle_ticks = 0;
+                } else {
+                    waiting += 1;
+                }
+            }
+        }
+        waiting == 0 || self.idle_ticks >= timeout
+    }
+
+    /// Stops and gives every key that swung at least *min_swing* mV its deepest reading as its
+    /// bottom-out reading
+    pub fn finish(&mut self, ch_states: &mut [ChannelStates], min_swing: u16) -> Report {
+        self.running = false;
+        let mut report = Report::default();
+        for (multi, states) in ch_states.iter_mut().enumerate() {
+            for (chan, state) in states.states.iter_mut().enumerate() {
+                if !is_key(multi, chan, state) {
+                    continue;
+                }
+                if swing(state) >= min_swing {
+                    state.bottom_out = state.deepest();
+                    report.calibrated[multi] |= 1 << chan;
+                } else {
+                    report.failed[multi] |= 1 << chan;
+                }
+            }
+        }
+        report
+    }
+}
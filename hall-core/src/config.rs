@@ -0,0 +1,151 @@
+//! Compile-time settings (derived from userconfig.rs) under the names the rest of the firmware uses
+
+use crate::config_structs::{
+    Config, DevConfig, DisplayConfig, DksConfig, EncoderConfig, GamepadConfig, InfraredConfig,
+    KeyboardConfig, LedsConfig, ModTapConfig, MouseConfig, TapDanceConfig,
+};
+use crate::userconfig;
+
+pub const KEYBOARD_NORTH_DOWN: u8 = userconfig::NORTH_DOWN;
+pub const KEYBOARD_IGNORE_BELOW: u16 = userconfig::IGNORE_BELOW;
+pub const KEYBOARD_NUM_MULTIPLEXERS: usize = userconfig::NUM_MULTIPLEXERS;
+pub const KEYBOARD_MAX_CHANNELS: usize = userconfig::MAX_CHANNELS;
+pub const ENCODER_MUX: usize = userconfig::ENCODER_MUX;
+pub const ENCODER_CHANNEL1: usize = userconfig::ENCODER_CHANNEL1;
+pub const ENCODER_CHANNEL2: usize = userconfig::ENCODER_CHANNEL2;
+pub const ENCODER_PRESS_CHANNEL: usize = userconfig::ENCODER_PRESS_CHANNEL;
+pub const ENCODER_PRESS_THRESHOLD: u16 = userconfig::ENCODER_PRESS_THRESHOLD;
+/// The host never polls a full speed device more than once a frame (1ms)
+pub const MAX_POLLING_RATE: u32 = 1_000;
+
+/// Smallest and largest values a field (by its "<section>.<field>" name) can be changed to at
+/// runtime.  These are the ones that size, index or divide something the firmware set up from
+/// userconfig.rs; anything else can be whatever its type holds.
+pub fn limits(name: &str) -> Option<(u64, u64)> {
+    let last_mux = (KEYBOARD_NUM_MULTIPLEXERS - 1) as u64;
+    let last_channel = (KEYBOARD_MAX_CHANNELS - 1) as u64;
+    match name {
+        "keyboard.num_multiplexers" => Some((1, KEYBOARD_NUM_MULTIPLEXERS as u64)),
+        "keyboard.max_channels" => Some((1, KEYBOARD_MAX_CHANNELS as u64)),
+        "keyboard.filter_window" => Some((1, crate::filter::SMOOTHING as u64)),
+        "keyboard.polling_rate" => Some((1, MAX_POLLING_RATE as u64)),
+        "keyboard.recalibration_rate" | "keyboard.calibration_timeout" => {
+            Some((1, u32::MAX as u64))
+        }
+        "encoder.mux" => Some((0, last_mux)),
+        "encoder.channel1" | "encoder.channel2" | "encoder.press_channel" => {
+            Some((0, last_channel))
+        }
+        _ => None,
+    }
+}
+
+/// Builds the runtime configuration from the compile-time settings in userconfig.rs
+pub fn from_userconfig() -> Config {
+    Config {
+        keyboard: KeyboardConfig {
+            north_down: userconfig::NORTH_DOWN,
+            actuation_threshold: userconfig::ACTUATION_THRESHOLD,
+            release_threshold: userconfig::RELEASE_THRESHOLD,
+            rapid_trigger_press_sensitivity: userconfig::RAPID_TRIGGER_PRESS_SENSITIVITY,
+            rapid_trigger_release_sensitivity: userconfig::RAPID_TRIGGER_RELEASE_SENSITIVITY,
+            filter: userconfig::FILTER,
+            filter_window: userconfig::FILTER_WINDOW,
+            filter_alpha: userconfig::FILTER_ALPHA,
+            threshold_units: userconfig::THRESHOLD_UNITS,
+            full_travel: userconfig::FULL_TRAVEL,
+            bottom_out_swing: userconfig::BOTTOM_OUT_SWING,
+            switch_curve: userconfig::SWITCH_CURVE_TYPE,
+            calibration_min_swing: userconfig::CALIBRATION_MIN_SWING,
+            calibration_timeout: userconfig::CALIBRATION_TIMEOUT,
+            ignore_below: userconfig::IGNORE_BELOW,
+            recalibration_rate: userconfig::RECALIBRATION_RATE,
+            recalibration_noise: userconfig::RECALIBRATION_NOISE,
+            recalibration_max_step: userconfig::RECALIBRATION_MAX_STEP,
+            num_multiplexers: userconfig::NUM_MULTIPLEXERS,
+            max_channels: userconfig::MAX_CHANNELS,
+            usb_vid: userconfig::USB_VID,
+            usb_pid: userconfig::USB_PID,
+            polling_rate: userconfig::POLLING_RATE,
+            report_mode: userconfig::REPORT_MODE,
+        },
+        // NOTE: This board doesn't have a mouse mode, LEDs, displays or an IR receiver (yet)
+        // so these are just sensible defaults
+        mouse: MouseConfig { scroll_amount: 1 },
+        encoder: EncoderConfig {
+            mux: userconfig::ENCODER_MUX,
+            resolution: 100,
+            press_threshold: userconfig::ENCODER_PRESS_THRESHOLD,
+            channel1: userconfig::ENCODER_CHANNEL1,
+            channel2: userconfig::ENCODER_CHANNEL2,
+            press_channel: userconfig::ENCODER_PRESS_CHANNEL,
+        },
+        leds: LedsConfig {
+            brightness: 0,
+            max_brightness_unpowered: 0,
+            max_brightness_powered: 0,
+            step: 0,
+            num_leds: 0,
+            speed: 0,
+        },
+        display: DisplayConfig {
+            num_matrices: 0,
+            brightness: 0,
+            max_brightness: 0,
+            buffer_length: 0,
+            vertical_flip: 0,
+            mirror: 0,
+            refresh_interval: 0,
+        },
+        infrared: InfraredConfig { encoding: 0, mux: 0 },
+        gamepad: GamepadConfig {
+            deadzone: userconfig::GAMEPAD_DEADZONE,
+            curve: userconfig::GAMEPAD_CURVE,
+        },
+        dks: DksConfig {
+            actuation: userconfig::DKS_ACTUATION,
+            bottom_out: userconfig::DKS_BOTTOM_OUT,
+            tap_time: userconfig::DKS_TAP_TIME,
+        },
+        mod_tap: ModTapConfig {
+            tap_point: userconfig::MOD_TAP_TAP_POINT,
+            hold_point: userconfig::MOD_TAP_HOLD_POINT,
+        },
+        tap_dance: TapDanceConfig { window: userconfig::TAP_DANCE_WINDOW },
+        dev: DevConfig { debug_refresh_interval: 0 },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_structs::ConfigError;
+    use std::string::ToString;
+
+    #[test]
+    fn set_keeps_to_the_limits() {
+        let mut config = from_userconfig();
+        let too_many = (KEYBOARD_NUM_MULTIPLEXERS + 1).to_string();
+        assert_eq!(
+            config.set("keyboard.num_multiplexers", &too_many),
+            Err(ConfigError::OutOfRange)
+        );
+        assert_eq!(
+            config.set("keyboard.polling_rate", "0"),
+            Err(ConfigError::OutOfRange)
+        );
+        assert_eq!(
+            config.set("keyboard.polling_rate", "8000"),
+            Err(ConfigError::OutOfRange)
+        );
+        assert_eq!(
+            config.set("keyboard.recalibration_rate", "0"),
+            Err(ConfigError::OutOfRange)
+        );
+        assert_eq!(config.keyboard.polling_rate, userconfig::POLLING_RATE);
+        assert_eq!(config.set("keyboard.polling_rate", "500"), Ok(()));
+        assert_eq!(config.keyboard.polling_rate, 500);
+        // Fields without limits only have to parse
+        assert_eq!(config.set("dks.tap_time", "0"), Ok(()));
+    }
+}
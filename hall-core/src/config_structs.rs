@@ -0,0 +1,427 @@
+use num_format::{Buffer, CustomFormat};
+/// Configuration structs; separate from config.rs so we can use them in build.rs without having to duplicate them
+// use core::fmt::Write;
+// use heapless::String;
+// use heapless::consts::U32;
+use serde::{Deserialize, Serialize};
+// use core::fmt::Display;
+
+// This lets us generate a userconfig.rs from inside build.rs that gets include!(concat!()) inside config.rs:
+macro_rules! add_const_gen {
+    (
+        $(#[$meta:meta])*
+        pub struct $struct_name:ident {
+        $(
+            $(#[$field_meta:meta])*
+            $field_vis:vis $field_name:ident : $field_type:ty
+        ),*$(,)+
+        }
+    ) => {
+        $(#[$meta])*
+        pub struct $struct_name {
+            $(
+                $(#[$field_meta])*
+                pub $field_name : $field_type,
+            )*
+        }
+
+        impl $struct_name {
+            #[allow(dead_code)]
+            pub fn field_names() -> &'static [&'static str] {
+                static NAMES: &'static [&'static str] = &[$(stringify!($field_name)),*];
+                NAMES
+            }
+
+            #[allow(dead_code)]
+            pub fn gen_meta_tuple(&self, field: &'static str) -> (&str, &str, &str, Buffer) {
+                let rust_format = CustomFormat::builder()
+                    .separator("_")
+                    .build().unwrap();
+                match field {
+                    $(stringify!($field_name) => {
+                        let mut buf = Buffer::default();
+                        buf.write_formatted(&self.$field_name, &rust_format);
+                        (
+                            stringify!($struct_name),
+                            stringify!($field_name),
+                            stringify!($field_type),
+                            buf
+                        )
+                    }),*
+                    _ => ("","","",Buffer::default())
+                }
+            }
+
+            /// Parses *value* into the field with the given name
+            #[allow(dead_code)]
+            pub fn set_field(&mut self, field: &str, value: &str) -> Result<(), ConfigError> {
+                match field {
+                    $(stringify!($field_name) => {
+                        self.$field_name = value.parse().map_err(|_| ConfigError::InvalidValue)?;
+                        Ok(())
+                    }),*
+                    _ => Err(ConfigError::UnknownField)
+                }
+            }
+
+            /// Same as gen_meta_tuple() but takes a field name that isn't 'static (e.g. typed in
+            /// over the serial console)
+            #[allow(dead_code)]
+            pub fn get_field(&self, field: &str) -> Option<(&str, &str, &str, Buffer)> {
+                Self::field_names()
+                    .iter()
+                    .find(|name| **name == field)
+                    .map(|name| self.gen_meta_tuple(name))
+            }
+
+            // #[allow(dead_code)]
+            // fn gen_meta_tuple(&self, field: &'static str) -> (&str, &str, &str, &str) {
+            //     // let rust_format = CustomFormat::builder()
+            //     //     .separator("_")
+            //     //     .build().unwrap();
+            //     match field {
+            //         $(stringify!($field_name) => {
+            //             // let mut buf = Buffer::default();
+            //             // buf.write_formatted(&self.$field_name, &rust_format);
+            //             let mut data = String::<U32>::from("");
+            //             (
+            //                 stringify!($struct_name),
+            //                 stringify!($field_name),
+            //                 stringify!($field_type),
+            //                 write!(data, "{}", &self.$field_name),
+            //             )
+            //         }),*
+            //         _ => ("","","","")
+            //     }
+            // }
+        }
+    }
+}
+
+/// Errors that can occur when changing settings at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// There's no section/field by that name
+    UnknownField,
+    /// The value couldn't be parsed into the field's type
+    InvalidValue,
+    /// The value is outside of what the field can be set to (see config::limits())
+    OutOfRange,
+}
+
+#[allow(dead_code)]
+struct IsInt<'__, T>(&'__ T);
+#[allow(dead_code)]
+impl<T: ::num_format::ToFormattedStr> IsInt<'_, T> {
+    fn pretty_display(self) -> impl 'static + ::core::fmt::Display {
+        let rust_format = &CustomFormat::builder().separator("_").build().unwrap();
+        let mut buf = Buffer::default();
+        buf.write_formatted(self.0, rust_format);
+        buf
+    }
+}
+
+// impl<T: ::num_format::ToFormattedStr> bool {
+//     fn pretty_display(self: Self) -> impl 'static + ::core::fmt::Display {
+//         let ref rust_format = CustomFormat::builder().separator("_").build().unwrap();
+//         let mut buf = Buffer::default();
+//         buf.write_formatted(self.0, rust_format);
+//         buf
+//     }
+// }
+
+#[allow(dead_code)]
+trait Fallback<'__, T> {
+    fn pretty_display(self) -> &'__ T;
+}
+impl<'lt, T: ::core::fmt::Display> Fallback<'lt, T> for IsInt<'lt, T> {
+    fn pretty_display(self) -> &'lt T {
+        self.0
+    }
+}
+
+add_const_gen! {
+/// Configuration items related to keys, sensors, and the rotary encoder
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyboardConfig {
+    /// Millivolts difference where we consider it a Press()
+    pub north_down: u8,
+    /// Millivolts difference where we consider it a Press()
+    pub actuation_threshold: u16,
+    /// Millivolts above the actuation threshold where we consider it a Release() (prevents bouncing)
+    pub release_threshold: u16,
+    /// Millivolts a rapid trigger key needs to move down from its shallowest point before it's pressed again
+    pub rapid_trigger_press_sensitivity: u16,
+    /// Millivolts a rapid trigger key needs to move up from its deepest point before it's released
+    pub rapid_trigger_release_sensitivity: u16,
+    /// Noise filter applied to every channel's readings (0 = none, 1 = boxcar, 2 = IIR, 3 = median)
+    pub filter: u8,
+    /// Number of readings the boxcar and median filters look at (1-8)
+    pub filter_window: u8,
+    /// How much each new reading counts for in the IIR filter (out of 256)
+    pub filter_alpha: u8,
+    /// What the thresholds and rapid trigger sensitivities are in (0 = millivolts, 1 = 0.01mm of travel)
+    pub threshold_units: u8,
+    /// Total travel of the switches (0.01mm)
+    pub full_travel: u16,
+    /// Millivolts between resting and bottomed out for keys that haven't been bottomed out yet
+    pub bottom_out_swing: u16,
+    /// How travel relates to the millivolts (0 = linear, 1 = the lookup table in userconfig.rs)
+    pub switch_curve: u8,
+    /// Millivolts a key has to move when pressed all the way down to pass full-travel calibration
+    pub calibration_min_swing: u16,
+    /// Seconds without a key passing before full-travel calibration gives up on the rest
+    pub calibration_timeout: u32,
+    /// Millivolt values below this value will be ignored (so we can skip mux pins connected to ground)
+    pub ignore_below: u16,
+    /// How often (seconds) to check to see if the default mV values need to be adjusted
+    pub recalibration_rate: u32,
+    /// Maximum millivolts a resting key may wobble between recalibrations and still get recalibrated
+    pub recalibration_noise: u16,
+    /// Maximum millivolts the default value may move per recalibration
+    pub recalibration_max_step: u16,
+    /// Total number of multiplexers on this keyboard
+    pub num_multiplexers: usize,
+    /// Maximum number of channels per multiplexer or buttons per IR remote (use whatever is greater)
+    pub max_channels: usize,
+    /// The USB VID the keyboard will identify itself with
+    pub usb_vid: u16,
+    /// The USB PID the keyboard will identify itself with
+    pub usb_pid: u16,
+    /// How often (Hz) the host should poll for HID reports (1000 at most; takes a restart).
+    /// The keyboard keeps scanning at userconfig::POLLING_RATE no matter what this is set to.
+    pub polling_rate: u32,
+    /// Which keyboard interface to report keys on (0 = 6KRO boot keyboard, 1 = NKRO)
+    pub report_mode: u8,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to mouse emulation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MouseConfig {
+    /// Amount of units (aka lines) to scroll per press/rotary encoder movement
+    pub scroll_amount: u8,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to a rotary encoder
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncoderConfig {
+    /// The multiplexer the encoder sensors are connected to
+    pub mux: usize,
+    /// Millivolts difference required before we consider the encoder moved in either direction
+    pub resolution: u16,
+    /// Millivolts difference before we consider it an encoder Press()
+    pub press_threshold: u16,
+    /// Channel on the multiplexer where the encoder's first sensor resides
+    pub channel1: usize,
+    /// Channel on the multiplexer where the encoder's second sensor resides
+    pub channel2: usize,
+    /// Virtual channel on the multiplexer where Press() events get defined
+    pub press_channel: usize,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to the WS2812B-B RGB LEDs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LedsConfig {
+    /// Default brightness of the LEDs (0-255)
+    pub brightness: u8,
+    /// Max brightness of the LEDs when powered only via USB (0-255)
+    pub max_brightness_unpowered: u8,
+    /// Max brightness of the LEDs when external power is connected (0-255)
+    pub max_brightness_powered: u8,
+    /// Amount to change brightness when increasing or decreasing it
+    pub step: u8,
+    /// Total number of LEDs on or attached to the keyboard (70 plus however many you've connected)
+    pub num_leds: usize,
+    /// Rate (in hz) at which the LEDs are updated
+    pub speed: u32,
+}
+}
+
+// TODO: Make this more display-agnostic so it can work with anything
+add_const_gen! {
+/// Configuration items related to the MAX7219 displays/matrices
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DisplayConfig {
+    /// Total number of MAX7219 matrices connected to the keyboard
+    pub num_matrices: usize,
+    /// Brightness (aka display intensity) (0-8)
+    pub brightness: u8,
+    /// Max brightness overall (0-8)
+    pub max_brightness: u8,
+    /// Maximum amount of characters we'll buffer (uses up RAM so don't make it too big)
+    pub buffer_length: usize,
+    /// If the display is upside down (0 or 1)
+    pub vertical_flip: u8,
+    /// If the display needs to be mirrored (0 or 1)
+    pub mirror: u8,
+    /// How often (Hz) the display should be re-drawn
+    pub refresh_interval: u32,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to mouse emulation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InfraredConfig {
+    /// Amount of units (aka lines) to scroll per press/rotary encoder movement
+    pub encoding: u8,
+    /// Imaginary multiplexer that holds IR remote button mappings
+    pub mux: usize,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to the analog gamepad (keys bound to sticks and triggers)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GamepadConfig {
+    /// Key travel (0.01mm) that doesn't move the stick/trigger at all
+    pub deadzone: u16,
+    /// How the rest of the travel maps to the axis (0 = linear, 1 = smooth/quadratic, 2 = fast)
+    pub curve: u8,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to dynamic keystrokes (keys bound to DKS slots)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DksConfig {
+    /// Key travel (0.01mm) of the press/release point
+    pub actuation: u16,
+    /// Key travel (0.01mm) of the bottom-out/lift point
+    pub bottom_out: u16,
+    /// Milliseconds a tapped key stays down for
+    pub tap_time: u16,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to mod-tap by depth (keys bound to MT() codes)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModTapConfig {
+    /// Key travel (0.01mm) where the tap key gets pressed
+    pub tap_point: u16,
+    /// Key travel (0.01mm) where the modifiers get pressed instead
+    pub hold_point: u16,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to tap dances (see the firmware's layout.rs)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TapDanceConfig {
+    /// How long (ms) a tap-dance key waits for another tap before doing what it's been tapped to
+    pub window: u16,
+}
+}
+
+add_const_gen! {
+/// Configuration items related to development stuff
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DevConfig {
+    /// Minimum amount of time to wait before sending debug messages to the debugger
+    pub debug_refresh_interval: u16,
+}
+}
+
+/// Central location for referencing and updating runtime settings
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    /// Keyboard configuration items
+    pub keyboard: KeyboardConfig,
+    /// Mouse configuration items
+    pub mouse: MouseConfig,
+    /// Encoder configuration items
+    pub encoder: EncoderConfig,
+    /// LED configuration items
+    pub leds: LedsConfig,
+    /// Display (MAX7219) configuration items
+    pub display: DisplayConfig,
+    /// Remote control (infrared) configuration items
+    pub infrared: InfraredConfig,
+    /// Analog gamepad configuration items
+    pub gamepad: GamepadConfig,
+    /// Dynamic keystroke configuration items
+    pub dks: DksConfig,
+    /// Mod-tap configuration items
+    pub mod_tap: ModTapConfig,
+    /// Tap dance configuration items
+    pub tap_dance: TapDanceConfig,
+    /// Development configuration items (e.g. debug stuff)
+    pub dev: DevConfig,
+}
+
+impl Config {
+    /// Names of the sections (used as the prefix of "<section>.<field>" names)
+    pub const SECTIONS: &'static [&'static str] = &[
+        "keyboard", "mouse", "encoder", "leds", "display", "infrared", "gamepad", "dks", "mod_tap",
+        "tap_dance", "dev",
+    ];
+
+    /// Returns the names of all the fields in the given section
+    pub fn field_names(section: &str) -> &'static [&'static str] {
+        match section {
+            "keyboard" => KeyboardConfig::field_names(),
+            "mouse" => MouseConfig::field_names(),
+            "encoder" => EncoderConfig::field_names(),
+            "leds" => LedsConfig::field_names(),
+            "display" => DisplayConfig::field_names(),
+            "infrared" => InfraredConfig::field_names(),
+            "gamepad" => GamepadConfig::field_names(),
+            "dks" => DksConfig::field_names(),
+            "mod_tap" => ModTapConfig::field_names(),
+            "tap_dance" => TapDanceConfig::field_names(),
+            "dev" => DevConfig::field_names(),
+            _ => &[],
+        }
+    }
+
+    /// Looks up a field by its "<section>.<field>" name (e.g. "keyboard.actuation_threshold")
+    pub fn get(&self, name: &str) -> Option<(&str, &str, &str, Buffer)> {
+        let (section, field) = name.split_once('.')?;
+        match section {
+            "keyboard" => self.keyboard.get_field(field),
+            "mouse" => self.mouse.get_field(field),
+            "encoder" => self.encoder.get_field(field),
+            "leds" => self.leds.get_field(field),
+            "display" => self.display.get_field(field),
+            "infrared" => self.infrared.get_field(field),
+            "gamepad" => self.gamepad.get_field(field),
+            "dks" => self.dks.get_field(field),
+            "mod_tap" => self.mod_tap.get_field(field),
+            "tap_dance" => self.tap_dance.get_field(field),
+            "dev" => self.dev.get_field(field),
+            _ => None,
+        }
+    }
+
+    /// Changes a field by its "<section>.<field>" name
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let (section, field) = name.split_once('.').ok_or(ConfigError::UnknownField)?;
+        if let Some((min, max)) = crate::config::limits(name) {
+            let number: u64 = value.parse().map_err(|_| ConfigError::InvalidValue)?;
+            if !(min..=max).contains(&number) {
+                return Err(ConfigError::OutOfRange);
+            }
+        }
+        match section {
+            "keyboard" => self.keyboard.set_field(field, value),
+            "mouse" => self.mouse.set_field(field, value),
+            "encoder" => self.encoder.set_field(field, value),
+            "leds" => self.leds.set_field(field, value),
+            "display" => self.display.set_field(field, value),
+            "infrared" => self.infrared.set_field(field, value),
+            "gamepad" => self.gamepad.set_field(field, value),
+            "dks" => self.dks.set_field(field, value),
+            "mod_tap" => self.mod_tap.set_field(field, value),
+            "tap_dance" => self.tap_dance.set_field(field, value),
+            "dev" => self.dev.set_field(field, value),
+            _ => Err(ConfigError::UnknownField),
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Dynamic keystrokes (DKS): one key doing different things at different depths
+//!
+//! A key bound to a DKS slot (see the `DKS_*` codes in the firmware's keymap.rs) doesn't get
+//! pressed/released like a normal key.  Instead it goes through four points as it travels:
+//!
+//! * `Press`: passing the actuation point on the way down
+//! * `BottomOut`: reaching the bottom-out point
+//! * `Lift`: coming back up off the bottom-out point
+//! * `Release`: coming back up past the actuation point
+//!
+//! and the slot says what to do at each one: press a key (it stays down until something
+//! releases it), release one, tap one or nothing.  Whatever a key pressed gets released at the
+//! `Release` point no matter what so nothing can get stuck.  E.g. "hold W until released but
+//! also tap Space when bottomed out" is `Press: down W`, `BottomOut: tap Space`.
+//!
+//! Which slot a key uses gets latched when it passes the actuation point (so switching layers
+//! mid-press doesn't leave keys down) and where each key is at comes from its travel (see
+//! travel.rs) every sweep.
+
+use crate::config_structs::DksConfig;
+use crate::multiplexers::ChannelStates;
+use crate::sensors::MUX_CHANNELS;
+use crate::travel::Travel;
+use crate::userconfig::{self, NUM_MULTIPLEXERS};
+use serde::{Deserialize, Serialize};
+
+/// Number of slots (the valid indexes of the `DKS_*` codes)
+pub const MAX_SLOTS: usize = 8;
+/// How far (0.01mm) a key has to come back up past a point before it counts as having left it
+/// (so keys sitting right on a point don't chatter)
+pub const HYSTERESIS: u16 = 10;
+/// Most keys one key can hold down at a time
+pub const MAX_HELD: usize = 4;
+/// Most taps that can be in flight at the same time
+pub const MAX_TAPS: usize = 8;
+
+/// The points in a key's travel where something can happen (in the order a key goes through
+/// them)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Point {
+    Press,
+    BottomOut,
+    Lift,
+    Release,
+}
+
+impl Point {
+    pub const COUNT: usize = 4;
+
+    /// The point by the name the console uses
+    pub fn from_name(name: &str) -> Option<Point> {
+        match name {
+            "press" => Some(Point::Press),
+            "bottom" => Some(Point::BottomOut),
+            "lift" => Some(Point::Lift),
+            "release" => Some(Point::Release),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Point::Press => "press",
+            Point::BottomOut => "bottom",
+            Point::Lift => "lift",
+            Point::Release => "release",
+        }
+    }
+
+    pub fn all() -> [Point; Point::COUNT] {
+        [Point::Press, Point::BottomOut, Point::Lift, Point::Release]
+    }
+}
+
+/// What happens to a step's key at its point
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    #[default]
+    None,
+    /// Press it (and keep it down)
+    Down,
+    /// Let go of it
+    Up,
+    /// Press it and let go of it right away
+    Tap,
+}
+
+impl Op {
+    /// The op for the number used in userconfig.rs
+    pub fn from_config(op: u8) -> Op {
+        match op {
+            1 => Op::Down,
+            2 => Op::Up,
+            3 => Op::Tap,
+            _ => Op::None,
+        }
+    }
+
+    /// The op by the name the console uses
+    pub fn from_name(name: &str) -> Option<Op> {
+        match name {
+            "none" => Some(Op::None),
+            "down" => Some(Op::Down),
+            "up" => Some(Op::Up),
+            "tap" => Some(Op::Tap),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::None => "none",
+            Op::Down => "down",
+            Op::Up => "up",
+            Op::Tap => "tap",
+        }
+    }
+}
+
+/// What to do at one point
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    pub op: Op,
+    /// HID keyboard usage (e.g. 0x04 = A)
+    pub key: u8,
+}
+
+impl core::fmt::Display for Step {
+    /// E.g. "down 0x1A" (or just "none")
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.op {
+            Op::None => f.write_str(self.op.name()),
+            op => write!(f, "{} 0x{:02X}", op.name(), self.key),
+        }
+    }
+}
+
+/// What to do at each point (indexed by `Point`)
+pub type Slot = [Step; Point::COUNT];
+/// Every slot (as it gets saved)
+pub type Slots = [Slot; MAX_SLOTS];
+
+/// Returns true if *key* is a HID keyboard usage a step can use
+pub fn valid_key(key: u16) -> bool {
+    matches!(key, 0x04..=0xA4 | 0xE0..=0xE7)
+}
+
+/// The slots from userconfig.rs (anything past MAX_SLOTS gets ignored)
+pub fn default_slots() -> Slots {
+    let mut slots = [[Step::default(); Point::COUNT]; MAX_SLOTS];
+    for (slot, steps) in slots.iter_mut().zip(userconfig::DKS_SLOTS) {
+        for (step, &(op, key)) in slot.iter_mut().zip(steps) {
+            *step = Step {
+                op: Op::from_config(op),
+                key,
+            };
+        }
+    }
+    slots
+}
+
+/// Where the points are
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Travel (0.01mm) of the `Press`/`Release` point
+    pub actuation: u16,
+    /// Travel (0.01mm) of the `BottomOut`/`Lift` point
+    pub bottom_out: u16,
+    /// Sweeps a tapped key stays down for
+    pub tap_sweeps: u32,
+}
+
+impl Settings {
+    /// *sweep_rate* being how many sweeps happen per second
+    pub fn from_config(dks: &DksConfig, sweep_rate: u32) -> Settings {
+        // Keys at rest need to be able to get back below the actuation point
+        let actuation = dks.actuation.max(HYSTERESIS + 1);
+        Settings {
+            actuation,
+            bottom_out: dks.bottom_out.max(actuation.saturating_add(HYSTERESIS)),
+            tap_sweeps: (dks.tap_time as u32 * sweep_rate / 1000).max(1),
+        }
+    }
+}
+
+/// Where a key is in its travel (as far as DKS goes)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    #[default]
+    Up,
+    /// Past the actuation point
+    Pressed,
+    /// Past the bottom-out point
+    BottomedOut,
+}
+
+/// One key's state machine
+#[derive(Debug, Default, Clone, Copy)]
+struct KeyState {
+    phase: Phase,
+    slot: usize,          // Latched when the key went past the actuation point
+    held: [u8; MAX_HELD], // Keys this one is holding down (0 = none)
+}
+
+impl KeyState {
+    /// Moves to wherever *travel* is, doing the steps of every point passed on the way
+    fn update(&mut self, travel: u16, settings: &Settings, slot: &Slot, taps: &mut Taps) {
+        let mut passed = |point: Point, held: &mut [u8; MAX_HELD]| {
+            let step = slot[point as usize];
+            match step.op {
+                Op::None => (),
+                Op::Down => {
+                    if !held.contains(&step.key) {
+                        if let Some(free) = held.iter_mut().find(|key| **key == 0) {
+                            *free = step.key;
+                        }
+                    }
+                }
+                Op::Up => held
+                    .iter_mut()
+                    .filter(|key| **key == step.key)
+                    .for_each(|key| *key = 0),
+                Op::Tap => taps.add(step.key, settings.tap_sweeps),
+            }
+        };
+        if self.phase == Phase::Up && travel >= settings.actuation {
+            self.phase = Phase::Pressed;
+            passed(Point::Press, &mut self.held);
+        }
+        if self.phase == Phase::Pressed && travel >= settings.bottom_out {
+            self.phase = Phase::BottomedOut;
+            passed(Point::BottomOut, &mut self.held);
+        }
+        if self.phase == Phase::BottomedOut
+            && travel.saturating_add(HYSTERESIS) < settings.bottom_out
+        {
+            self.phase = Phase::Pressed;
+            passed(Point::Lift, &mut self.held);
+        }
+        if self.phase == Phase::Pressed && travel.saturating_add(HYSTERESIS) < settings.actuation {
+            self.phase = Phase::Up;
+            passed(Point::Release, &mut self.held);
+            self.held = [0; MAX_HELD];
+        }
+    }
+}
+
+/// Tapped keys and how many more sweeps they stay down for
+#[derive(Debug, Default, Clone, Copy)]
+struct Taps([(u8, u32); MAX_TAPS]);
+
+impl Taps {
+    /// Taps *key* (restarting it if it's already being tapped; dropped if there are too many)
+    fn add(&mut self, key: u8, sweeps: u32) {
+        let existing = self.0.iter().position(|(k, left)| *k == key && *left > 0);
+        let free = || self.0.iter().position(|(_, left)| *left == 0);
+        if let Some(index) = existing.or_else(free) {
+            self.0[index] = (key, sweeps);
+        }
+    }
+
+    fn tick(&mut self) {
+        for (_, left) in self.0.iter_mut() {
+            *left = left.saturating_sub(1);
+        }
+    }
+}
+
+/// Every slot and the state machine of every key
+#[derive(Debug, Clone)]
+pub struct Dks {
+    slots: Slots,
+    keys: [[KeyState; MUX_CHANNELS as usize]; NUM_MULTIPLEXERS],
+    taps: Taps,
+}
+
+impl Dks {
+    /// Uses the given slots (e.g. loaded from flash)
+    pub fn new(slots: Slots) -> Dks {
+        Dks {
+            slots,
+            keys: [[KeyState::default(); MUX_CHANNELS as usize]; NUM_MULTIPLEXERS],
+            taps: Taps::default(),
+        }
+    }
+
+    pub fn slots(&self) -> &Slots {
+        &self.slots
+    }
+
+    /// Changes what a slot does at *point*.  Returns false if there's no such slot.
+    pub fn set(&mut self, slot: usize, point: Point, step: Step) -> bool {
+        match self.slots.get_mut(slot) {
+            Some(steps) => {
+                steps[point as usize] = step;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs every key's state machine.  *slot_of* says which slot (if any) a key (multiplexer,
+    /// channel) is bound to right now; keys that are already down stick with the slot they
+    /// started out with.
+    pub fn sweep(
+        &mut self,
+        ch_states: &[ChannelStates],
+        travel: &Travel,
+        settings: &Settings,
+        slot_of: impl Fn(usize, usize) -> Option<usize>,
+    ) {
+        self.taps.tick();
+        for ((mux, keys), states) in self.keys.iter_mut().enumerate().zip(ch_states) {
+            for ((chan, key), state) in keys.iter_mut().enumerate().zip(&states.states) {
+                if key.phase == Phase::Up {
+                    match slot_of(mux, chan) {
+                        Some(slot) if slot < MAX_SLOTS => key.slot = slot,
+                        _ => continue,
+                    }
+                }
+                let slot = &self.slots[key.slot];
+                key.update(state.travel(travel), settings, slot, &mut self.taps);
+            }
+        }
+    }
+
+    /// Every HID keyboard usage that's down right now (may repeat)
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        let held = self.keys.iter().flatten().flat_map(|key| key.held);
+        let tapped = self
+            .taps
+            .0
+            .iter()
+            .filter(|(_, left)| *left > 0)
+            .map(|(key, _)| *key);
+        held.chain(tapped).filter(|key| *key != 0)
+    }
+}
+
+impl Default for Dks {
+    fn default() -> Self {
+        Dks::new(default_slots())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::travel::{SwitchCurve, Units};
+    use std::vec::Vec;
+
+    const REST: u16 = 2000;
+    const W: u8 = 0x1A;
+    const SPACE: u8 = 0x2C;
+    const TRAVEL: Travel = Travel {
+        units: Units::Travel,
+        curve: SwitchCurve::Linear,
+        full_travel: 400,
+        bottom_out_swing: 1000,
+    };
+    const SETTINGS: Settings = Settings {
+        actuation: 100,
+        bottom_out: 300,
+        tap_sweeps: 2,
+    };
+
+    /// Runs a sweep with key (0, 0) bound to slot 0 at *travel* (0.01mm) and returns the keys
+    /// that are down afterwards
+    fn sweep(dks: &mut Dks, ch_states: &mut [ChannelStates], travel: u16) -> Vec<u8> {
+        // 2.5mV per 0.01mm with the swing and travel above
+        let mv = travel * 5 / 2;
+        ch_states[0][0].value = match config::KEYBOARD_NORTH_DOWN {
+            0 => REST + mv,
+            _ => REST - mv,
+        };
+        dks.sweep(ch_states, &TRAVEL, &SETTINGS, |mux, chan| {
+            (mux == 0 && chan == 0).then_some(0)
+        });
+        dks.keys().collect()
+    }
+
+    fn setup(slot: Slot) -> (Dks, [ChannelStates; NUM_MULTIPLEXERS]) {
+        let mut ch_states: [ChannelStates; NUM_MULTIPLEXERS] = Default::default();
+        for states in ch_states.iter_mut() {
+            for chan in 0..MUX_CHANNELS as usize {
+                states.update_default_by_index(chan, REST);
+            }
+        }
+        let mut slots = [[Step::default(); Point::COUNT]; MAX_SLOTS];
+        slots[0] = slot;
+        (Dks::new(slots), ch_states)
+    }
+
+    #[test]
+    fn hold_and_tap_on_bottom_out() {
+        let mut slot = [Step::default(); Point::COUNT];
+        slot[Point::Press as usize] = Step {
+            op: Op::Down,
+            key: W,
+        };
+        slot[Point::BottomOut as usize] = Step {
+            op: Op::Tap,
+            key: SPACE,
+        };
+        let (mut dks, mut ch_states) = setup(slot);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 50), []);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 150), [W]);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 350), [W, SPACE]);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 350), [W, SPACE]);
+        // The tap is over after two sweeps
+        assert_eq!(sweep(&mut dks, &mut ch_states, 350), [W]);
+        // Within the hysteresis of the actuation point W stays down
+        assert_eq!(sweep(&mut dks, &mut ch_states, 95), [W]);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 80), []);
+    }
+
+    #[test]
+    fn release_on_lift() {
+        let mut slot = [Step::default(); Point::COUNT];
+        slot[Point::Press as usize] = Step {
+            op: Op::Down,
+            key: W,
+        };
+        slot[Point::Lift as usize] = Step { op: Op::Up, key: W };
+        slot[Point::Release as usize] = Step {
+            op: Op::Tap,
+            key: SPACE,
+        };
+        let (mut dks, mut ch_states) = setup(slot);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 310), [W]);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 250), []);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 0), [SPACE]);
+    }
+
+    #[test]
+    fn slot_is_latched_while_pressed() {
+        let mut slot = [Step::default(); Point::COUNT];
+        slot[Point::Press as usize] = Step {
+            op: Op::Down,
+            key: W,
+        };
+        let (mut dks, mut ch_states) = setup(slot);
+        assert_eq!(sweep(&mut dks, &mut ch_states, 150), [W]);
+        // Unbinding the key (e.g. a layer change) doesn't leave W stuck
+        ch_states[0][0].value = REST;
+        dks.sweep(&ch_states, &TRAVEL, &SETTINGS, |_, _| None);
+        assert_eq!(dks.keys().count(), 0);
+    }
+
+    #[test]
+    fn set_checks_the_slot() {
+        let mut dks = Dks::default();
+        let step = Step {
+            op: Op::Tap,
+            key: W,
+        };
+        assert!(dks.set(1, Point::Lift, step));
+        assert_eq!(dks.slots()[1][Point::Lift as usize], step);
+        assert!(!dks.set(MAX_SLOTS, Point::Lift, step));
+    }
+}
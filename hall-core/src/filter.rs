@@ -0,0 +1,134 @@
+//! Noise filters that get applied to raw ADC readings before check_channel() evaluates them
+//!
+//! At low actuation thresholds a single noisy ADC sample is enough to cause a phantom press so
+//! every channel can smooth its readings with one of these.
+
+use arraydeque::{ArrayDeque, Wrapping};
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of readings a channel keeps around for the windowed filters
+pub const SMOOTHING: usize = 8;
+
+/// Recent readings for a single channel
+pub type History = ArrayDeque<u16, SMOOTHING, Wrapping>;
+
+/// Which filter a channel uses (and its settings)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Filter {
+    /// Use the raw readings as-is
+    #[default]
+    None,
+    /// Average of the last *window* readings
+    Boxcar { window: u8 },
+    /// Exponential moving average where each new reading counts for *alpha*/256
+    Iir { alpha: u8 },
+    /// Median of the last *window* readings (great at rejecting single-sample spikes)
+    Median { window: u8 },
+}
+
+impl Filter {
+    /// Builds a filter from the integer settings in `KeyboardConfig`
+    /// (kind: 0 = none, 1 = boxcar, 2 = IIR, 3 = median)
+    pub fn from_config(kind: u8, window: u8, alpha: u8) -> Filter {
+        let window = window.clamp(1, SMOOTHING as u8);
+        match kind {
+            1 => Filter::Boxcar { window },
+            2 => Filter::Iir { alpha: alpha.max(1) },
+            3 => Filter::Median { window },
+            _ => Filter::None,
+        }
+    }
+
+    /// Feeds *reading* into the filter and returns the filtered value.
+    /// *history* holds the recent readings and *iir* the (8-bit fixed point) IIR state.
+    pub fn apply(&self, history: &mut History, iir: &mut u32, reading: u16) -> u16 {
+        match *self {
+            Filter::None => reading,
+            Filter::Boxcar { window } => {
+                history.push_back(reading);
+                let window = (window as usize).min(history.len());
+                let sum: u32 = history.iter().rev().take(window).map(|v| *v as u32).sum();
+                (sum / window as u32) as u16
+            }
+            Filter::Iir { alpha } => {
+                let target = (reading as u32) << 8;
+                if *iir == 0 {
+                    *iir = target; // First reading; nothing to smooth yet
+                } else if target > *iir {
+                    *iir += (target - *iir) * alpha as u32 / 256;
+                } else {
+                    *iir -= (*iir - target) * alpha as u32 / 256;
+                }
+                (*iir >> 8) as u16
+            }
+            Filter::Median { window } => {
+                history.push_back(reading);
+                let window = (window as usize).min(history.len());
+                let mut sorted = [0u16; SMOOTHING];
+                for (slot, value) in sorted.iter_mut().zip(history.iter().rev().take(window)) {
+                    *slot = *value;
+                }
+                let sorted = &mut sorted[..window];
+                sorted.sort_unstable();
+                sorted[window / 2]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs every reading through *filter* and returns what came out
+    fn run(filter: Filter, readings: &[u16]) -> [u16; 6] {
+        let (mut history, mut iir) = (History::new(), 0);
+        let mut out = [0; 6];
+        for (slot, &reading) in out.iter_mut().zip(readings) {
+            *slot = filter.apply(&mut history, &mut iir, reading);
+        }
+        out
+    }
+
+    #[test]
+    fn from_config() {
+        assert_eq!(Filter::from_config(0, 4, 64), Filter::None);
+        assert_eq!(Filter::from_config(1, 4, 64), Filter::Boxcar { window: 4 });
+        assert_eq!(Filter::from_config(1, 0, 64), Filter::Boxcar { window: 1 });
+        assert_eq!(
+            Filter::from_config(3, 200, 64),
+            Filter::Median {
+                window: SMOOTHING as u8
+            }
+        );
+        assert_eq!(Filter::from_config(2, 4, 0), Filter::Iir { alpha: 1 });
+    }
+
+    #[test]
+    fn none_passes_readings_through() {
+        let readings = [1700, 1710, 1690, 2500, 1700, 1705];
+        assert_eq!(run(Filter::None, &readings), readings);
+    }
+
+    #[test]
+    fn boxcar_averages_the_window() {
+        let filter = Filter::Boxcar { window: 3 };
+        let out = run(filter, &[1200, 1500, 1800, 2100, 2100, 2100]);
+        assert_eq!(out, [1200, 1350, 1500, 1800, 2000, 2100]);
+    }
+
+    #[test]
+    fn iir_moves_by_alpha() {
+        // Half of the way there every reading
+        let filter = Filter::Iir { alpha: 128 };
+        let out = run(filter, &[1000, 2000, 2000, 2000, 1000, 1000]);
+        assert_eq!(out, [1000, 1500, 1750, 1875, 1437, 1218]);
+    }
+
+    #[test]
+    fn median_rejects_spikes() {
+        let filter = Filter::Median { window: 3 };
+        let out = run(filter, &[1700, 1702, 3000, 1704, 1701, 0]);
+        assert_eq!(out, [1700, 1702, 1702, 1704, 1704, 1701]);
+    }
+}
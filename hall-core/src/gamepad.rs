@@ -0,0 +1,173 @@
+//! Analog gamepad output: keys bound to a stick direction or a trigger (see the `GP_*` codes in
+//! the firmware's keymap.rs) push it as far as they've been pressed
+//!
+//! How far a key has gone is its travel (see travel.rs) so the same calibrated readings
+//! check_channel() goes by drive the gamepad.  Travel within the deadzone counts for nothing,
+//! the rest gets scaled to the full range of the axis and bent by the response curve.  The two
+//! keys of a stick axis (e.g. A and D for left/right) pull against each other: pressing both
+//! equally far leaves the stick centred.
+
+use crate::config_structs::GamepadConfig;
+use crate::travel::CURVE_SCALE;
+
+/// Size (in bytes) of a gamepad report: the four stick axes then the two triggers (all i16)
+pub const REPORT_SIZE: usize = 12;
+/// How far a stick (either way) or trigger goes
+pub const AXIS_MAX: i16 = 32767;
+
+/// The stick axes in the order they're reported (positive X is right, positive Y is down)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stick {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+}
+
+/// What a key can be bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// Push the stick towards the negative end of the axis (left or up)
+    Negative(Stick),
+    /// Push the stick towards the positive end of the axis (right or down)
+    Positive(Stick),
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl Input {
+    /// Number of inputs (the valid indexes for from_index())
+    pub const COUNT: u8 = 10;
+
+    /// The input with the given index (left stick left/right/up/down, right stick
+    /// left/right/up/down, left trigger, right trigger)
+    pub fn from_index(index: u8) -> Option<Input> {
+        const STICKS: [Stick; 4] = [Stick::LeftX, Stick::LeftY, Stick::RightX, Stick::RightY];
+        match index {
+            0..=7 => {
+                let stick = STICKS[index as usize / 2];
+                Some(match index & 1 {
+                    0 => Input::Negative(stick),
+                    _ => Input::Positive(stick),
+                })
+            }
+            8 => Some(Input::LeftTrigger),
+            9 => Some(Input::RightTrigger),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> u8 {
+        match self {
+            Input::Negative(stick) => stick as u8 * 2,
+            Input::Positive(stick) => stick as u8 * 2 + 1,
+            Input::LeftTrigger => 8,
+            Input::RightTrigger => 9,
+        }
+    }
+}
+
+/// How the travel past the deadzone maps to how far the axis goes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    #[default]
+    Linear,
+    /// Fine control near the top of the travel, ramping up towards the bottom (quadratic)
+    Smooth,
+    /// Most of the axis early on, leveling off towards the bottom (inverse quadratic)
+    Fast,
+}
+
+impl Response {
+    pub fn from_config(curve: u8) -> Response {
+        match curve {
+            1 => Response::Smooth,
+            2 => Response::Fast,
+            _ => Response::Linear,
+        }
+    }
+
+    /// Maps a fraction of the travel to a fraction of the axis (both out of CURVE_SCALE)
+    pub fn apply(&self, fraction: u32) -> u32 {
+        let fraction = fraction.min(CURVE_SCALE);
+        match self {
+            Response::Linear => fraction,
+            Response::Smooth => fraction * fraction / CURVE_SCALE,
+            Response::Fast => {
+                let rest = CURVE_SCALE - fraction;
+                CURVE_SCALE - rest * rest / CURVE_SCALE
+            }
+        }
+    }
+}
+
+/// Everything needed to turn travel into axis values
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Travel (0.01mm) that doesn't count for anything
+    pub deadzone: u16,
+    /// Travel (0.01mm) that counts as all the way
+    pub full_travel: u16,
+    pub response: Response,
+}
+
+impl Settings {
+    pub fn from_config(gamepad: &GamepadConfig, full_travel: u16) -> Settings {
+        Settings {
+            deadzone: gamepad.deadzone,
+            full_travel,
+            response: Response::from_config(gamepad.curve),
+        }
+    }
+
+    /// How far (0 to AXIS_MAX) a key that has travelled *travel* (0.01mm) pushes its input
+    pub fn deflection(&self, travel: u16) -> i16 {
+        if travel <= self.deadzone {
+            return 0;
+        }
+        let range = self.full_travel.saturating_sub(self.deadzone).max(1) as u32;
+        let fraction = ((travel - self.deadzone) as u32).min(range) * CURVE_SCALE / range;
+        (self.response.apply(fraction) * AXIS_MAX as u32 / CURVE_SCALE) as i16
+    }
+}
+
+/// Where the sticks and triggers are (as the bytes that go out over USB)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Report([u8; REPORT_SIZE]);
+
+impl Report {
+    /// Works out the report from the travel (0.01mm) of every key bound to an input.  When
+    /// several keys are bound to the same input the deepest one counts.
+    pub fn from_travel(
+        keys: impl IntoIterator<Item = (Input, u16)>,
+        settings: &Settings,
+    ) -> Report {
+        let mut negative = [0i16; 4];
+        let mut positive = [0i16; 4];
+        let mut triggers = [0i16; 2];
+        for (input, travel) in keys {
+            let deflection = settings.deflection(travel);
+            let slot = match input {
+                Input::Negative(stick) => &mut negative[stick as usize],
+                Input::Positive(stick) => &mut positive[stick as usize],
+                Input::LeftTrigger => &mut triggers[0],
+                Input::RightTrigger => &mut triggers[1],
+            };
+            *slot = (*slot).max(deflection);
+        }
+        let sticks = negative
+            .iter()
+            .zip(positive)
+            .map(|(negative, positive)| positive - negative);
+        let mut report = Report::default();
+        for (chunk, value) in report.0.chunks_exact_mut(2).zip(sticks.chain(triggers)) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        report
+    }
+
+    /// Returns the report as it gets sent over the wire
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
@@ -0,0 +1,267 @@
+//! HID keyboard usages (the same names keyberon uses so layers.rs reads the same)
+//!
+//! Only the usages a keyboard report can carry are here: everything from `No` (0x00) to `ExSel`
+//! (0xA4) and the eight modifiers (0xE0-0xE7).
+
+/// A HID keyboard usage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum KeyCode {
+    No = 0x00,
+    ErrorRollOver,
+    PostFail,
+    ErrorUndefined,
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+    I,
+    J,
+    K,
+    L,
+    M, // 0x10
+    N,
+    O,
+    P,
+    Q,
+    R,
+    S,
+    T,
+    U,
+    V,
+    W,
+    X,
+    Y,
+    Z,
+    Kb1,
+    Kb2,
+    Kb3, // 0x20
+    Kb4,
+    Kb5,
+    Kb6,
+    Kb7,
+    Kb8,
+    Kb9,
+    Kb0,
+    Enter,
+    Escape,
+    BSpace,
+    Tab,
+    Space,
+    Minus,
+    Equal,
+    LBracket,
+    RBracket, // 0x30
+    Bslash,
+    NonUsHash,
+    SColon,
+    Quote,
+    Grave,
+    Comma,
+    Dot,
+    Slash,
+    CapsLock,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7, // 0x40
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PScreen,
+    ScrollLock,
+    Pause,
+    Insert,
+    Home,
+    PgUp,
+    Delete,
+    End,
+    PgDown,
+    Right,
+    Left, // 0x50
+    Down,
+    Up,
+    NumLock,
+    KpSlash,
+    KpAsterisk,
+    KpMinus,
+    KpPlus,
+    KpEnter,
+    Kp1,
+    Kp2,
+    Kp3,
+    Kp4,
+    Kp5,
+    Kp6,
+    Kp7,
+    Kp8, // 0x60
+    Kp9,
+    Kp0,
+    KpDot,
+    NonUsBslash,
+    Application,
+    Power,
+    KpEqual,
+    F13,
+    F14,
+    F15,
+    F16,
+    F17,
+    F18,
+    F19,
+    F20,
+    F21, // 0x70
+    F22,
+    F23,
+    F24,
+    Execute,
+    Help,
+    Menu,
+    Select,
+    Stop,
+    Again,
+    Undo,
+    Cut,
+    Copy,
+    Paste,
+    Find,
+    Mute,
+    VolUp, // 0x80
+    VolDown,
+    LockingCapsLock,
+    LockingNumLock,
+    LockingScrollLock,
+    KpComma,
+    KpEqualSign,
+    Intl1,
+    Intl2,
+    Intl3,
+    Intl4,
+    Intl5,
+    Intl6,
+    Intl7,
+    Intl8,
+    Intl9,
+    Lang1, // 0x90
+    Lang2,
+    Lang3,
+    Lang4,
+    Lang5,
+    Lang6,
+    Lang7,
+    Lang8,
+    Lang9,
+    AltErase,
+    SysReq,
+    Cancel,
+    Clear,
+    Prior,
+    Return,
+    Separator,
+    Out, // 0xA0
+    Oper,
+    ClearAgain,
+    CrSel,
+    ExSel,
+    LCtrl = 0xE0,
+    LShift,
+    LAlt,
+    LGui,
+    RCtrl,
+    RShift,
+    RAlt,
+    RGui,
+}
+
+/// Every usage from No (0x00) to ExSel (0xA4) in order
+#[rustfmt::skip]
+const USAGES: [KeyCode; 0xA5] = {
+    use KeyCode::*;
+    [
+        No, ErrorRollOver, PostFail, ErrorUndefined, A, B, C, D, // 0x00
+        E, F, G, H, I, J, K, L, // 0x08
+        M, N, O, P, Q, R, S, T, // 0x10
+        U, V, W, X, Y, Z, Kb1, Kb2, // 0x18
+        Kb3, Kb4, Kb5, Kb6, Kb7, Kb8, Kb9, Kb0, // 0x20
+        Enter, Escape, BSpace, Tab, Space, Minus, Equal, LBracket, // 0x28
+        RBracket, Bslash, NonUsHash, SColon, Quote, Grave, Comma, Dot, // 0x30
+        Slash, CapsLock, F1, F2, F3, F4, F5, F6, // 0x38
+        F7, F8, F9, F10, F11, F12, PScreen, ScrollLock, // 0x40
+        Pause, Insert, Home, PgUp, Delete, End, PgDown, Right, // 0x48
+        Left, Down, Up, NumLock, KpSlash, KpAsterisk, KpMinus, KpPlus, // 0x50
+        KpEnter, Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, // 0x58
+        Kp8, Kp9, Kp0, KpDot, NonUsBslash, Application, Power, KpEqual, // 0x60
+        F13, F14, F15, F16, F17, F18, F19, F20, // 0x68
+        F21, F22, F23, F24, Execute, Help, Menu, Select, // 0x70
+        Stop, Again, Undo, Cut, Copy, Paste, Find, Mute, // 0x78
+        VolUp, VolDown, LockingCapsLock, LockingNumLock, LockingScrollLock, KpComma, KpEqualSign, Intl1, // 0x80
+        Intl2, Intl3, Intl4, Intl5, Intl6, Intl7, Intl8, Intl9, // 0x88
+        Lang1, Lang2, Lang3, Lang4, Lang5, Lang6, Lang7, Lang8, // 0x90
+        Lang9, AltErase, SysReq, Cancel, Clear, Prior, Return, Separator, // 0x98
+        Out, Oper, ClearAgain, CrSel, ExSel, // 0xA0
+    ]
+};
+
+/// The modifiers (LCtrl, 0xE0, to RGui, 0xE7) in order
+const MODIFIERS: [KeyCode; 8] = {
+    use KeyCode::*;
+    [LCtrl, LShift, LAlt, LGui, RCtrl, RShift, RAlt, RGui]
+};
+
+impl KeyCode {
+    /// The key for a HID keyboard usage (if it's one of the ones above)
+    pub fn from_usage(usage: u8) -> Option<KeyCode> {
+        match usage {
+            0xE0..=0xE7 => Some(MODIFIERS[(usage - 0xE0) as usize]),
+            _ => USAGES.get(usage as usize).copied(),
+        }
+    }
+
+    /// Returns true for the modifiers (they go in a report's modifier byte)
+    pub fn is_modifier(self) -> bool {
+        KeyCode::LCtrl <= self && self <= KeyCode::RGui
+    }
+
+    /// The modifier byte bit of a modifier (0 for anything else)
+    pub fn as_modifier_bit(self) -> u8 {
+        if self.is_modifier() {
+            1 << (self as u8 - KeyCode::LCtrl as u8)
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_usage() {
+        for usage in 0..=u8::MAX {
+            match KeyCode::from_usage(usage) {
+                Some(kc) => assert_eq!(kc as u8, usage),
+                None => assert!(usage > 0xA4 && !(0xE0..=0xE7).contains(&usage)),
+            }
+        }
+        assert_eq!(KeyCode::from_usage(0x04), Some(KeyCode::A));
+        assert_eq!(KeyCode::from_usage(0xE1), Some(KeyCode::LShift));
+    }
+
+    #[test]
+    fn modifiers() {
+        assert!(KeyCode::RGui.is_modifier());
+        assert!(!KeyCode::ExSel.is_modifier());
+        assert_eq!(KeyCode::LShift.as_modifier_bit(), 0x02);
+        assert_eq!(KeyCode::A.as_modifier_bit(), 0);
+    }
+}
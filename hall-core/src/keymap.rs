@@ -0,0 +1,395 @@
+//! The keymap as it's used at runtime (seeded from `layers::LAYERS` but editable over the serial
+//! console and saved along with the rest of the settings)
+//!
+//! Actions can't be stored or sent anywhere (they're full of `&'static` references) so
+//! every key is described by a 16-bit code instead.  The codes are the same ones QMK (and by
+//! extension VIA) uses:
+//!
+//! | Code              | Action                                     |
+//! |-------------------|--------------------------------------------|
+//! | `0x0000`          | Nothing (`KC_NO`)                          |
+//! | `0x0001`          | Transparent (`KC_TRNS`)                    |
+//! | `0x0004`-`0x00E7` | HID keyboard usage (e.g. `0x0004` = A)     |
+//! | `0x2000`-`0x3FFF` | Mod-tap by depth (`MT(mods, kc)`)          |
+//! | `0x5200` + layer  | Switch the default layer (`TO(layer)`)     |
+//! | `0x5220` + layer  | Momentary layer (`MO(layer)`)              |
+//! | `0x5700` + index  | Tap dance (`TD(n)`; see `TAP_DANCES`)      |
+//! | `0x7C00`          | Reboot into the bootloader (`QK_BOOT`)     |
+//! | `0x7E00`          | Full-travel calibration (`QK_CALIBRATE`)   |
+//! | `0x7E10` + input  | Gamepad stick direction/trigger (`GP_*`)   |
+//! | `0x7E20` + slot   | Dynamic keystroke (`DKS_*`)                |
+//! | `0xFFFF`          | Whatever `layers::LAYERS` has for this key |
+//!
+//! The keymap also holds the (VIA) macro buffer: `MACRO_COUNT` NUL-terminated macros packed
+//! into `MACRO_BUFFER_SIZE` bytes.  The macros get stored/saved but there's no way to play them
+//! back yet (so the QMK macro keycodes aren't accepted).
+
+use crate::action::Action;
+use crate::key_code::KeyCode;
+use crate::layers::{LAYERS, TAP_DANCES};
+use crate::layout;
+use crate::userconfig::NUM_MULTIPLEXERS;
+use crate::{dks, gamepad, modtap};
+use heapless::Deque;
+
+/// Number of layers (same as `layers::LAYERS`)
+pub const NUM_LAYERS: usize = 7;
+/// Number of keys (channels) per multiplexer (row) in the layout
+pub const NUM_COLUMNS: usize = 16;
+
+pub const KC_NO: u16 = 0x0000;
+pub const KC_TRNS: u16 = 0x0001;
+/// Tap key in the low byte, QMK's 5-bit modifiers in the next 5 bits
+pub const QK_MOD_TAP: u16 = 0x2000;
+pub const QK_MOD_TAP_MAX: u16 = 0x3FFF;
+pub const QK_TO: u16 = 0x5200;
+pub const QK_MOMENTARY: u16 = 0x5220;
+/// Plus the index of a tap dance in `layers::TAP_DANCES`
+pub const QK_TAP_DANCE: u16 = 0x5700;
+pub const QK_BOOT: u16 = 0x7C00;
+/// Start a full-travel calibration (the first of QMK's keyboard-specific codes, QK_KB_0)
+pub const QK_CALIBRATE: u16 = 0x7E00;
+/// Drive a gamepad input by how far the key is pressed (plus `gamepad::Input::index()`)
+pub const QK_GAMEPAD: u16 = 0x7E10;
+/// Do whatever a DKS slot says at each point of the key's travel (plus the slot)
+pub const QK_DKS: u16 = 0x7E20;
+/// Keep the compiled-in action (used for anything that doesn't have a code)
+pub const KC_DEFAULT: u16 = 0xFFFF;
+
+/// What `Action::Custom` actions do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomAction {
+    /// Reboot into the bootloader (when released)
+    Bootloader,
+    /// Start a full-travel calibration (see calibrate.rs)
+    Calibrate,
+    /// Push a gamepad stick/trigger (see gamepad.rs; nothing happens on press/release)
+    Gamepad(gamepad::Input),
+    /// Use a DKS slot (see dks.rs; nothing happens on press/release either)
+    Dks(usize),
+    /// Tap key or modifiers depending on how far the key is pressed (see modtap.rs;
+    /// `Action::HoldTap` is the time-based version)
+    ModTap(modtap::ModTap),
+}
+
+/// All of the layers as actions
+pub type Actions = layout::Layers<NUM_COLUMNS, NUM_MULTIPLEXERS, NUM_LAYERS, CustomAction>;
+/// The layout the actions go into
+pub type Layout = layout::Layout<NUM_COLUMNS, NUM_MULTIPLEXERS, NUM_LAYERS, CustomAction>;
+/// All of the layers as codes
+pub type Codes = [[[u16; NUM_COLUMNS]; NUM_MULTIPLEXERS]; NUM_LAYERS];
+
+/// Number of macros in the macro buffer
+pub const MACRO_COUNT: u8 = 16;
+/// Size of the macro buffer (in bytes)
+pub const MACRO_BUFFER_SIZE: usize = 512;
+/// The macro buffer, split into chunks since serde only handles arrays of up to 32 elements
+pub type MacroBuffer = [[u8; 32]; MACRO_BUFFER_SIZE / 32];
+
+/// Turns an action into its code
+pub fn to_code(action: &Action<CustomAction>) -> u16 {
+    match action {
+        Action::NoOp => KC_NO,
+        Action::Trans => KC_TRNS,
+        Action::KeyCode(kc) => *kc as u16,
+        Action::DefaultLayer(layer) if *layer < NUM_LAYERS => QK_TO + *layer as u16,
+        Action::Layer(layer) if *layer < NUM_LAYERS => QK_MOMENTARY + *layer as u16,
+        Action::Custom(CustomAction::Bootloader) => QK_BOOT,
+        Action::Custom(CustomAction::Calibrate) => QK_CALIBRATE,
+        Action::Custom(CustomAction::Gamepad(input)) => QK_GAMEPAD + input.index() as u16,
+        Action::Custom(CustomAction::Dks(slot)) if *slot < dks::MAX_SLOTS => QK_DKS + *slot as u16,
+        Action::Custom(CustomAction::ModTap(mod_tap)) => {
+            QK_MOD_TAP | (mod_tap.qmk_mods() as u16) << 8 | mod_tap.tap as u16
+        }
+        Action::TapDance(dance) => TAP_DANCES
+            .iter()
+            .position(|d| core::ptr::eq(d, *dance))
+            .map_or(KC_DEFAULT, |index| QK_TAP_DANCE + index as u16),
+        _ => KC_DEFAULT,
+    }
+}
+
+/// Turns a code into an action (*default* being the compiled-in action for the same key).
+/// Returns None if the code isn't one we know.
+pub fn to_action(
+    code: u16,
+    default: &Action<CustomAction>,
+) -> Option<Action<CustomAction>> {
+    match code {
+        KC_NO => Some(Action::NoOp),
+        KC_TRNS => Some(Action::Trans),
+        0x0002..=0x00FF => KeyCode::from_usage(code as u8).map(Action::KeyCode),
+        QK_MOD_TAP..=QK_MOD_TAP_MAX => {
+            let (mods, tap) = ((code >> 8) as u8 & 0x1F, code as u8);
+            let valid = mods & 0x0F != 0 && matches!(tap, 0x04..=0xA4 | 0xE0..=0xE7);
+            valid.then(|| Action::Custom(CustomAction::ModTap(modtap::ModTap::from_qmk(mods, tap))))
+        }
+        c if (QK_TO..QK_TO + NUM_LAYERS as u16).contains(&c) => {
+            Some(Action::DefaultLayer((c - QK_TO) as usize))
+        }
+        c if (QK_MOMENTARY..QK_MOMENTARY + NUM_LAYERS as u16).contains(&c) => {
+            Some(Action::Layer((c - QK_MOMENTARY) as usize))
+        }
+        QK_BOOT => Some(Action::Custom(CustomAction::Bootloader)),
+        QK_CALIBRATE => Some(Action::Custom(CustomAction::Calibrate)),
+        c if (QK_GAMEPAD..QK_GAMEPAD + gamepad::Input::COUNT as u16).contains(&c) => {
+            gamepad::Input::from_index((c - QK_GAMEPAD) as u8)
+                .map(|input| Action::Custom(CustomAction::Gamepad(input)))
+        }
+        c if (QK_DKS..QK_DKS + dks::MAX_SLOTS as u16).contains(&c) => {
+            Some(Action::Custom(CustomAction::Dks((c - QK_DKS) as usize)))
+        }
+        c if (QK_TAP_DANCE..QK_TAP_DANCE + TAP_DANCES.len() as u16).contains(&c) => {
+            Some(Action::TapDance(&TAP_DANCES[(c - QK_TAP_DANCE) as usize]))
+        }
+        KC_DEFAULT => Some(*default),
+        _ => None,
+    }
+}
+
+/// What a key does that the layout doesn't know about (things driven by how far it's pressed
+/// rather than by Press()/Release())
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    #[default]
+    None,
+    /// Whatever the key does on the default layer
+    Transparent,
+    Gamepad(gamepad::Input),
+    Dks(usize),
+    ModTap(modtap::ModTap),
+}
+
+impl Binding {
+    pub fn of(action: &Action<CustomAction>) -> Binding {
+        match action {
+            Action::Trans => Binding::Transparent,
+            Action::Custom(CustomAction::Gamepad(input)) => Binding::Gamepad(*input),
+            Action::Custom(CustomAction::Dks(slot)) => Binding::Dks(*slot),
+            Action::Custom(CustomAction::ModTap(mod_tap)) => Binding::ModTap(*mod_tap),
+            _ => Binding::None,
+        }
+    }
+}
+
+/// The binding of every key on every layer (kept by whoever drives the layout since it doesn't
+/// hand its actions back out)
+pub type Bindings = [[[Binding; NUM_COLUMNS]; NUM_MULTIPLEXERS]; NUM_LAYERS];
+
+/// What a key (multiplexer, channel) is bound to with *layer* active
+pub fn binding(
+    bindings: &Bindings,
+    layer: usize,
+    default_layer: usize,
+    mux: usize,
+    chan: usize,
+) -> Binding {
+    let get = |layer: usize| bindings[layer].get(mux).and_then(|row| row.get(chan)).copied();
+    match get(layer) {
+        Some(Binding::Transparent) => get(default_layer).unwrap_or_default(),
+        binding => binding.unwrap_or_default(),
+    }
+}
+
+/// Every key (multiplexer, channel) that drives a gamepad input with *layer* active
+pub fn gamepad_keys(
+    bindings: &Bindings,
+    layer: usize,
+    default_layer: usize,
+) -> impl Iterator<Item = (usize, usize, gamepad::Input)> + '_ {
+    (0..NUM_MULTIPLEXERS).flat_map(move |mux| {
+        (0..NUM_COLUMNS).filter_map(move |chan| {
+            match binding(bindings, layer, default_layer, mux, chan) {
+                Binding::Gamepad(input) => Some((mux, chan, input)),
+                _ => None,
+            }
+        })
+    })
+}
+
+/// The DKS slot a key (multiplexer, channel) uses with *layer* active (if any)
+pub fn dks_slot(
+    bindings: &Bindings,
+    layer: usize,
+    default_layer: usize,
+    mux: usize,
+    chan: usize,
+) -> Option<usize> {
+    match binding(bindings, layer, default_layer, mux, chan) {
+        Binding::Dks(slot) => Some(slot),
+        _ => None,
+    }
+}
+
+/// The mod-tap a key (multiplexer, channel) does with *layer* active (if any)
+pub fn mod_tap(
+    bindings: &Bindings,
+    layer: usize,
+    default_layer: usize,
+    mux: usize,
+    chan: usize,
+) -> Option<modtap::ModTap> {
+    match binding(bindings, layer, default_layer, mux, chan) {
+        Binding::ModTap(mod_tap) => Some(mod_tap),
+        _ => None,
+    }
+}
+
+/// Codes for the compiled-in keymap
+pub fn default_codes() -> Codes {
+    let mut codes = [[[KC_DEFAULT; NUM_COLUMNS]; NUM_MULTIPLEXERS]; NUM_LAYERS];
+    for (layer, rows) in LAYERS.iter().enumerate() {
+        for (mux, actions) in rows.iter().enumerate() {
+            for (chan, action) in actions.iter().enumerate() {
+                codes[layer][mux][chan] = to_code(action);
+            }
+        }
+    }
+    codes
+}
+
+/// A key that changed (so the layout can be told about it)
+#[derive(Debug, Clone, Copy)]
+pub struct Change {
+    pub layer: usize,
+    pub mux: usize,
+    pub chan: usize,
+    pub action: Action<CustomAction>,
+}
+
+/// The runtime keymap (as codes) along with any changes the layout hasn't picked up yet
+pub struct Keymap {
+    codes: Codes,
+    macros: MacroBuffer,
+    changes: Deque<Change, 32>,
+    reload: bool, // Too many changes to keep track of; the layout needs to reload everything
+}
+
+impl Keymap {
+    /// Uses the given codes/macros (e.g. loaded from flash); anything invalid keeps its LAYERS
+    /// action
+    pub fn new(codes: Codes, macros: MacroBuffer) -> Keymap {
+        let mut keymap = Keymap {
+            codes,
+            macros,
+            changes: Deque::new(),
+            reload: false,
+        };
+        for layer in keymap.codes.iter_mut() {
+            for row in layer.iter_mut() {
+                for code in row.iter_mut() {
+                    if to_action(*code, &Action::NoOp).is_none() {
+                        *code = KC_DEFAULT;
+                    }
+                }
+            }
+        }
+        keymap
+    }
+
+    pub fn codes(&self) -> &Codes {
+        &self.codes
+    }
+
+    pub fn macros(&self) -> &MacroBuffer {
+        &self.macros
+    }
+
+    /// Returns the action for the given key
+    pub fn action(&self, layer: usize, mux: usize, chan: usize) -> Action<CustomAction> {
+        let default = &LAYERS[layer][mux][chan];
+        to_action(self.codes[layer][mux][chan], default).unwrap_or(*default)
+    }
+
+    /// The binding of every key (see binding())
+    pub fn bindings(&self) -> Bindings {
+        let mut bindings = [[[Binding::None; NUM_COLUMNS]; NUM_MULTIPLEXERS]; NUM_LAYERS];
+        for (layer, rows) in bindings.iter_mut().enumerate() {
+            for (mux, row) in rows.iter_mut().enumerate() {
+                for (chan, binding) in row.iter_mut().enumerate() {
+                    *binding = Binding::of(&self.action(layer, mux, chan));
+                }
+            }
+        }
+        bindings
+    }
+
+    /// Builds the full set of actions for the layout to use
+    pub fn actions(&self) -> Actions {
+        let mut actions = LAYERS;
+        for (layer, rows) in actions.iter_mut().enumerate() {
+            for (mux, row) in rows.iter_mut().enumerate() {
+                for (chan, action) in row.iter_mut().enumerate() {
+                    if let Some(a) = to_action(self.codes[layer][mux][chan], action) {
+                        *action = a;
+                    }
+                }
+            }
+        }
+        actions
+    }
+
+    /// Returns the code for the given key
+    pub fn get(&self, layer: usize, mux: usize, chan: usize) -> Option<u16> {
+        self.codes.get(layer)?.get(mux)?.get(chan).copied()
+    }
+
+    /// Changes the code for the given key.  Returns false if the key or code isn't valid.
+    pub fn set(&mut self, layer: usize, mux: usize, chan: usize, code: u16) -> bool {
+        if self.get(layer, mux, chan).is_none() {
+            return false;
+        }
+        let Some(action) = to_action(code, &LAYERS[layer][mux][chan]) else {
+            return false;
+        };
+        self.codes[layer][mux][chan] = code;
+        if self.changes.push_back(Change { layer, mux, chan, action }).is_err() {
+            self.reload = true;
+        }
+        true
+    }
+
+    /// Puts every key back the way LAYERS has it
+    pub fn reset(&mut self) {
+        self.codes = default_codes();
+        self.changes.clear();
+        self.reload = true;
+    }
+
+    /// Hands out the next change the layout needs to know about
+    pub fn take_change(&mut self) -> Option<Change> {
+        self.changes.pop_front()
+    }
+
+    /// Returns true (once) if the layout needs to reload every action (see action())
+    pub fn take_reload(&mut self) -> bool {
+        core::mem::take(&mut self.reload)
+    }
+
+    /// Returns a byte of the macro buffer
+    pub fn macro_byte(&self, offset: usize) -> Option<u8> {
+        self.macros.get(offset / 32).map(|chunk| chunk[offset % 32])
+    }
+
+    /// Changes a byte of the macro buffer.  Returns false if *offset* is out of range.
+    pub fn set_macro_byte(&mut self, offset: usize, byte: u8) -> bool {
+        match self.macros.get_mut(offset / 32) {
+            Some(chunk) => {
+                chunk[offset % 32] = byte;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Clears every macro
+    pub fn reset_macros(&mut self) {
+        self.macros = [[0; 32]; MACRO_BUFFER_SIZE / 32];
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::new(default_codes(), [[0; 32]; MACRO_BUFFER_SIZE / 32])
+    }
+}
@@ -1,11 +1,13 @@
 //! Holds our default (initial) keyboard layout/actions
 #![allow(dead_code)]
-use keyberon::action::{k, l, Action::*};
-use keyberon::key_code::KeyCode::*;
+use crate::action::{k, l, Action::*, TapDance, TapDanceStep};
+use crate::key_code::KeyCode::*;
+use crate::keymap::CustomAction;
+use crate::layout::Layers;
 
 // NOTE: What most folks consider the "Menu" key is actually the "Application" key in Keyberon./
-// TODO: Figure out a way to read this in from a config file
-// TODO: Also figure out how to make this configurable via the USB serial port
+// NOTE: This is only the starting point; keys can be changed over the USB serial port (and get
+//       saved along with the other settings).  See keymap.rs.
 // NOTE: For the prototype we're using a 4x6-ish numpad but the keys don't have a nice 1-to-1 mapping
 //       of multiplexer-pin-to-key.  This was because routing on the PCB required compromises.
 //       Here's the schema mapping on the PCB:
@@ -22,7 +24,7 @@ use keyberon::key_code::KeyCode::*;
 
 */
 #[rustfmt::skip]
-pub static LAYERS: keyberon::layout::Layers<16, 5, 7, ()> = [
+pub static LAYERS: Layers<16, 5, 7, CustomAction> = [
     /*
     Since Keyberon was made for key switch matrices and not multi-channel analog multiplexers
     our mapping below is vastly more arbitrary and based on the tracks of the PCB rather than
@@ -181,6 +183,16 @@ pub static LAYERS: keyberon::layout::Layers<16, 5, 7, ()> = [
     ],
 ];
 
+/// Tap dances (put them on keys with TD(<index>) codes; see keymap.rs)
+#[rustfmt::skip]
+pub static TAP_DANCES: &[TapDance<CustomAction>] = &[
+    // 0: Escape when tapped once and Caps Lock twice (or held: left control and left shift)
+    TapDance { steps: &[
+        TapDanceStep { tap: k(Escape), hold: k(LCtrl) },
+        TapDanceStep { tap: k(CapsLock), hold: k(LShift) },
+    ] },
+];
+
 /// Map key locations to multiplexer pins
 pub type MuxMap = &'static [&'static [&'static [u8; 2]]];
 
@@ -0,0 +1,757 @@
+//! The equivalent of Keyberon's layout.rs but for individual hall effect sensors
+//!
+//! Keys are addressed by (multiplexer, channel) instead of keyberon's (row, column) `u8`s and
+//! take the Press()/Release() events straight from check_channel() (or socd.rs).  The actions
+//! are the ones in action.rs.  Events go into a queue and get taken off it one per tick() so a
+//! HoldTap key that's still making up its mind holds up the keys pressed after it (they get sent
+//! in the right order once it has).
+//!
+//! HoldTap timeouts (and tap_hold_interval) are in milliseconds no matter how fast tick() gets
+//! called.
+//!
+//! Tap dances (`Action::TapDance`; see `layers::TAP_DANCES`) hold up the queue the same
+//! way.  Each press of the key within the tap dance window of the last press/release counts as
+//! another tap and the dance is over once the window goes by without one (or another key gets
+//! pressed or there are no more steps).  If the key is still down by then it's the step's hold
+//! action that happens, otherwise the tap action (released with the key).
+
+use crate::action::{Action, HoldTapAction, HoldTapConfig, TapDance};
+use crate::key_code::KeyCode;
+use crate::multiplexers::Event;
+use crate::userconfig;
+use arraydeque::behavior::Wrapping;
+use arraydeque::ArrayDeque;
+use heapless::Vec;
+
+use State::*;
+
+/// Most keycodes/layer modifiers/custom actions that can be active at the same time
+pub const MAX_STATES: usize = 64;
+/// Most events that can wait in the queue (the oldest ones get forced through past that)
+pub const MAX_STACKED: usize = 16;
+
+/// A key: (multiplexer, channel)
+pub type Coord = (usize, usize);
+
+/// The action of every key (*C* channels on each of *R* multiplexers) on each of *L* layers
+pub type Layers<const C: usize, const R: usize, const L: usize, T> = [[[Action<T>; C]; R]; L];
+
+pub struct Layout<const C: usize, const R: usize, const L: usize, T: 'static> {
+    layers: &'static mut Layers<C, R, L, T>,
+    default_layer: usize,
+    ticks_per_second: u32,
+    states: Vec<State<T>, MAX_STATES>,
+    waiting: Option<WaitingState<T>>,
+    stacked: ArrayDeque<Stacked, MAX_STACKED, Wrapping>,
+    last_tap: Option<LastTap>,
+    dance: Option<DanceState<T>>,
+    tap_dance_window: u16,
+    custom_event: CustomEvent<T>,
+}
+
+/// A custom action (see keymap.rs) being pressed or released
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CustomEvent<T> {
+    NoEvent,
+    Press(T),
+    Release(T),
+}
+
+impl<T> CustomEvent<T> {
+    /// Keeps the first event of a tick
+    fn update(&mut self, event: CustomEvent<T>) {
+        if matches!(self, CustomEvent::NoEvent) {
+            *self = event;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum State<T> {
+    NormalKey { keycode: KeyCode, coord: Coord },
+    LayerModifier { value: usize, coord: Coord },
+    Custom { value: T, coord: Coord },
+}
+
+impl<T: Copy> State<T> {
+    fn keycode(&self) -> Option<KeyCode> {
+        match self {
+            NormalKey { keycode, .. } => Some(*keycode),
+            _ => None,
+        }
+    }
+
+    fn coord(&self) -> Coord {
+        match *self {
+            NormalKey { coord, .. } | LayerModifier { coord, .. } | Custom { coord, .. } => coord,
+        }
+    }
+
+    fn get_layer(&self) -> Option<usize> {
+        match self {
+            LayerModifier { value, .. } => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// What a HoldTap key turned out to be
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum WaitingAction {
+    Hold,
+    Tap,
+}
+
+/// A HoldTap key that hasn't made up its mind yet
+#[derive(Debug, Copy, Clone)]
+struct WaitingState<T: 'static> {
+    coord: Coord,
+    /// Ticks since the key was pressed
+    ticks: u32,
+    /// Ticks it becomes a hold after
+    timeout: u32,
+    hold_tap: &'static HoldTapAction<T>,
+}
+
+impl<T> WaitingState<T> {
+    /// Counts a tick and works out whether the key is a hold or a tap yet from the events that
+    /// came in after it
+    fn tick(
+        &mut self,
+        stacked: &ArrayDeque<Stacked, MAX_STACKED, Wrapping>,
+    ) -> Option<WaitingAction> {
+        self.ticks = self.ticks.saturating_add(1);
+        // Events that happened after the timeout don't count
+        let in_time = stacked
+            .iter()
+            .filter(|s| self.ticks.saturating_sub(s.since) < self.timeout);
+        let mut pressed: Vec<Coord, MAX_STACKED> = Vec::new();
+        for s in in_time {
+            if s.event == Event::Release(self.coord.0, self.coord.1) {
+                return Some(WaitingAction::Tap);
+            }
+            match (&self.hold_tap.config, s.event) {
+                (HoldTapConfig::HoldOnOtherKeyPress, Event::Press(..)) => {
+                    return Some(WaitingAction::Hold)
+                }
+                (HoldTapConfig::PermissiveHold, Event::Press(mux, chan)) => {
+                    let _ = pressed.push((mux, chan));
+                }
+                (HoldTapConfig::PermissiveHold, Event::Release(mux, chan))
+                    if pressed.contains(&(mux, chan)) =>
+                {
+                    return Some(WaitingAction::Hold)
+                }
+                _ => (),
+            }
+        }
+        (self.ticks >= self.timeout).then_some(WaitingAction::Hold)
+    }
+}
+
+/// How a tap dance ended
+#[derive(Debug, Copy, Clone)]
+struct DanceEnd<T: 'static> {
+    action: Action<T>,
+    /// How many of the queued events came in before the end
+    used: usize,
+    /// The queued release of the last tap (it stays in the queue to release the action)
+    release: Option<usize>,
+}
+
+/// A tap-dance key that's still being tapped
+#[derive(Debug, Copy, Clone)]
+struct DanceState<T: 'static> {
+    coord: Coord,
+    /// Ticks since the key was first pressed
+    ticks: u32,
+    dance: &'static TapDance<T>,
+}
+
+impl<T: Copy> DanceState<T> {
+    /// Counts a tick and works out whether the dance is over yet from the events that came in
+    /// after it (*window* being in ticks).  *force* ends it no matter what.
+    fn tick(
+        &mut self,
+        stacked: &ArrayDeque<Stacked, MAX_STACKED, Wrapping>,
+        window: u32,
+        force: bool,
+    ) -> Option<DanceEnd<T>> {
+        self.ticks = self.ticks.saturating_add(1);
+        let own = |s: &Stacked| (s.event.coord() == self.coord).then_some(s.event);
+        let mut taps = 1;
+        let mut down = true;
+        let mut release = None;
+        let mut last = self.ticks; // Ticks since the key was last pressed/released
+        let mut end = None;
+        let mut interrupted = false;
+        for (index, s) in stacked.iter().enumerate() {
+            if last.saturating_sub(s.since) >= window {
+                end = Some(index);
+                break;
+            }
+            match own(s) {
+                Some(Event::Press(..)) if !down && taps < self.dance.steps.len() => {
+                    taps += 1;
+                    down = true;
+                }
+                Some(Event::Release(..)) if down => {
+                    down = false;
+                    release = Some(index);
+                }
+                // Another key going down (or a tap too many) ends it right away
+                Some(Event::Press(..)) | None if s.event.is_press() => {
+                    interrupted = true;
+                    end = Some(index);
+                    break;
+                }
+                _ => continue,
+            }
+            last = s.since;
+        }
+        let over = force || last >= window || (!down && taps == self.dance.steps.len());
+        let used = match end {
+            Some(used) => used,
+            None if over => stacked.len(),
+            None => return None,
+        };
+        let step = self.dance.steps[taps - 1];
+        Some(DanceEnd {
+            // Keys that get interrupted while down still count as tapped
+            action: if down && !interrupted {
+                step.hold
+            } else {
+                step.tap
+            },
+            used,
+            release: release.filter(|_| !down),
+        })
+    }
+}
+
+/// A queued event and how many ticks ago it came in
+#[derive(Debug, Copy, Clone)]
+struct Stacked {
+    event: Event,
+    since: u32,
+}
+
+impl From<Event> for Stacked {
+    fn from(event: Event) -> Self {
+        Stacked { event, since: 0 }
+    }
+}
+
+impl Stacked {
+    fn tick(&mut self) {
+        self.since = self.since.saturating_add(1);
+    }
+}
+
+/// The HoldTap key that was tapped last (pressing it again soon enough taps right away so it
+/// can be held down to repeat)
+#[derive(Debug, Copy, Clone)]
+struct LastTap {
+    coord: Coord,
+    /// Ticks since it was released
+    ticks: u32,
+}
+
+impl<const C: usize, const R: usize, const L: usize, T: Copy> Layout<C, R, L, T> {
+    /// *ticks_per_second* being how often tick() gets called
+    pub fn new(layers: &'static mut Layers<C, R, L, T>, ticks_per_second: u32) -> Self {
+        Self {
+            layers,
+            default_layer: 0,
+            ticks_per_second,
+            states: Vec::new(),
+            waiting: None,
+            stacked: ArrayDeque::new(),
+            last_tap: None,
+            dance: None,
+            tap_dance_window: userconfig::TAP_DANCE_WINDOW,
+            custom_event: CustomEvent::NoEvent,
+        }
+    }
+
+    /// Every key that's down right now
+    pub fn keycodes(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.states.iter().filter_map(State::keycode)
+    }
+
+    /// Changes how long (in milliseconds) a tap-dance key waits for another tap
+    pub fn set_tap_dance_window(&mut self, ms: u16) {
+        self.tap_dance_window = ms;
+    }
+
+    /// Milliseconds to ticks
+    fn ticks(&self, ms: u16) -> u32 {
+        ms as u32 * self.ticks_per_second / 1000
+    }
+
+    fn waiting_into(&mut self, action: WaitingAction) {
+        let Some(w) = self.waiting.take() else {
+            return;
+        };
+        match action {
+            WaitingAction::Hold => self.do_action(w.hold_tap.hold, w.coord, w.ticks),
+            WaitingAction::Tap => {
+                self.last_tap = Some(LastTap {
+                    coord: w.coord,
+                    ticks: 0,
+                });
+                self.do_action(w.hold_tap.tap, w.coord, w.ticks)
+            }
+        }
+    }
+
+    /// Ends the tap dance in progress (if there is one) if it's over.  Its own presses/releases
+    /// get taken off the queue (except the release of whatever it ends up doing).
+    fn dance_into(&mut self, force: bool) {
+        let window = self.ticks(self.tap_dance_window);
+        let Some(dance) = &mut self.dance else {
+            return;
+        };
+        let Some(end) = dance.tick(&self.stacked, window, force) else {
+            return;
+        };
+        let DanceState { coord, ticks, .. } = *dance;
+        self.dance = None;
+        for index in (0..end.used).rev() {
+            let own = self.stacked.get(index).map(|s| s.event.coord()) == Some(coord);
+            if own && end.release != Some(index) {
+                self.stacked.remove(index);
+            }
+        }
+        self.do_action(end.action, coord, ticks);
+    }
+
+    /// Moves everything along by a tick: a waiting HoldTap key or tap dance gets a chance to
+    /// make up its mind or (if there isn't one) the next queued event gets handled.  Returns the custom
+    /// action that got pressed/released (if any).
+    pub fn tick(&mut self) -> CustomEvent<T> {
+        self.stacked.iter_mut().for_each(Stacked::tick);
+        if let Some(last_tap) = &mut self.last_tap {
+            last_tap.ticks = last_tap.ticks.saturating_add(1);
+        }
+        if let Some(w) = &mut self.waiting {
+            if let Some(action) = w.tick(&self.stacked) {
+                self.waiting_into(action);
+            }
+        } else if self.dance.is_some() {
+            self.dance_into(false);
+        } else if let Some(s) = self.stacked.pop_front() {
+            self.unstack(s);
+        }
+        core::mem::replace(&mut self.custom_event, CustomEvent::NoEvent)
+    }
+
+    fn unstack(&mut self, stacked: Stacked) {
+        match stacked.event {
+            Event::Release(mux, chan) => {
+                let coord = (mux, chan);
+                for state in self.states.iter().filter(|s| s.coord() == coord) {
+                    if let Custom { value, .. } = state {
+                        self.custom_event.update(CustomEvent::Release(*value));
+                    }
+                }
+                self.states.retain(|s| s.coord() != coord);
+                if let Some(last_tap) = &mut self.last_tap {
+                    if last_tap.coord == coord {
+                        last_tap.ticks = stacked.since;
+                    }
+                }
+            }
+            Event::Press(mux, chan) => {
+                let action = self.press_as_action((mux, chan), self.current_layer());
+                self.do_action(action, (mux, chan), stacked.since);
+            }
+        }
+    }
+
+    /// Queues a key being pressed/released (it gets handled by tick())
+    pub fn event(&mut self, event: Event) {
+        if self.stacked.is_full() {
+            // The oldest event can't wait any longer (and neither can whatever a HoldTap or tap
+            // dance comes to if that's another one)
+            while self.is_waiting() {
+                self.waiting_into(WaitingAction::Hold);
+                self.dance_into(true);
+            }
+            if let Some(stacked) = self.stacked.pop_front() {
+                self.unstack(stacked);
+            }
+        }
+        let _ = self.stacked.push_back(event.into());
+    }
+
+    /// The action of a key on *layer* (going down to the default layer if it's transparent)
+    fn press_as_action(&self, coord: Coord, layer: usize) -> Action<T> {
+        let action = self
+            .layers
+            .get(layer)
+            .and_then(|l| l.get(coord.0))
+            .and_then(|l| l.get(coord.1));
+        match action {
+            None => Action::NoOp,
+            Some(Action::Trans) => {
+                if layer != self.default_layer {
+                    self.press_as_action(coord, self.default_layer)
+                } else {
+                    Action::NoOp
+                }
+            }
+            Some(action) => *action,
+        }
+    }
+
+    /// Returns true if a HoldTap key or tap dance is holding up the queue
+    fn is_waiting(&self) -> bool {
+        self.waiting.is_some() || self.dance.is_some()
+    }
+
+    /// Starts doing *action* for a key that was pressed *delay* ticks ago
+    fn do_action(&mut self, action: Action<T>, coord: Coord, delay: u32) {
+        assert!(!self.is_waiting());
+        match action {
+            Action::NoOp | Action::Trans => (),
+            Action::HoldTap(hold_tap) => {
+                let interval = self.ticks(hold_tap.tap_hold_interval);
+                let repeating = self
+                    .last_tap
+                    .is_some_and(|t| t.coord == coord && t.ticks < interval);
+                if repeating {
+                    self.do_action(hold_tap.tap, coord, delay);
+                } else {
+                    self.waiting = Some(WaitingState {
+                        coord,
+                        ticks: delay,
+                        timeout: self.ticks(hold_tap.timeout),
+                        hold_tap,
+                    });
+                }
+            }
+            Action::KeyCode(keycode) => {
+                let _ = self.states.push(NormalKey { coord, keycode });
+            }
+            Action::MultipleKeyCodes(keycodes) => {
+                for &keycode in keycodes {
+                    let _ = self.states.push(NormalKey { coord, keycode });
+                }
+            }
+            Action::MultipleActions(actions) => {
+                for &action in actions {
+                    // Anything after a HoldTap/tap dance gets dropped (there's only one waiting
+                    // key)
+                    if !self.is_waiting() {
+                        self.do_action(action, coord, delay);
+                    }
+                }
+            }
+            Action::Layer(value) => {
+                let _ = self.states.push(LayerModifier { value, coord });
+            }
+            Action::DefaultLayer(value) => self.set_default_layer(value),
+            Action::TapDance(dance) => {
+                if !dance.steps.is_empty() {
+                    self.dance = Some(DanceState {
+                        coord,
+                        ticks: delay,
+                        dance,
+                    });
+                }
+            }
+            Action::Custom(value) => {
+                if self.states.push(Custom { value, coord }).is_ok() {
+                    self.custom_event.update(CustomEvent::Press(value));
+                }
+            }
+        }
+    }
+
+    /// The layer that's active right now (the held layer keys added up the way keyberon does
+    /// or the default layer if that doesn't come out to a layer)
+    pub fn current_layer(&self) -> usize {
+        let mut iter = self.states.iter().filter_map(State::get_layer);
+        let layer = match iter.next() {
+            None => self.default_layer,
+            Some(first) => iter.fold(first, |layer, l| layer + l),
+        };
+        if layer < self.layers.len() {
+            layer
+        } else {
+            self.default_layer
+        }
+    }
+
+    pub fn default_layer(&self) -> usize {
+        self.default_layer
+    }
+
+    /// Switches the default layer (ignored if there's no such layer)
+    pub fn set_default_layer(&mut self, layer: usize) {
+        if layer < self.layers.len() {
+            self.default_layer = layer;
+        }
+    }
+
+    /// Changes the action of a key on *layer*.  Returns false if there's no such key.
+    pub fn change_action(&mut self, coord: Coord, layer: usize, action: Action<T>) -> bool {
+        let key = self
+            .layers
+            .get_mut(layer)
+            .and_then(|l| l.get_mut(coord.0))
+            .and_then(|l| l.get_mut(coord.1));
+        match key {
+            Some(key) => {
+                *key = action;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{d, k, l, HoldTapConfig, TapDanceStep};
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+    use KeyCode::*;
+
+    type TestLayout = Layout<4, 1, 3, u8>;
+
+    static HOLD_TAP: HoldTapAction<u8> = HoldTapAction {
+        timeout: 100,
+        hold: k(LShift),
+        tap: k(A),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    };
+    static HOLD_ON_PRESS: HoldTapAction<u8> = HoldTapAction {
+        config: HoldTapConfig::HoldOnOtherKeyPress,
+        ..HOLD_TAP
+    };
+    static PERMISSIVE: HoldTapAction<u8> = HoldTapAction {
+        config: HoldTapConfig::PermissiveHold,
+        ..HOLD_TAP
+    };
+    static DANCE: TapDance<u8> = TapDance {
+        steps: &[
+            TapDanceStep {
+                tap: k(Escape),
+                hold: k(LCtrl),
+            },
+            TapDanceStep {
+                tap: k(CapsLock),
+                hold: k(LShift),
+            },
+        ],
+    };
+
+    /// A layout (ticking once a millisecond) with *key* at (0, 0), E at (0, 1), layer 1 at
+    /// (0, 2) and F1 (layer 1)/G (layer 0) at (0, 3)
+    fn layout(key: Action<u8>) -> TestLayout {
+        let mut layers = [[[Action::NoOp; 4]; 1]; 3];
+        layers[0][0] = [key, k(E), l(1), k(G)];
+        layers[1][0] = [Action::Trans, Action::Trans, Action::Trans, k(F1)];
+        layers[2][0] = [k(Kb2), Action::Trans, Action::Trans, Action::Trans];
+        Layout::new(Box::leak(Box::new(layers)), 1000)
+    }
+
+    /// Ticks through *script* ((millisecond, event)) and returns every time the keys that are
+    /// down changed along with the millisecond it happened in
+    fn run(layout: &mut TestLayout, script: &[(u32, Event)]) -> Vec<(u32, Vec<KeyCode>)> {
+        let mut changes = Vec::new();
+        let mut last = Vec::new();
+        let end = script.last().map_or(0, |(ms, _)| ms + 500);
+        for ms in 0..end {
+            for (_, event) in script.iter().filter(|(at, _)| *at == ms) {
+                layout.event(*event);
+            }
+            layout.tick();
+            let keys: Vec<KeyCode> = layout.keycodes().collect();
+            if keys != last {
+                changes.push((ms, keys.clone()));
+                last = keys;
+            }
+        }
+        changes
+    }
+
+    use Event::{Press, Release};
+
+    #[test]
+    fn keys_and_layers() {
+        let mut layout = layout(k(A));
+        let changes = run(
+            &mut layout,
+            &[
+                (0, Press(0, 3)),
+                (10, Release(0, 3)),
+                (20, Press(0, 2)),
+                (30, Press(0, 3)),
+                (35, Press(0, 1)),
+                (40, Release(0, 3)),
+                (50, Release(0, 2)),
+                (60, Release(0, 1)),
+            ],
+        );
+        assert_eq!(
+            changes,
+            [
+                (0, vec![G]),
+                (10, vec![]),
+                (30, vec![F1]),
+                // Transparent on layer 1
+                (35, vec![F1, E]),
+                (40, vec![E]),
+                (60, vec![]),
+            ]
+        );
+        assert_eq!(layout.current_layer(), 0);
+    }
+
+    #[test]
+    fn default_layer() {
+        let mut layout = layout(d(2));
+        layout.event(Press(0, 0));
+        layout.event(Release(0, 0));
+        layout.tick();
+        layout.tick();
+        assert_eq!(layout.default_layer(), 2);
+        assert_eq!(layout.current_layer(), 2);
+        // Layer 3 doesn't exist
+        layout.set_default_layer(3);
+        assert_eq!(layout.default_layer(), 2);
+        // Transparent keys on the default layer don't do anything
+        let changes = run(&mut layout, &[(0, Press(0, 0)), (5, Press(0, 1))]);
+        assert_eq!(changes, [(0, vec![Kb2])]);
+    }
+
+    #[test]
+    fn change_action() {
+        let mut layout = layout(k(A));
+        assert!(layout.change_action((0, 0), 0, k(B)));
+        assert!(!layout.change_action((0, 4), 0, k(B)));
+        assert!(!layout.change_action((0, 0), 3, k(B)));
+        let changes = run(&mut layout, &[(0, Press(0, 0)), (5, Release(0, 0))]);
+        assert_eq!(changes, [(0, vec![B]), (5, vec![])]);
+    }
+
+    #[test]
+    fn custom_events() {
+        let mut layout = layout(Action::Custom(7));
+        layout.event(Press(0, 0));
+        assert_eq!(layout.tick(), CustomEvent::Press(7));
+        assert_eq!(layout.tick(), CustomEvent::NoEvent);
+        layout.event(Release(0, 0));
+        assert_eq!(layout.tick(), CustomEvent::Release(7));
+    }
+
+    #[test]
+    fn hold_tap() {
+        let script = [(0, Press(0, 0)), (20, Release(0, 0))];
+        let changes = run(&mut layout(Action::HoldTap(&HOLD_TAP)), &script);
+        assert_eq!(changes, [(20, vec![A]), (21, vec![])]);
+        let script = [(0, Press(0, 0)), (150, Release(0, 0))];
+        let changes = run(&mut layout(Action::HoldTap(&HOLD_TAP)), &script);
+        assert_eq!(changes, [(99, vec![LShift]), (150, vec![])]);
+    }
+
+    #[test]
+    fn hold_tap_holds_up_the_queue() {
+        // E gets pressed and released while the HoldTap key is still deciding so it goes out
+        // after it (and the key is a tap since it came back up before the timeout)
+        let script = [
+            (0, Press(0, 0)),
+            (10, Press(0, 1)),
+            (20, Release(0, 1)),
+            (30, Release(0, 0)),
+        ];
+        let changes = run(&mut layout(Action::HoldTap(&HOLD_TAP)), &script);
+        assert_eq!(
+            changes,
+            [(30, vec![A]), (31, vec![A, E]), (32, vec![A]), (33, vec![])]
+        );
+    }
+
+    #[test]
+    fn hold_tap_configs() {
+        let script = [(0, Press(0, 0)), (10, Press(0, 1)), (30, Release(0, 0))];
+        let changes = run(&mut layout(Action::HoldTap(&HOLD_ON_PRESS)), &script);
+        assert_eq!(
+            changes,
+            [(10, vec![LShift]), (11, vec![LShift, E]), (30, vec![E])]
+        );
+        let script = [
+            (0, Press(0, 0)),
+            (10, Press(0, 1)),
+            (20, Release(0, 1)),
+            (30, Release(0, 0)),
+        ];
+        let changes = run(&mut layout(Action::HoldTap(&PERMISSIVE)), &script);
+        assert_eq!(
+            changes,
+            [
+                (20, vec![LShift]),
+                (21, vec![LShift, E]),
+                (22, vec![LShift]),
+                (30, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_dance() {
+        let mut single = layout(Action::TapDance(&DANCE));
+        let changes = run(&mut single, &[(0, Press(0, 0)), (20, Release(0, 0))]);
+        assert_eq!(changes, [(219, vec![Escape]), (220, vec![])]);
+        let script = [
+            (0, Press(0, 0)),
+            (20, Release(0, 0)),
+            (60, Press(0, 0)),
+            (80, Release(0, 0)),
+        ];
+        let changes = run(&mut layout(Action::TapDance(&DANCE)), &script);
+        // Out of steps after two taps so there's no waiting for the window
+        assert_eq!(changes, [(80, vec![CapsLock]), (81, vec![])]);
+    }
+
+    #[test]
+    fn tap_dance_hold() {
+        let script = [(0, Press(0, 0)), (500, Release(0, 0))];
+        let changes = run(&mut layout(Action::TapDance(&DANCE)), &script);
+        assert_eq!(changes, [(199, vec![LCtrl]), (500, vec![])]);
+        let script = [
+            (0, Press(0, 0)),
+            (20, Release(0, 0)),
+            (60, Press(0, 0)),
+            (600, Release(0, 0)),
+        ];
+        let changes = run(&mut layout(Action::TapDance(&DANCE)), &script);
+        assert_eq!(changes, [(259, vec![LShift]), (600, vec![])]);
+    }
+
+    #[test]
+    fn tap_dance_interrupted() {
+        let script = [
+            (0, Press(0, 0)),
+            (20, Release(0, 0)),
+            (30, Press(0, 1)),
+            (40, Release(0, 1)),
+        ];
+        let changes = run(&mut layout(Action::TapDance(&DANCE)), &script);
+        assert_eq!(
+            changes,
+            [
+                (30, vec![Escape]),
+                (31, vec![]),
+                (32, vec![E]),
+                (40, vec![])
+            ]
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Everything about turning hall effect sensor readings into key presses that doesn't need the
+//! actual hardware: the per-channel state tracking and noise filtering, the thresholds, the
+//! runtime config and the layout/keymap that turn presses into reports.
+//!
+//! The firmware supplies the ADC/multiplexers (see `sensors`); anything else (e.g. the host,
+//! with `mock` and the `mock` feature turned on) can do the same, e.g.:
+//!
+//! ```sh
+//! cargo test -p hall-core --target x86_64-unknown-linux-gnu
+//! ```
+
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+pub mod action;
+pub mod calibrate;
+pub mod config;
+pub mod config_structs;
+pub mod dks;
+pub mod filter;
+pub mod gamepad;
+pub mod key_code;
+pub mod keymap;
+pub mod layers;
+pub mod layout;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod modtap;
+pub mod multiplexers;
+pub mod report;
+pub mod sensors;
+pub mod socd;
+pub mod thresholds;
+pub mod trace;
+pub mod travel;
+pub mod userconfig;
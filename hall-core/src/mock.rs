@@ -0,0 +1,145 @@
+//! Stand-ins for the hardware in sensors.rs so the whole press/release pipeline can run on the
+//! host (e.g. under `cargo test`) with made-up readings:
+//!
+//! ```
+//! # use core::cell::Cell;
+//! # use hall_core::mock::{MockInputs, MockKeyboard, MockMultiplexers};
+//! # use hall_core::multiplexers::Event;
+//! let selected = Cell::new(0);
+//! let mut mux = MockMultiplexers::new(&selected);
+//! let mut adc = MockInputs::new(&selected, 1500);
+//! let mut keyboard = MockKeyboard::new(&mut mux, &mut adc);
+//! adc.set(0, 13, 1400); // Push W down 100mV
+//! let mut events = Vec::new();
+//! keyboard.scan(&mut mux, &mut adc, |event| events.push(event));
+//! assert_eq!(events, [Event::Press(0, 13)]);
+//! ```
+
+use crate::config;
+use crate::config_structs::Config;
+use crate::filter::Filter;
+use crate::multiplexers::{self, ChannelStates, Event};
+use crate::sensors::{self, AnalogInputs, Frame, Multiplexers};
+use crate::thresholds::ThresholdTable;
+use crate::travel::Travel;
+use crate::userconfig::{MAX_CHANNELS, NUM_MULTIPLEXERS};
+use core::cell::Cell;
+
+/// Select lines that just remember which channel is selected (shared with MockInputs)
+pub struct MockMultiplexers<'a> {
+    selected: &'a Cell<u8>,
+    /// Number of times select() got called
+    pub selects: usize,
+}
+
+impl<'a> MockMultiplexers<'a> {
+    pub fn new(selected: &'a Cell<u8>) -> MockMultiplexers<'a> {
+        MockMultiplexers {
+            selected,
+            selects: 0,
+        }
+    }
+}
+
+impl Multiplexers for MockMultiplexers<'_> {
+    fn select(&mut self, channel: u8) {
+        self.selected.set(channel);
+        self.selects += 1;
+    }
+}
+
+/// Analog inputs that read whatever millivolt values they've been given for the selected channel
+pub struct MockInputs<'a> {
+    selected: &'a Cell<u8>,
+    pub millivolts: Frame,
+}
+
+impl<'a> MockInputs<'a> {
+    /// Every channel reads *resting* until told otherwise
+    pub fn new(selected: &'a Cell<u8>, resting: u16) -> MockInputs<'a> {
+        MockInputs {
+            selected,
+            millivolts: [[resting; MAX_CHANNELS]; NUM_MULTIPLEXERS],
+        }
+    }
+
+    /// Changes what the given channel reads from now on
+    pub fn set(&mut self, multiplexer: usize, chan: usize, millivolts: u16) {
+        self.millivolts[multiplexer][chan] = millivolts;
+    }
+}
+
+impl AnalogInputs for MockInputs<'_> {
+    fn read(&mut self, millivolts: &mut [u16; NUM_MULTIPLEXERS]) {
+        let chan = self.selected.get() as usize;
+        for (multi, value) in millivolts.iter_mut().enumerate() {
+            *value = self.millivolts[multi][chan];
+        }
+    }
+}
+
+/// Everything the firmware keeps track of between scans, set up the same way it is at boot
+/// (minus the saved settings)
+pub struct MockKeyboard {
+    pub config: Config,
+    pub ch_states: [ChannelStates; NUM_MULTIPLEXERS],
+    pub thresholds: ThresholdTable,
+    pub frame: Frame,
+}
+
+impl MockKeyboard {
+    /// Uses the compile-time config and takes the current readings as every key's resting value
+    pub fn new(mux: &mut impl Multiplexers, adc: &mut impl AnalogInputs) -> MockKeyboard {
+        let config = config::from_userconfig();
+        let mut keyboard = MockKeyboard {
+            ch_states: Default::default(),
+            thresholds: ThresholdTable::new(
+                config.keyboard.actuation_threshold,
+                config.keyboard.release_threshold,
+            ),
+            config,
+            frame: [[0; MAX_CHANNELS]; NUM_MULTIPLEXERS],
+        };
+        keyboard.set_filter(Filter::from_config(
+            keyboard.config.keyboard.filter,
+            keyboard.config.keyboard.filter_window,
+            keyboard.config.keyboard.filter_alpha,
+        ));
+        sensors::sweep(mux, adc, &mut keyboard.frame);
+        for (multi, readings) in keyboard.frame.iter().enumerate() {
+            for (chan, millivolts) in readings.iter().enumerate() {
+                keyboard.ch_states[multi].update_default_by_index(chan, *millivolts);
+            }
+        }
+        keyboard
+    }
+
+    /// Uses *filter* on every channel
+    pub fn set_filter(&mut self, filter: Filter) {
+        for states in self.ch_states.iter_mut() {
+            for chan in 0..MAX_CHANNELS {
+                states.update_filter_by_index(chan, filter);
+            }
+        }
+    }
+
+    /// Sweeps every channel and runs the readings through the same checks the firmware does,
+    /// handing every Press()/Release() to *on_event*
+    pub fn scan(
+        &mut self,
+        mux: &mut impl Multiplexers,
+        adc: &mut impl AnalogInputs,
+        on_event: impl FnMut(Event),
+    ) {
+        sensors::sweep(mux, adc, &mut self.frame);
+        multiplexers::scan(
+            &self.frame,
+            &mut self.ch_states,
+            &self.thresholds,
+            self.config.keyboard.rapid_trigger_press_sensitivity,
+            self.config.keyboard.rapid_trigger_release_sensitivity,
+            &Travel::from_config(&self.config.keyboard),
+            on_event,
+        );
+    }
+}
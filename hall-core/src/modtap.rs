@@ -0,0 +1,237 @@
+//! Mod-tap by depth: a key that's a normal key when pressed a little and modifiers when pressed
+//! further (e.g. home-row mods) without any timeouts
+//!
+//! Keys bound to a mod-tap (QMK's `MT()` codes; see the firmware's keymap.rs) go through:
+//!
+//! * Past the tap point: the tap key goes down
+//! * Past the (deeper) hold point: the tap key comes back up and the modifiers go down instead
+//! * Back up past the tap point: whatever is down comes back up
+//!
+//! Once the modifiers are down they stay down until the key is let go of (so easing off a bit
+//! while holding doesn't type anything).  Since a key has to go past the tap point on its way to
+//! the hold point the tap key can go down briefly before the modifiers do; pressing straight
+//! through both points within one sweep skips it.  Keyberon's (time-based) HoldTap is still
+//! there for keys in `layers::LAYERS` that want that instead.
+//!
+//! Which mod-tap a key uses gets latched when it passes the tap point (so switching layers
+//! mid-press doesn't leave keys down).
+
+use crate::config_structs::ModTapConfig;
+use crate::multiplexers::ChannelStates;
+use crate::sensors::MUX_CHANNELS;
+use crate::travel::Travel;
+use crate::userconfig::NUM_MULTIPLEXERS;
+
+/// How far (0.01mm) a key has to come back up past the tap point before it's let go of
+pub const HYSTERESIS: u16 = 10;
+/// HID usage of the first modifier (left control; the modifier bits go in the same order)
+const FIRST_MODIFIER: u8 = 0xE0;
+
+/// What a mod-tap key does
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ModTap {
+    /// HID modifier bits (bit 0 = left control ... bit 7 = right GUI)
+    pub mods: u8,
+    /// HID keyboard usage (e.g. 0x04 = A)
+    pub tap: u8,
+}
+
+impl ModTap {
+    /// From the parts of a QMK `MT()` code: 5-bit modifiers (control, shift, alt, GUI and
+    /// whether they're the right-hand ones) and a basic keycode
+    pub fn from_qmk(mods: u8, tap: u8) -> ModTap {
+        let mods = match mods & 0x10 {
+            0 => mods & 0x0F,
+            _ => (mods & 0x0F) << 4,
+        };
+        ModTap { mods, tap }
+    }
+
+    /// The 5-bit QMK modifiers (the left-hand ones win if there's a mix)
+    pub fn qmk_mods(&self) -> u8 {
+        match self.mods & 0x0F {
+            0 => (self.mods >> 4) | 0x10,
+            left => left,
+        }
+    }
+
+    /// The HID usages of the modifiers
+    fn modifiers(&self) -> impl Iterator<Item = u8> {
+        let mods = self.mods;
+        (0..8)
+            .filter(move |bit| mods & (1 << bit) != 0)
+            .map(|bit| FIRST_MODIFIER + bit)
+    }
+}
+
+/// Where the points are
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    /// Travel (0.01mm) where the tap key goes down
+    pub tap_point: u16,
+    /// Travel (0.01mm) where the modifiers take over
+    pub hold_point: u16,
+}
+
+impl Settings {
+    pub fn from_config(mod_tap: &ModTapConfig) -> Settings {
+        // Keys at rest need to be able to get back below the tap point
+        let tap_point = mod_tap.tap_point.max(HYSTERESIS + 1);
+        Settings {
+            tap_point,
+            hold_point: mod_tap.hold_point.max(tap_point.saturating_add(1)),
+        }
+    }
+}
+
+/// Where a key is at
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    #[default]
+    Up,
+    /// The tap key is down
+    Tapping,
+    /// The modifiers are down
+    Holding,
+}
+
+/// One key's state machine
+#[derive(Debug, Default, Clone, Copy)]
+struct KeyState {
+    phase: Phase,
+    mod_tap: ModTap, // Latched when the key went past the tap point
+}
+
+impl KeyState {
+    fn update(&mut self, travel: u16, settings: &Settings) {
+        if self.phase == Phase::Up && travel >= settings.tap_point {
+            self.phase = Phase::Tapping;
+        }
+        if self.phase == Phase::Tapping && travel >= settings.hold_point {
+            self.phase = Phase::Holding;
+        }
+        if self.phase != Phase::Up && travel.saturating_add(HYSTERESIS) < settings.tap_point {
+            self.phase = Phase::Up;
+        }
+    }
+}
+
+/// The state machine of every key
+#[derive(Debug, Default, Clone)]
+pub struct ModTaps {
+    keys: [[KeyState; MUX_CHANNELS as usize]; NUM_MULTIPLEXERS],
+}
+
+impl ModTaps {
+    /// Runs every key's state machine.  *mod_tap_of* says which mod-tap (if any) a key
+    /// (multiplexer, channel) is bound to right now; keys that are already down stick with the
+    /// one they started out with.
+    pub fn sweep(
+        &mut self,
+        ch_states: &[ChannelStates],
+        travel: &Travel,
+        settings: &Settings,
+        mod_tap_of: impl Fn(usize, usize) -> Option<ModTap>,
+    ) {
+        for ((mux, keys), states) in self.keys.iter_mut().enumerate().zip(ch_states) {
+            for ((chan, key), state) in keys.iter_mut().enumerate().zip(&states.states) {
+                if key.phase == Phase::Up {
+                    match mod_tap_of(mux, chan) {
+                        Some(mod_tap) => key.mod_tap = mod_tap,
+                        None => continue,
+                    }
+                }
+                key.update(state.travel(travel), settings);
+            }
+        }
+    }
+
+    /// Every HID keyboard usage that's down right now (may repeat)
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.keys.iter().flatten().flat_map(|key| {
+            let tap = (key.phase == Phase::Tapping).then_some(key.mod_tap.tap);
+            let mods = key
+                .mod_tap
+                .modifiers()
+                .filter(move |_| key.phase == Phase::Holding);
+            tap.into_iter().chain(mods)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::travel::{SwitchCurve, Units};
+    use std::vec::Vec;
+
+    const REST: u16 = 2000;
+    const A: u8 = 0x04;
+    const LSHIFT: u8 = 0xE1;
+    const TRAVEL: Travel = Travel {
+        units: Units::Travel,
+        curve: SwitchCurve::Linear,
+        full_travel: 400,
+        bottom_out_swing: 1000,
+    };
+    const SETTINGS: Settings = Settings {
+        tap_point: 100,
+        hold_point: 250,
+    };
+
+    /// Runs a sweep with key (0, 0) bound to MT(MOD_LSFT, KC_A) at *travel* (0.01mm) and
+    /// returns the keys that are down afterwards
+    fn sweep(mod_taps: &mut ModTaps, ch_states: &mut [ChannelStates], travel: u16) -> Vec<u8> {
+        // 2.5mV per 0.01mm with the swing and travel above
+        let mv = travel * 5 / 2;
+        ch_states[0][0].value = match config::KEYBOARD_NORTH_DOWN {
+            0 => REST + mv,
+            _ => REST - mv,
+        };
+        mod_taps.sweep(ch_states, &TRAVEL, &SETTINGS, |mux, chan| {
+            (mux == 0 && chan == 0).then_some(ModTap::from_qmk(0x02, A))
+        });
+        mod_taps.keys().collect()
+    }
+
+    fn setup() -> (ModTaps, [ChannelStates; NUM_MULTIPLEXERS]) {
+        let mut ch_states: [ChannelStates; NUM_MULTIPLEXERS] = Default::default();
+        for states in ch_states.iter_mut() {
+            for chan in 0..MUX_CHANNELS as usize {
+                states.update_default_by_index(chan, REST);
+            }
+        }
+        (ModTaps::default(), ch_states)
+    }
+
+    #[test]
+    fn tap_then_hold() {
+        let (mut mod_taps, mut ch_states) = setup();
+        assert_eq!(sweep(&mut mod_taps, &mut ch_states, 50), []);
+        assert_eq!(sweep(&mut mod_taps, &mut ch_states, 150), [A]);
+        assert_eq!(sweep(&mut mod_taps, &mut ch_states, 300), [LSHIFT]);
+        // Easing off doesn't bring the tap key back
+        assert_eq!(sweep(&mut mod_taps, &mut ch_states, 150), [LSHIFT]);
+        assert_eq!(sweep(&mut mod_taps, &mut ch_states, 95), [LSHIFT]);
+        assert_eq!(sweep(&mut mod_taps, &mut ch_states, 80), []);
+    }
+
+    #[test]
+    fn straight_to_hold() {
+        let (mut mod_taps, mut ch_states) = setup();
+        assert_eq!(sweep(&mut mod_taps, &mut ch_states, 300), [LSHIFT]);
+        assert_eq!(sweep(&mut mod_taps, &mut ch_states, 0), []);
+    }
+
+    #[test]
+    fn qmk_mods() {
+        let left = ModTap::from_qmk(0x03, A); // Control + shift
+        assert_eq!(left.mods, 0x03);
+        assert_eq!(left.qmk_mods(), 0x03);
+        let right = ModTap::from_qmk(0x14, A); // Right alt
+        assert_eq!(right.mods, 0x40);
+        assert_eq!(right.qmk_mods(), 0x14);
+        assert_eq!(right.modifiers().collect::<Vec<_>>(), [0xE6]);
+    }
+}
@@ -0,0 +1,667 @@
+//! The equivalent of Keyberon's matrix.rs but for Hall Effect sensors
+//! connected to analog multiplexers
+
+use core::ops::{Index, IndexMut};
+use crate::config;
+use crate::filter::{Filter, History};
+use crate::sensors::{Frame, MUX_CHANNELS};
+use crate::thresholds::ThresholdTable;
+use crate::travel::Travel;
+use serde::{Deserialize, Serialize};
+
+/// A key (by multiplexer and channel) that got pressed or released.  The firmware hands these
+/// to its layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Press(usize, usize),
+    Release(usize, usize),
+}
+
+impl Event {
+    /// The key: (multiplexer, channel)
+    pub fn coord(&self) -> (usize, usize) {
+        match *self {
+            Event::Press(mux, chan) | Event::Release(mux, chan) => (mux, chan),
+        }
+    }
+
+    pub fn is_press(&self) -> bool {
+        matches!(self, Event::Press(..))
+    }
+}
+
+/// How a channel decides when it has been pressed or released
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TriggerMode {
+    /// Press and release at fixed distances from the default (resting) value
+    #[default]
+    Threshold,
+    /// Release as soon as the key rises a little from its deepest point and press again
+    /// as soon as it moves back down, no matter where in the travel that happens
+    RapidTrigger,
+}
+
+impl TriggerMode {
+    /// The mode by the name the console uses
+    pub fn from_name(name: &str) -> Option<TriggerMode> {
+        match name {
+            "threshold" => Some(TriggerMode::Threshold),
+            "rapid" => Some(TriggerMode::RapidTrigger),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TriggerMode::Threshold => "threshold",
+            TriggerMode::RapidTrigger => "rapid",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelState {
+    pub pressed: bool,
+    pub value: u16,
+    pub default: u16,
+    pub bottom_out: u16, // Deepest (filtered) reading when pressed all the way down (0 = not yet)
+    pub filter: Filter,
+    pub smoothed: History, // Recent raw readings (used by the windowed filters)
+    pub iir: u32,          // IIR filter state (8-bit fixed point)
+    // These are so we can track/debug voltage wobble:
+    pub low: u16,     // Keep track of the lowest value
+    pub high: u16,    // Keep track of the highest value
+    pub rising: bool, // Whether or not this channel is rising (only used by rotary encoders)
+    pub mode: TriggerMode,
+    // Deepest travel since the last press or shallowest travel since the last release (mV):
+    pub peak: u16,
+    // Rolling window of resting values that recalibrate() uses to move the default:
+    pub rest_sum: u32,
+    pub rest_count: u16,
+    pub rest_low: u16,
+    pub rest_high: u16,
+}
+
+impl Default for ChannelState {
+    fn default() -> ChannelState {
+        ChannelState {
+            pressed: false,
+            value: 0,
+            default: 0,
+            bottom_out: 0,
+            filter: Filter::None,
+            smoothed: History::new(),
+            iir: 0,
+            low: 3200,
+            high: 0,
+            rising: false,
+            mode: TriggerMode::Threshold,
+            peak: 0,
+            rest_sum: 0,
+            rest_count: 0,
+            rest_low: u16::MAX,
+            rest_high: 0,
+        }
+    }
+}
+
+impl ChannelState {
+    /// Sets self.pressed to true
+    pub fn press(&mut self) {
+        self.pressed = true;
+    }
+
+    /// Sets self.pressed to false
+    pub fn release(&mut self) {
+        self.pressed = false;
+    }
+
+    /// Updates self.default with the given value
+    pub fn update_default(&mut self, val: u16) {
+        self.default = val;
+    }
+
+    /// How far (mV) *millivolts* has moved down from the default (resting) value
+    pub fn difference(&self, millivolts: u16) -> u16 {
+        if config::KEYBOARD_NORTH_DOWN > 0 {
+            self.default.saturating_sub(millivolts) // North side down switches result in a mV drop
+        } else {
+            millivolts.saturating_sub(self.default) // South side down switches result in a mV increase
+        }
+    }
+
+    /// Millivolts between the default value and the bottom-out reading (0 if the key hasn't
+    /// been bottomed out yet)
+    pub fn calibrated_swing(&self) -> u16 {
+        if self.bottom_out == 0 {
+            0
+        } else {
+            self.difference(self.bottom_out)
+        }
+    }
+
+    /// Millivolts between resting and bottomed out (*travel*'s guess if it hasn't been seen)
+    pub fn swing(&self, travel: &Travel) -> u16 {
+        match self.calibrated_swing() {
+            0 => travel.bottom_out_swing,
+            swing => swing,
+        }
+    }
+
+    /// Puts the bottom-out reading *swing* mV past the default value (0 forgets it)
+    pub fn set_swing(&mut self, swing: u16) {
+        self.bottom_out = match swing {
+            0 => 0,
+            _ if config::KEYBOARD_NORTH_DOWN > 0 => self.default.saturating_sub(swing).max(1),
+            _ => self.default.saturating_add(swing),
+        };
+    }
+
+    /// How far *millivolts* is from the default value in the units the thresholds are in
+    pub fn distance(&self, millivolts: u16, travel: &Travel) -> u16 {
+        travel.distance(self.difference(millivolts), self.swing(travel))
+    }
+
+    /// Travel (0.01mm) of the last recorded (filtered) value
+    pub fn travel(&self, travel: &Travel) -> u16 {
+        travel.travel(self.difference(self.value), self.swing(travel))
+    }
+
+    /// Takes *millivolts* as the new bottom-out reading if it's deeper than the current one
+    /// (or *travel*'s guess if there isn't one)
+    pub fn learn_bottom_out(&mut self, millivolts: u16, travel: &Travel) {
+        if self.default != 0 && self.difference(millivolts) > self.swing(travel) {
+            self.bottom_out = millivolts;
+        }
+    }
+
+    /// Sets the trigger mode and forgets any rapid trigger travel tracking
+    pub fn set_mode(&mut self, mode: TriggerMode) {
+        self.mode = mode;
+        self.peak = 0;
+    }
+
+    /// Sets the noise filter and forgets any previous readings
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+        self.smoothed.clear();
+        self.iir = 0;
+    }
+
+    /// Runs the given raw reading through this channel's filter and returns the result
+    pub fn smooth(&mut self, val: u16) -> u16 {
+        self.filter.apply(&mut self.smoothed, &mut self.iir, val)
+    }
+
+    /// Records the given value, making sure to record any lows or highs.
+    /// Values recorded while the key isn't pressed also go into the resting window.
+    pub fn record_value(&mut self, val: u16) {
+        self.value = val;
+        if val > self.high {
+            self.high = val;
+        }
+        if val < self.low {
+            self.low = val;
+        }
+        if !self.pressed && self.rest_count < u16::MAX {
+            self.rest_sum += val as u32;
+            self.rest_count += 1;
+            self.rest_low = self.rest_low.min(val);
+            self.rest_high = self.rest_high.max(val);
+        }
+    }
+
+    /// The lowest (north side down) or highest (south side down) value recorded, i.e. the
+    /// furthest the key has been pushed down
+    pub fn deepest(&self) -> u16 {
+        if config::KEYBOARD_NORTH_DOWN > 0 {
+            self.low
+        } else {
+            self.high
+        }
+    }
+
+    /// Forgets the lowest and highest values recorded
+    pub fn reset_extremes(&mut self) {
+        self.low = ChannelState::default().low;
+        self.high = 0;
+    }
+
+    /// Empties the resting window
+    pub fn reset_rest(&mut self) {
+        self.rest_sum = 0;
+        self.rest_count = 0;
+        self.rest_low = u16::MAX;
+        self.rest_high = 0;
+    }
+
+    /// Moves self.default towards the average of the resting window (at most *max_step* mV)
+    /// and starts a new window.  Nothing changes if the key is pressed, if it's currently
+    /// moved *near_actuation* (in *travel*'s units) or more from the default, or if the window
+    /// wobbled (or strayed from the default) more than *max_noise* mV--that's what a key being
+    /// slowly held down looks like as opposed to temperature drift.  The bottom-out reading
+    /// drifts along with the default.  Returns true if the default was updated.
+    pub fn recalibrate(
+        &mut self,
+        near_actuation: u16,
+        max_noise: u16,
+        max_step: u16,
+        travel: &Travel,
+    ) -> bool {
+        let moved = travel.distance(self.value.abs_diff(self.default), self.swing(travel));
+        let updated = if self.pressed
+            || self.rest_count == 0
+            || self.default == 0
+            || moved >= near_actuation
+            || self.rest_high - self.rest_low > max_noise
+        {
+            false
+        } else {
+            let average = (self.rest_sum / self.rest_count as u32) as u16;
+            let difference = average.abs_diff(self.default);
+            if difference > max_noise {
+                false
+            } else {
+                let step = difference.min(max_step);
+                let swing = self.calibrated_swing();
+                if average > self.default {
+                    self.default += step;
+                } else {
+                    self.default -= step;
+                }
+                self.set_swing(swing);
+                step > 0
+            }
+        };
+        self.reset_rest();
+        updated
+    }
+}
+
+/// Struct for storing the state of each channel and pretty-printing it via rprintln
+#[derive(Debug, Default, Clone)]
+pub struct ChannelStates {
+    pub states: [ChannelState; config::KEYBOARD_MAX_CHANNELS],
+    curr: usize,        // Iterator tracking
+    #[allow(dead_code)]
+    next: usize,        // Ditto
+    pub pressed: usize, // Records how many keys are currently pressed
+}
+
+impl ChannelStates {
+    pub fn update_default_by_index(&mut self, chan: usize, val: u16) {
+        self.states[chan].default = val;
+    }
+    pub fn update_rising_by_index(&mut self, chan: usize, val: bool) {
+        self.states[chan].rising = val;
+    }
+    pub fn update_mode_by_index(&mut self, chan: usize, mode: TriggerMode) {
+        self.states[chan].set_mode(mode);
+    }
+    pub fn update_filter_by_index(&mut self, chan: usize, filter: Filter) {
+        self.states[chan].set_filter(filter);
+    }
+    pub fn update_peak_by_index(&mut self, chan: usize, val: u16) {
+        self.states[chan].peak = val;
+    }
+    pub fn update_bottom_out_by_index(&mut self, chan: usize, val: u16) {
+        self.states[chan].bottom_out = val;
+    }
+    /// Recalibrates the default value of every channel (see ChannelState::recalibrate()).
+    /// *near_actuation* gets called with each channel to get its limit.
+    pub fn recalibrate(
+        &mut self,
+        near_actuation: impl Fn(usize) -> u16,
+        max_noise: u16,
+        max_step: u16,
+        travel: &Travel,
+    ) -> usize {
+        let mut updated = 0;
+        for (chan, state) in self.states.iter_mut().enumerate() {
+            if state.recalibrate(near_actuation(chan), max_noise, max_step, travel) {
+                updated += 1;
+            }
+        }
+        updated
+    }
+    /// Uses the current value of every channel that isn't pressed as its new default
+    pub fn reset_defaults(&mut self) {
+        for state in self.states.iter_mut().filter(|s| !s.pressed) {
+            state.update_default(state.value);
+            state.reset_rest();
+        }
+    }
+    pub fn press(&mut self, chan: usize) {
+        self.states[chan].press();
+        self.pressed_add();
+    }
+    pub fn release(&mut self, chan: usize) {
+        self.states[chan].release();
+        self.pressed_sub();
+    }
+    pub fn pressed_add(&mut self) {
+        self.pressed += 1;
+    }
+    pub fn pressed_sub(&mut self) {
+        self.pressed -= 1;
+    }
+}
+
+impl Iterator for ChannelStates {
+    type Item = ChannelState;
+
+    fn next(&mut self) -> Option<ChannelState> {
+        let state = self.states.get(self.curr)?.clone();
+        self.curr += 1;
+        Some(state)
+    }
+}
+
+impl Index<usize> for ChannelStates {
+    type Output = ChannelState;
+
+    fn index(&self, i: usize) -> &ChannelState {
+        &self.states[i]
+    }
+}
+
+impl IndexMut<usize> for ChannelStates {
+    fn index_mut(&mut self, i: usize) -> &mut ChannelState {
+        &mut self.states[i]
+    }
+}
+
+// impl our super user-friendly terminal view into all channels
+impl core::fmt::Display for ChannelStates {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        // let _ = f.write_str("\x1B[2J\x1B[0H"); // Clear the screen and move cursor to start
+        let _ = f.write_str("Multiplexer Channel Values:\n\n");
+        // Eight channels to a row with the channel numbers above their values
+        let channels = &self.states[..MUX_CHANNELS as usize];
+        for (row, states) in channels.chunks(8).enumerate() {
+            for chan in row * 8..row * 8 + states.len() {
+                f.write_fmt(format_args!("ch{:<5}", chan))?;
+            }
+            let _ = f.write_str("\n");
+            for state in states {
+                f.write_fmt(format_args!("{:<7}", state.value))?;
+            }
+            let _ = f.write_str("\n");
+        }
+        Ok(())
+    }
+}
+
+/// True for the encoder's rotation sensors (which never produce key events)
+pub fn is_encoder_sensor(multiplexer: usize, chan: usize) -> bool {
+    // Encoder press doesn't work very reliably (needs work--probably a change to the PCB):
+    multiplexer == config::ENCODER_MUX
+        && (chan == config::ENCODER_CHANNEL1 || chan == config::ENCODER_CHANNEL2)
+}
+
+/// Presses *chan* and returns the event for the layout (skipping the encoder's rotation sensors)
+fn press_channel(
+    multiplexer: usize,
+    chan: usize,
+    ch_states: &mut [ChannelStates],
+) -> Option<Event> {
+    if is_encoder_sensor(multiplexer, chan) {
+        return None;
+    }
+    ch_states[multiplexer].press(chan);
+    Some(Event::Press(multiplexer, chan))
+}
+
+/// Releases *chan* and returns the event for the layout (skipping the encoder's rotation sensors)
+fn release_channel(
+    multiplexer: usize,
+    chan: usize,
+    ch_states: &mut [ChannelStates],
+) -> Option<Event> {
+    if is_encoder_sensor(multiplexer, chan) {
+        return None;
+    }
+    ch_states[multiplexer].release(chan);
+    Some(Event::Release(multiplexer, chan))
+}
+
+/// Evaluates the reading last recorded for *chan* (already filtered; see record_value()) and
+/// returns the Press()/Release() (if any) it resulted in
+pub fn check_channel(
+    multiplexer: usize,
+    chan: usize,
+    ch_states: &mut [ChannelStates],
+    thresholds: &ThresholdTable,
+    rapid_trigger_press_sensitivity: u16,
+    rapid_trigger_release_sensitivity: u16,
+    travel: &Travel,
+) -> Option<Event> {
+    let ch_state = &ch_states[multiplexer][chan];
+    let (value, pressed, peak, mode) = (
+        ch_state.value,
+        ch_state.pressed,
+        ch_state.peak,
+        ch_state.mode,
+    );
+    if value > config::KEYBOARD_IGNORE_BELOW {
+        if pressed {
+            ch_states[multiplexer][chan].learn_bottom_out(value, travel);
+        }
+        // In millivolts or 0.01mm (whatever the thresholds are in)
+        let voltage_difference = ch_states[multiplexer][chan].distance(value, travel);
+        let actuation_threshold = thresholds.actuation(multiplexer, chan);
+        let release_threshold = thresholds.release(multiplexer, chan);
+        match mode {
+            TriggerMode::Threshold => {
+                // Handle normal keypresses
+                if voltage_difference > actuation_threshold {
+                    if !pressed {
+                        return press_channel(multiplexer, chan, ch_states);
+                    }
+                } else if voltage_difference < release_threshold && pressed {
+                    return release_channel(multiplexer, chan, ch_states);
+                }
+            }
+            TriggerMode::RapidTrigger => {
+                if pressed {
+                    // Release on any upward movement of the release sensitivity from the deepest
+                    // point (or when the key makes it all the way back up):
+                    if voltage_difference > peak {
+                        ch_states[multiplexer].update_peak_by_index(chan, voltage_difference);
+                    } else if peak - voltage_difference >= rapid_trigger_release_sensitivity
+                        || voltage_difference < release_threshold
+                    {
+                        let event = release_channel(multiplexer, chan, ch_states);
+                        ch_states[multiplexer].update_peak_by_index(chan, voltage_difference);
+                        return event;
+                    }
+                } else {
+                    // Press again once the key moves down by the press sensitivity from the
+                    // shallowest point since release.  The actuation threshold still applies
+                    // so noise at the top of the travel can't trigger anything.
+                    if voltage_difference < peak {
+                        ch_states[multiplexer].update_peak_by_index(chan, voltage_difference);
+                    } else if voltage_difference - peak >= rapid_trigger_press_sensitivity
+                        && voltage_difference > actuation_threshold
+                    {
+                        let event = press_channel(multiplexer, chan, ch_states);
+                        ch_states[multiplexer].update_peak_by_index(chan, voltage_difference);
+                        return event;
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Runs a whole frame of readings (see sensors::sweep()) through check_channel(), handing every
+/// resulting event to *on_event*
+pub fn scan(
+    frame: &Frame,
+    ch_states: &mut [ChannelStates],
+    thresholds: &ThresholdTable,
+    rapid_trigger_press_sensitivity: u16,
+    rapid_trigger_release_sensitivity: u16,
+    travel: &Travel,
+    mut on_event: impl FnMut(Event),
+) {
+    for (multi, readings) in frame.iter().enumerate() {
+        for (chan, &millivolts) in readings.iter().enumerate().take(MUX_CHANNELS as usize) {
+            // Filter out ADC noise before anything (value, travel(), lows/highs) sees it
+            let millivolts = ch_states[multi][chan].smooth(millivolts);
+            ch_states[multi][chan].record_value(millivolts);
+            if let Some(event) = check_channel(
+                multi,
+                chan,
+                ch_states,
+                thresholds,
+                rapid_trigger_press_sensitivity,
+                rapid_trigger_release_sensitivity,
+                travel,
+            ) {
+                on_event(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockInputs, MockKeyboard, MockMultiplexers};
+    use core::cell::Cell;
+    use std::vec::Vec;
+
+    const REST: u16 = 1500;
+    const W: (usize, usize) = (0, 13);
+
+    /// The reading of a key pushed down *mv* from resting (whichever way the magnets face)
+    fn down(mv: u16) -> u16 {
+        if config::KEYBOARD_NORTH_DOWN > 0 {
+            REST - mv
+        } else {
+            REST + mv
+        }
+    }
+
+    /// Scans once per reading of W and returns every event along with the scan it came out of
+    fn run(filter: Filter, mode: TriggerMode, readings: &[u16]) -> Vec<(usize, Event)> {
+        let selected = Cell::new(0);
+        let mut mux = MockMultiplexers::new(&selected);
+        let mut adc = MockInputs::new(&selected, REST);
+        let mut keyboard = MockKeyboard::new(&mut mux, &mut adc);
+        keyboard.set_filter(filter);
+        keyboard.ch_states[W.0].update_mode_by_index(W.1, mode);
+        let mut events = Vec::new();
+        for (sweep, &millivolts) in readings.iter().enumerate() {
+            adc.set(W.0, W.1, millivolts);
+            keyboard.scan(&mut mux, &mut adc, |event| events.push((sweep, event)));
+        }
+        events
+    }
+
+    #[test]
+    fn threshold_press_and_release() {
+        // Actuation is 20mV and release 5mV
+        let readings = [down(10), down(21), down(30), down(6), down(5), down(4)];
+        let events = run(Filter::None, TriggerMode::Threshold, &readings);
+        assert_eq!(
+            events,
+            [(1, Event::Press(W.0, W.1)), (5, Event::Release(W.0, W.1))]
+        );
+    }
+
+    #[test]
+    fn filtered_readings_decide() {
+        // The boxcar average only gets past the actuation threshold on the third reading and
+        // back under the release threshold once every reading in the window is back at rest
+        let mut readings = [REST; 12];
+        readings[4..7].fill(down(30));
+        let events = run(
+            Filter::Boxcar { window: 4 },
+            TriggerMode::Threshold,
+            &readings,
+        );
+        assert_eq!(
+            events,
+            [(6, Event::Press(W.0, W.1)), (10, Event::Release(W.0, W.1))]
+        );
+    }
+
+    #[test]
+    fn median_ignores_a_spike() {
+        let readings = [REST, REST, down(200), REST, REST];
+        let events = run(
+            Filter::Median { window: 3 },
+            TriggerMode::Threshold,
+            &readings,
+        );
+        assert_eq!(events, []);
+        let events = run(Filter::None, TriggerMode::Threshold, &readings);
+        assert_eq!(
+            events,
+            [(2, Event::Press(W.0, W.1)), (3, Event::Release(W.0, W.1))]
+        );
+    }
+
+    #[test]
+    fn rapid_trigger() {
+        // Sensitivities are 8mV both ways
+        let readings = [
+            down(30), // Press
+            down(40), // Deepest point
+            down(33),
+            down(32), // Up 8 from the deepest point: release
+            down(30), // Shallowest point
+            down(38), // Down 8 from the shallowest point: press
+            down(3),  // All the way back up
+        ];
+        let events = run(Filter::None, TriggerMode::RapidTrigger, &readings);
+        assert_eq!(
+            events,
+            [
+                (0, Event::Press(W.0, W.1)),
+                (3, Event::Release(W.0, W.1)),
+                (5, Event::Press(W.0, W.1)),
+                (6, Event::Release(W.0, W.1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn recorded_value_is_filtered() {
+        let selected = Cell::new(0);
+        let mut mux = MockMultiplexers::new(&selected);
+        let mut adc = MockInputs::new(&selected, REST);
+        let mut keyboard = MockKeyboard::new(&mut mux, &mut adc);
+        keyboard.set_filter(Filter::Boxcar { window: 2 });
+        keyboard.scan(&mut mux, &mut adc, |_| ());
+        adc.set(W.0, W.1, down(100));
+        keyboard.scan(&mut mux, &mut adc, |_| ());
+        assert_eq!(keyboard.ch_states[W.0][W.1].value, down(50));
+    }
+
+    #[test]
+    fn encoder_sensors_dont_press() {
+        let selected = Cell::new(0);
+        let mut mux = MockMultiplexers::new(&selected);
+        let mut adc = MockInputs::new(&selected, REST);
+        let mut keyboard = MockKeyboard::new(&mut mux, &mut adc);
+        adc.set(config::ENCODER_MUX, config::ENCODER_CHANNEL1, down(200));
+        let mut events = Vec::new();
+        keyboard.scan(&mut mux, &mut adc, |event| events.push(event));
+        assert_eq!(events, []);
+    }
+
+    #[test]
+    fn iterates_over_every_channel() {
+        let mut states = ChannelStates::default();
+        for chan in 0..config::KEYBOARD_MAX_CHANNELS {
+            states.update_default_by_index(chan, chan as u16);
+        }
+        let defaults: Vec<u16> = states.map(|state| state.default).collect();
+        let expected: Vec<u16> = (0..config::KEYBOARD_MAX_CHANNELS as u16).collect();
+        assert_eq!(defaults, expected);
+    }
+}
@@ -0,0 +1,147 @@
+//! Keyboard reports (the bytes that go out over USB)
+//!
+//! The boot protocol report only has room for six keys which defeats the point of an analog
+//! keyboard used for gaming.  The NKRO interface (see the firmware's
+//! hid::NKRO_KEYBOARD_REPORT_DESCRIPTOR) gets a bitmap with one bit per keycode instead.
+
+use crate::key_code::KeyCode;
+
+/// Size (in bytes) of an NKRO report: one modifier byte followed by a bitmap of keycodes 0x00-0xDF
+pub const NKRO_REPORT_SIZE: usize = 29;
+
+/// Which keyboard interface the keycodes get reported on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportMode {
+    /// Six keys (plus modifiers) via the boot keyboard interface
+    Boot6Kro,
+    /// Any number of keys via the NKRO interface
+    Nkro,
+}
+
+impl ReportMode {
+    /// Converts the integer setting in `KeyboardConfig` (0 = 6KRO, 1 = NKRO)
+    pub fn from_config(mode: u8) -> ReportMode {
+        match mode {
+            1 => ReportMode::Nkro,
+            _ => ReportMode::Boot6Kro,
+        }
+    }
+
+    /// The opposite of from_config()
+    pub fn to_config(self) -> u8 {
+        match self {
+            ReportMode::Boot6Kro => 0,
+            ReportMode::Nkro => 1,
+        }
+    }
+}
+
+/// A boot protocol report: a modifier byte, a reserved byte and up to six keycodes (all of
+/// them ErrorRollOver if there are more than that)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BootReport([u8; 8]);
+
+impl BootReport {
+    /// Returns the report as it gets sent over the wire
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Adds the given keycode to the report
+    pub fn pressed(&mut self, kc: KeyCode) {
+        match kc {
+            KeyCode::No => {}
+            KeyCode::ErrorRollOver | KeyCode::PostFail | KeyCode::ErrorUndefined => {
+                self.set_all(kc)
+            }
+            kc if kc.is_modifier() => self.0[0] |= kc.as_modifier_bit(),
+            _ => match self.0[2..].iter_mut().find(|c| **c == 0) {
+                Some(slot) => *slot = kc as u8,
+                None => self.set_all(KeyCode::ErrorRollOver),
+            },
+        }
+    }
+
+    fn set_all(&mut self, kc: KeyCode) {
+        self.0[2..].fill(kc as u8);
+    }
+}
+
+impl core::iter::FromIterator<KeyCode> for BootReport {
+    fn from_iter<T>(iter: T) -> BootReport
+    where
+        T: IntoIterator<Item = KeyCode>,
+    {
+        let mut report = BootReport::default();
+        for kc in iter {
+            report.pressed(kc);
+        }
+        report
+    }
+}
+
+/// A single NKRO report: a modifier byte followed by a bitmap of keycodes 0x00-0xDF
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NkroReport([u8; NKRO_REPORT_SIZE]);
+
+impl Default for NkroReport {
+    fn default() -> NkroReport {
+        NkroReport([0; NKRO_REPORT_SIZE])
+    }
+}
+
+impl NkroReport {
+    /// Returns the report as it gets sent over the wire
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Marks the given keycode as pressed (keycodes outside the keyboard page are ignored)
+    pub fn pressed(&mut self, kc: KeyCode) {
+        let kc = kc as u8;
+        match kc {
+            0xE0..=0xE7 => self.0[0] |= 1 << (kc - 0xE0), // Modifiers
+            0x00..=0xDF => self.0[1 + kc as usize / 8] |= 1 << (kc % 8),
+            _ => {}
+        }
+    }
+}
+
+impl core::iter::FromIterator<KeyCode> for NkroReport {
+    fn from_iter<T>(iter: T) -> NkroReport
+    where
+        T: IntoIterator<Item = KeyCode>,
+    {
+        let mut report = NkroReport::default();
+        for kc in iter {
+            report.pressed(kc);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyCode::*;
+
+    #[test]
+    fn boot() {
+        let report: BootReport = [LShift, A, No, RAlt, B].into_iter().collect();
+        assert_eq!(report.as_bytes(), [0x42, 0, 0x04, 0x05, 0, 0, 0, 0]);
+        // Seven keys is one too many
+        let report: BootReport = [A, B, C, D, E, F, LCtrl, G].into_iter().collect();
+        assert_eq!(report.as_bytes(), [0x01, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn nkro() {
+        let report: NkroReport = [LShift, A, B, Z, ExSel].into_iter().collect();
+        let mut expected = [0; NKRO_REPORT_SIZE];
+        expected[0] = 0x02;
+        expected[1] = 0x30; // A (0x04) and B (0x05)
+        expected[4] = 0x20; // Z (0x1D)
+        expected[21] = 0x10; // ExSel (0xA4)
+        assert_eq!(report.as_bytes(), expected);
+    }
+}
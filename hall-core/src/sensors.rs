@@ -0,0 +1,40 @@
+//! How the sensing code gets at the hardware
+//!
+//! Every key's hall effect sensor sits on one of the channels of an analog multiplexer and all
+//! the multiplexers share the same select lines, so a full scan means selecting each channel in
+//! turn and reading every multiplexer's output.  The firmware does that with the ADCs and DMA
+//! (see scanner.rs in the firmware); `sweep()` does it the simple (blocking) way for anything
+//! that implements these traits (e.g. the mocks in mock.rs).
+
+use crate::userconfig::{MAX_CHANNELS, NUM_MULTIPLEXERS};
+
+/// Number of channels on each (physical) multiplexer
+pub const MUX_CHANNELS: u8 = 16;
+
+/// Millivolt readings for every (multiplexer, channel) combo from one sweep
+pub type Frame = [[u16; MAX_CHANNELS]; NUM_MULTIPLEXERS];
+
+/// The select lines shared by every multiplexer
+pub trait Multiplexers {
+    /// Routes *channel* to the output of every multiplexer (and waits for it to settle)
+    fn select(&mut self, channel: u8);
+}
+
+/// The analog inputs the multiplexer outputs are connected to
+pub trait AnalogInputs {
+    /// Reads the output of every multiplexer (in millivolts) into *millivolts* (indexed by
+    /// multiplexer)
+    fn read(&mut self, millivolts: &mut [u16; NUM_MULTIPLEXERS]);
+}
+
+/// Reads every channel of every multiplexer into *frame*
+pub fn sweep(mux: &mut impl Multiplexers, adc: &mut impl AnalogInputs, frame: &mut Frame) {
+    let mut millivolts = [0; NUM_MULTIPLEXERS];
+    for chan in 0..MUX_CHANNELS {
+        mux.select(chan);
+        adc.read(&mut millivolts);
+        for (multi, value) in millivolts.iter().enumerate() {
+            frame[multi][chan as usize] = *value;
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! SOCD (simultaneous opposite cardinal directions) resolution
+//!
+//! Keys can be paired up (e.g. A and D) so that only one of the two is ever down as far as the
+//! layout is concerned.  What happens when both are held depends on the pair's mode:
+//!
+//! * `Last`: the one pressed most recently wins (a.k.a. snap tap); letting go of it hands over
+//!   to the other one if that's still held
+//! * `First`: the one that was already down stays down
+//! * `Neutral`: neither
+//! * `Deeper`: whichever is pressed further down (by travel, see travel.rs) at the moment
+//!
+//! The Press()/Release() events of paired keys coming out of check_channel() only update what's
+//! physically held; what the layout gets told is worked out by resolve() after every sweep.
+//! When a pair gets removed resolve() also catches the layout up on its keys (e.g. presses the
+//! one that was being held back) since they won't see another event until they move.
+
+use crate::multiplexers::{ChannelStates, Event};
+use crate::sensors::MUX_CHANNELS;
+use crate::travel::Travel;
+use crate::userconfig;
+use serde::{Deserialize, Serialize};
+
+/// Most pairs there can be
+pub const MAX_PAIRS: usize = 8;
+/// How much deeper (0.01mm) the other key of a `Deeper` pair has to be to take over (so two
+/// keys held about equally far down don't keep swapping)
+pub const DEEPER_HYSTERESIS: u16 = 5;
+
+/// What happens when both keys of a pair are held
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    #[default]
+    Last,
+    First,
+    Neutral,
+    Deeper,
+}
+
+impl Mode {
+    /// The mode for the number used in userconfig.rs
+    pub fn from_config(mode: u8) -> Mode {
+        match mode {
+            1 => Mode::First,
+            2 => Mode::Neutral,
+            3 => Mode::Deeper,
+            _ => Mode::Last,
+        }
+    }
+
+    /// The mode by the name the console uses
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "last" => Some(Mode::Last),
+            "first" => Some(Mode::First),
+            "neutral" => Some(Mode::Neutral),
+            "deeper" => Some(Mode::Deeper),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Last => "last",
+            Mode::First => "first",
+            Mode::Neutral => "neutral",
+            Mode::Deeper => "deeper",
+        }
+    }
+}
+
+/// Two keys (multiplexer, channel) that cancel each other out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pair {
+    pub keys: [(usize, usize); 2],
+    pub mode: Mode,
+}
+
+/// Every pair (as it gets saved)
+pub type Pairs = [Option<Pair>; MAX_PAIRS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocdError {
+    /// A key doesn't exist or both keys are the same
+    InvalidKey,
+    /// Every slot is taken
+    Full,
+}
+
+/// The pairs from userconfig.rs (anything past MAX_PAIRS gets ignored; Socd::new() checks the
+/// keys)
+pub fn default_pairs() -> Pairs {
+    let mut pairs = [None; MAX_PAIRS];
+    for (slot, &(mux1, chan1, mux2, chan2, mode)) in pairs.iter_mut().zip(userconfig::SOCD_PAIRS) {
+        *slot = Some(Pair {
+            keys: [(mux1, chan1), (mux2, chan2)],
+            mode: Mode::from_config(mode),
+        });
+    }
+    pairs
+}
+
+/// Where a pair is at
+#[derive(Debug, Default, Clone, Copy)]
+struct PairState {
+    held: [bool; 2], // Physically down
+    sent: [bool; 2], // Down as far as the layout knows
+    last: usize,     // The key that went down most recently
+}
+
+/// A key of a removed pair that the layout may have been told something different about
+#[derive(Debug, Clone, Copy)]
+struct Unpaired {
+    key: (usize, usize),
+    sent: bool,
+}
+
+/// All of the pairs and where they're at
+#[derive(Debug, Default, Clone)]
+pub struct Socd {
+    pairs: Pairs,
+    states: [PairState; MAX_PAIRS],
+    unpaired: [Option<Unpaired>; MAX_PAIRS * 2],
+}
+
+impl Socd {
+    /// Uses the given pairs (e.g. loaded from flash).  Pairs with keys that don't exist (or that
+    /// are already part of an earlier pair) get dropped.
+    pub fn new(pairs: Pairs, ch_states: &[ChannelStates]) -> Socd {
+        let mut socd = Socd::default();
+        for pair in pairs.into_iter().flatten() {
+            let [a, b] = pair.keys;
+            if socd.find(a).is_none() && socd.find(b).is_none() {
+                let _ = socd.set(pair, ch_states);
+            }
+        }
+        socd
+    }
+
+    pub fn pairs(&self) -> &Pairs {
+        &self.pairs
+    }
+
+    /// Which pair (and which side of it) *key* belongs to
+    fn find(&self, key: (usize, usize)) -> Option<(usize, usize)> {
+        self.pairs.iter().enumerate().find_map(|(index, pair)| {
+            let side = pair.as_ref()?.keys.iter().position(|k| *k == key)?;
+            Some((index, side))
+        })
+    }
+
+    /// Adds a pair (replacing any pair either key was already part of).  Keys that are already
+    /// down start out that way.
+    pub fn set(&mut self, pair: Pair, ch_states: &[ChannelStates]) -> Result<(), SocdError> {
+        let [a, b] = pair.keys;
+        let exists =
+            |(mux, chan): (usize, usize)| mux < ch_states.len() && chan < MUX_CHANNELS as usize;
+        if a == b || !exists(a) || !exists(b) {
+            return Err(SocdError::InvalidKey);
+        }
+        self.remove(a);
+        self.remove(b);
+        let index = self
+            .pairs
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(SocdError::Full)?;
+        let held = pair.keys.map(|(mux, chan)| ch_states[mux][chan].pressed);
+        // Unpaired keys have already been sent as they are (unless they were part of a pair
+        // resolve() hasn't caught the layout up on yet)
+        let mut sent = held;
+        for (side, key) in pair.keys.into_iter().enumerate() {
+            if let Some(unpaired) = self.take_unpaired(key) {
+                sent[side] = unpaired.sent;
+            }
+        }
+        self.pairs[index] = Some(pair);
+        self.states[index] = PairState {
+            held,
+            sent,
+            last: 0,
+        };
+        Ok(())
+    }
+
+    /// Removes the pair *key* is part of (if any).  Returns false if it wasn't in one.
+    /// The next resolve() sends whatever it takes for the layout to see its keys as they are.
+    pub fn remove(&mut self, key: (usize, usize)) -> bool {
+        let Some((index, _)) = self.find(key) else {
+            return false;
+        };
+        if let Some(pair) = self.pairs[index].take() {
+            for (key, sent) in pair.keys.into_iter().zip(self.states[index].sent) {
+                self.take_unpaired(key);
+                if let Some(slot) = self.unpaired.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(Unpaired { key, sent });
+                }
+            }
+        }
+        true
+    }
+
+    /// Forgets (and returns) what the layout was told about *key* if it's waiting in `unpaired`
+    fn take_unpaired(&mut self, key: (usize, usize)) -> Option<Unpaired> {
+        self.unpaired
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|u| u.key == key))?
+            .take()
+    }
+
+    /// Takes an event from check_channel().  Events of keys that aren't paired get handed back
+    /// for the layout; the rest wait for resolve().
+    pub fn filter(&mut self, event: Event) -> Option<Event> {
+        let (key, down) = match event {
+            Event::Press(mux, chan) => ((mux, chan), true),
+            Event::Release(mux, chan) => ((mux, chan), false),
+        };
+        let Some((index, side)) = self.find(key) else {
+            // The layout gets this one so it's caught up already
+            self.take_unpaired(key);
+            return Some(event);
+        };
+        let state = &mut self.states[index];
+        state.held[side] = down;
+        if down {
+            state.last = side;
+        }
+        None
+    }
+
+    /// Works out which key of every pair should be down and hands the layout the events to get
+    /// there (releases first)
+    pub fn resolve(
+        &mut self,
+        ch_states: &[ChannelStates],
+        travel: &Travel,
+        mut on_event: impl FnMut(Event),
+    ) {
+        for unpaired in self.unpaired.iter_mut().filter_map(Option::take) {
+            let (mux, chan) = unpaired.key;
+            match (unpaired.sent, ch_states[mux][chan].pressed) {
+                (true, false) => on_event(Event::Release(mux, chan)),
+                (false, true) => on_event(Event::Press(mux, chan)),
+                _ => {}
+            }
+        }
+        for (pair, state) in self.pairs.iter().zip(self.states.iter_mut()) {
+            let Some(pair) = pair else {
+                continue;
+            };
+            let wanted = match state.held {
+                [true, true] => {
+                    let winner = match pair.mode {
+                        Mode::Last => Some(state.last),
+                        Mode::First => Some(1 - state.last),
+                        Mode::Neutral => None,
+                        Mode::Deeper => {
+                            let [a, b] = pair
+                                .keys
+                                .map(|(mux, chan)| ch_states[mux][chan].travel(travel));
+                            // Whichever is down already stays down unless the other is deeper
+                            let current = state.sent.iter().position(|sent| *sent);
+                            match current {
+                                Some(0) if b > a.saturating_add(DEEPER_HYSTERESIS) => Some(1),
+                                Some(1) if a > b.saturating_add(DEEPER_HYSTERESIS) => Some(0),
+                                Some(side) => Some(side),
+                                None => Some(if b > a { 1 } else { 0 }),
+                            }
+                        }
+                    };
+                    [winner == Some(0), winner == Some(1)]
+                }
+                held => held,
+            };
+            let keys = pair.keys.iter().zip(state.sent).zip(wanted);
+            for ((&(mux, chan), sent), want) in keys.clone() {
+                if sent && !want {
+                    on_event(Event::Release(mux, chan));
+                }
+            }
+            for ((&(mux, chan), sent), want) in keys {
+                if !sent && want {
+                    on_event(Event::Press(mux, chan));
+                }
+            }
+            state.sent = wanted;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::userconfig::NUM_MULTIPLEXERS;
+    use std::vec::Vec;
+
+    const A: (usize, usize) = (0, 1);
+    const D: (usize, usize) = (0, 3);
+
+    fn pair(mode: Mode) -> Pair {
+        Pair { keys: [A, D], mode }
+    }
+
+    /// Feeds *event* through the way the firmware does and returns what the layout gets
+    fn feed(socd: &mut Socd, ch_states: &mut [ChannelStates], event: Event) -> Vec<Event> {
+        match event {
+            Event::Press(mux, chan) => ch_states[mux].press(chan),
+            Event::Release(mux, chan) => ch_states[mux].release(chan),
+        }
+        let mut events: Vec<Event> = socd.filter(event).into_iter().collect();
+        socd.resolve(ch_states, &Travel::default(), |e| events.push(e));
+        events
+    }
+
+    #[test]
+    fn last_wins_and_hands_back() {
+        let mut ch_states: [ChannelStates; NUM_MULTIPLEXERS] = Default::default();
+        let mut socd = Socd::new(Default::default(), &ch_states);
+        socd.set(pair(Mode::Last), &ch_states).unwrap();
+        let events = feed(&mut socd, &mut ch_states, Event::Press(A.0, A.1));
+        assert_eq!(events, [Event::Press(A.0, A.1)]);
+        let events = feed(&mut socd, &mut ch_states, Event::Press(D.0, D.1));
+        assert_eq!(events, [Event::Release(A.0, A.1), Event::Press(D.0, D.1)]);
+        let events = feed(&mut socd, &mut ch_states, Event::Release(D.0, D.1));
+        assert_eq!(events, [Event::Release(D.0, D.1), Event::Press(A.0, A.1)]);
+    }
+
+    #[test]
+    fn neutral_releases_both() {
+        let mut ch_states: [ChannelStates; NUM_MULTIPLEXERS] = Default::default();
+        let mut socd = Socd::new(Default::default(), &ch_states);
+        socd.set(pair(Mode::Neutral), &ch_states).unwrap();
+        feed(&mut socd, &mut ch_states, Event::Press(A.0, A.1));
+        let events = feed(&mut socd, &mut ch_states, Event::Press(D.0, D.1));
+        assert_eq!(events, [Event::Release(A.0, A.1)]);
+    }
+
+    #[test]
+    fn new_drops_invalid_pairs() {
+        let ch_states: [ChannelStates; NUM_MULTIPLEXERS] = Default::default();
+        let mut pairs: Pairs = Default::default();
+        pairs[0] = Some(Pair {
+            keys: [A, (NUM_MULTIPLEXERS, 0)],
+            mode: Mode::Last,
+        });
+        pairs[1] = Some(Pair {
+            keys: [A, (0, MUX_CHANNELS as usize)],
+            mode: Mode::Last,
+        });
+        pairs[2] = Some(Pair {
+            keys: [A, A],
+            mode: Mode::Last,
+        });
+        pairs[3] = Some(pair(Mode::First));
+        pairs[4] = Some(Pair {
+            keys: [D, (1, 0)],
+            mode: Mode::Last,
+        });
+        let socd = Socd::new(pairs, &ch_states);
+        let kept: Vec<Pair> = socd.pairs().iter().flatten().copied().collect();
+        assert_eq!(kept, [pair(Mode::First)]);
+    }
+
+    #[test]
+    fn remove_sends_held_keys_again() {
+        let mut ch_states: [ChannelStates; NUM_MULTIPLEXERS] = Default::default();
+        let mut socd = Socd::new(Default::default(), &ch_states);
+        socd.set(pair(Mode::Last), &ch_states).unwrap();
+        feed(&mut socd, &mut ch_states, Event::Press(A.0, A.1));
+        feed(&mut socd, &mut ch_states, Event::Press(D.0, D.1));
+        // A is held but the layout only knows about D
+        assert!(socd.remove(A));
+        let mut events = Vec::new();
+        socd.resolve(&ch_states, &Travel::default(), |e| events.push(e));
+        assert_eq!(events, [Event::Press(A.0, A.1)]);
+        // Both are just regular keys now
+        let events = feed(&mut socd, &mut ch_states, Event::Release(A.0, A.1));
+        assert_eq!(events, [Event::Release(A.0, A.1)]);
+    }
+
+    #[test]
+    fn repairing_before_resolve_keeps_track() {
+        let mut ch_states: [ChannelStates; NUM_MULTIPLEXERS] = Default::default();
+        let mut socd = Socd::new(Default::default(), &ch_states);
+        socd.set(pair(Mode::Last), &ch_states).unwrap();
+        feed(&mut socd, &mut ch_states, Event::Press(A.0, A.1));
+        feed(&mut socd, &mut ch_states, Event::Press(D.0, D.1));
+        socd.remove(A);
+        socd.set(pair(Mode::Neutral), &ch_states).unwrap();
+        let mut events = Vec::new();
+        socd.resolve(&ch_states, &Travel::default(), |e| events.push(e));
+        assert_eq!(events, [Event::Release(D.0, D.1)]);
+    }
+}
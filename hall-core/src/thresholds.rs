@@ -0,0 +1,126 @@
+//! Per-key actuation and release thresholds
+//!
+//! Hall effect sensors (and the magnets in the stems) vary quite a bit from key to key so every
+//! (multiplexer, channel) combo can override the global thresholds from `KeyboardConfig`.
+
+use crate::userconfig::{MAX_CHANNELS, NUM_MULTIPLEXERS};
+use serde::{Deserialize, Serialize};
+
+/// Threshold overrides for a single key (`None` means use the global value)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyThresholds {
+    /// Millivolts difference where we consider it a Press()
+    pub actuation: Option<u16>,
+    /// Millivolts difference below which we consider it a Release()
+    pub release: Option<u16>,
+}
+
+/// Actuation/release thresholds for every (multiplexer, channel) combo
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThresholdTable {
+    /// Global actuation threshold (used when a key doesn't have an override)
+    pub actuation: u16,
+    /// Global release threshold (used when a key doesn't have an override)
+    pub release: u16,
+    pub keys: [[KeyThresholds; MAX_CHANNELS]; NUM_MULTIPLEXERS],
+}
+
+impl ThresholdTable {
+    /// Creates a table where every key uses the given global thresholds
+    pub fn new(actuation: u16, release: u16) -> ThresholdTable {
+        ThresholdTable {
+            actuation,
+            release,
+            keys: [[KeyThresholds::default(); MAX_CHANNELS]; NUM_MULTIPLEXERS],
+        }
+    }
+
+    /// Returns the actuation threshold for the given key
+    pub fn actuation(&self, multiplexer: usize, chan: usize) -> u16 {
+        self.get(multiplexer, chan)
+            .and_then(|t| t.actuation)
+            .unwrap_or(self.actuation)
+    }
+
+    /// Returns the release threshold for the given key
+    pub fn release(&self, multiplexer: usize, chan: usize) -> u16 {
+        self.get(multiplexer, chan)
+            .and_then(|t| t.release)
+            .unwrap_or(self.release)
+    }
+
+    /// Returns the overrides for the given key (if it exists)
+    pub fn get(&self, multiplexer: usize, chan: usize) -> Option<&KeyThresholds> {
+        self.keys.get(multiplexer).and_then(|m| m.get(chan))
+    }
+
+    /// Overrides the thresholds for the given key.  Returns false if the key doesn't exist.
+    pub fn set(&mut self, multiplexer: usize, chan: usize, thresholds: KeyThresholds) -> bool {
+        match self.keys.get_mut(multiplexer).and_then(|m| m.get_mut(chan)) {
+            Some(t) => {
+                *t = thresholds;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Updates the global (fallback) thresholds
+    pub fn set_global(&mut self, actuation: u16, release: u16) {
+        self.actuation = actuation;
+        self.release = release;
+    }
+
+    /// Removes all per-key overrides
+    pub fn clear(&mut self) {
+        self.keys = [[KeyThresholds::default(); MAX_CHANNELS]; NUM_MULTIPLEXERS];
+    }
+
+    /// Loads overrides in the form of (multiplexer, channel, actuation, release).
+    /// Entries for keys that don't exist are skipped.
+    pub fn load(&mut self, overrides: &[(usize, usize, u16, u16)]) {
+        for &(multiplexer, chan, actuation, release) in overrides {
+            self.set(
+                multiplexer,
+                chan,
+                KeyThresholds {
+                    actuation: Some(actuation),
+                    release: Some(release),
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_fall_back_to_global() {
+        let mut table = ThresholdTable::new(300, 250);
+        let partial = KeyThresholds {
+            actuation: Some(120),
+            release: None,
+        };
+        assert!(table.set(1, 2, partial));
+        assert_eq!((table.actuation(1, 2), table.release(1, 2)), (120, 250));
+        assert_eq!((table.actuation(0, 0), table.release(0, 0)), (300, 250));
+        table.set_global(400, 350);
+        assert_eq!((table.actuation(1, 2), table.release(1, 2)), (120, 350));
+        table.clear();
+        assert_eq!(table.actuation(1, 2), 400);
+    }
+
+    #[test]
+    fn keys_that_dont_exist() {
+        let mut table = ThresholdTable::new(300, 250);
+        assert!(!table.set(NUM_MULTIPLEXERS, 0, KeyThresholds::default()));
+        assert!(!table.set(0, MAX_CHANNELS, KeyThresholds::default()));
+        assert_eq!(table.get(NUM_MULTIPLEXERS, 0), None);
+        assert_eq!(table.actuation(0, MAX_CHANNELS), 300);
+        // Bad entries get skipped without upsetting the rest
+        table.load(&[(NUM_MULTIPLEXERS, 0, 1, 1), (0, 3, 150, 100)]);
+        assert_eq!((table.actuation(0, 3), table.release(0, 3)), (150, 100));
+    }
+}
@@ -0,0 +1,481 @@
+//! Recording raw ADC readings (on the keyboard) and playing them back (anywhere else)
+//!
+//! Phantom presses that only show up with certain magnets are next to impossible to debug on
+//! the keyboard itself so the firmware can stream every sweep's raw readings out over the
+//! serial port (see the `capture` console command) and `Replay` runs them through the same
+//! check_channel() pipeline on the host to get the exact same Press()/Release() sequence.
+//!
+//! A trace is a sequence of records, each one being:
+//!
+//! | Bytes | What                                   |
+//! |-------|----------------------------------------|
+//! | 1     | `SYNC` (0xA5)                          |
+//! | 1     | Kind (`KIND_SNAPSHOT` or `KIND_FRAME`) |
+//! | 2     | Size of the record (header included)   |
+//! | ...   | Payload                                |
+//!
+//! Everything is little endian.  A snapshot holds everything check_channel() looks at besides
+//! the readings (every channel's resting value, thresholds, state...) and gets sent when a
+//! capture starts and whenever any of it changes behind check_channel()'s back (e.g. after a
+//! recalibration).  Frames are the tick they were read on plus the reading of every channel.
+//!
+//! Since the serial port is shared with the console there can be text between the records;
+//! `parse()` skips over it.  Frames that didn't fit in the serial buffer get dropped so gaps
+//! in the ticks mean the capture was going faster than the host was reading.  Playback is only
+//! exact when every sweep got recorded (`capture 1`) since the filters and rapid trigger look
+//! at every reading.
+
+use crate::config_structs::KeyboardConfig;
+use crate::filter::Filter;
+use crate::multiplexers::{self, ChannelStates, Event, TriggerMode};
+use crate::sensors::{Frame, MUX_CHANNELS};
+use crate::thresholds::{KeyThresholds, ThresholdTable};
+use crate::travel::{SwitchCurve, Travel, Units, CURVE_POINTS};
+use crate::userconfig::{MAX_CHANNELS, NUM_MULTIPLEXERS};
+
+/// First byte of every record
+pub const SYNC: u8 = 0xA5;
+pub const KIND_SNAPSHOT: u8 = b'S';
+pub const KIND_FRAME: u8 = b'F';
+/// Bytes in front of every record's payload
+pub const HEADER_SIZE: usize = 4;
+/// Number of channels a record covers
+const CHANNELS: usize = NUM_MULTIPLEXERS * MUX_CHANNELS as usize;
+/// Bytes per channel in a snapshot (default, bottom-out, peak, actuation, release and flags)
+const SNAPSHOT_CHANNEL_SIZE: usize = 11;
+/// Bytes of snapshot payload in front of the channels
+const SNAPSHOT_GLOBALS_SIZE: usize = 27 + CURVE_POINTS * 2;
+pub const SNAPSHOT_SIZE: usize =
+    HEADER_SIZE + SNAPSHOT_GLOBALS_SIZE + CHANNELS * SNAPSHOT_CHANNEL_SIZE;
+pub const FRAME_SIZE: usize = HEADER_SIZE + 4 + CHANNELS * 2;
+
+const FLAG_PRESSED: u8 = 1 << 0;
+const FLAG_RAPID_TRIGGER: u8 = 1 << 1;
+
+/// The state of a single channel at the time of a snapshot
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelSnapshot {
+    pub default: u16,
+    pub bottom_out: u16,
+    pub peak: u16,
+    pub actuation: u16,
+    pub release: u16,
+    pub pressed: bool,
+    pub mode: TriggerMode,
+}
+
+/// Everything (besides the readings) check_channel() needs to come to the same conclusions the
+/// keyboard did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub tick: u32,
+    /// Ticks per second
+    pub tick_rate: u32,
+    /// Ticks between recorded frames (so gaps can be told apart from skipped sweeps)
+    pub every: u32,
+    pub rapid_trigger_press_sensitivity: u16,
+    pub rapid_trigger_release_sensitivity: u16,
+    pub encoder_press_threshold: u16,
+    /// The filter settings as they are in `KeyboardConfig` (see Filter::from_config())
+    pub filter: (u8, u8, u8),
+    pub travel: Travel,
+    pub channels: [[ChannelSnapshot; MUX_CHANNELS as usize]; NUM_MULTIPLEXERS],
+}
+
+impl Snapshot {
+    /// Captures the current state of every channel
+    pub fn capture(
+        tick: u32,
+        tick_rate: u32,
+        every: u32,
+        ch_states: &[ChannelStates],
+        thresholds: &ThresholdTable,
+        keyboard: &KeyboardConfig,
+        encoder_press_threshold: u16,
+    ) -> Snapshot {
+        let mut channels = [[ChannelSnapshot::default(); MUX_CHANNELS as usize]; NUM_MULTIPLEXERS];
+        for (multi, row) in channels.iter_mut().enumerate() {
+            for (chan, channel) in row.iter_mut().enumerate() {
+                let state = &ch_states[multi][chan];
+                *channel = ChannelSnapshot {
+                    default: state.default,
+                    bottom_out: state.bottom_out,
+                    peak: state.peak,
+                    actuation: thresholds.actuation(multi, chan),
+                    release: thresholds.release(multi, chan),
+                    pressed: state.pressed,
+                    mode: state.mode,
+                };
+            }
+        }
+        Snapshot {
+            tick,
+            tick_rate,
+            every,
+            rapid_trigger_press_sensitivity: keyboard.rapid_trigger_press_sensitivity,
+            rapid_trigger_release_sensitivity: keyboard.rapid_trigger_release_sensitivity,
+            encoder_press_threshold,
+            filter: (
+                keyboard.filter,
+                keyboard.filter_window,
+                keyboard.filter_alpha,
+            ),
+            travel: Travel::from_config(keyboard),
+            channels,
+        }
+    }
+
+    /// Writes the snapshot record into *buf* (which must be at least SNAPSHOT_SIZE bytes)
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut w = Writer::record(buf, KIND_SNAPSHOT, SNAPSHOT_SIZE);
+        w.u32(self.tick);
+        w.u32(self.tick_rate);
+        w.u32(self.every);
+        w.u16(self.rapid_trigger_press_sensitivity);
+        w.u16(self.rapid_trigger_release_sensitivity);
+        w.u16(self.encoder_press_threshold);
+        w.u8(self.filter.0);
+        w.u8(self.filter.1);
+        w.u8(self.filter.2);
+        let travel = &self.travel;
+        w.u8((travel.units == Units::Travel) as u8);
+        w.u16(travel.full_travel);
+        w.u16(travel.bottom_out_swing);
+        match travel.curve {
+            SwitchCurve::Linear => {
+                w.u8(0);
+                w.bytes(&[0; CURVE_POINTS * 2]);
+            }
+            SwitchCurve::Table(points) => {
+                w.u8(1);
+                for point in points {
+                    w.u16(point);
+                }
+            }
+        }
+        for channel in self.channels.iter().flatten() {
+            w.u16(channel.default);
+            w.u16(channel.bottom_out);
+            w.u16(channel.peak);
+            w.u16(channel.actuation);
+            w.u16(channel.release);
+            let mut flags = 0;
+            if channel.pressed {
+                flags |= FLAG_PRESSED;
+            }
+            if channel.mode == TriggerMode::RapidTrigger {
+                flags |= FLAG_RAPID_TRIGGER;
+            }
+            w.u8(flags);
+        }
+        SNAPSHOT_SIZE
+    }
+
+    fn decode(payload: &[u8]) -> Snapshot {
+        let mut r = Reader(payload);
+        let mut snapshot = Snapshot {
+            tick: r.u32(),
+            tick_rate: r.u32(),
+            every: r.u32(),
+            rapid_trigger_press_sensitivity: r.u16(),
+            rapid_trigger_release_sensitivity: r.u16(),
+            encoder_press_threshold: r.u16(),
+            filter: (r.u8(), r.u8(), r.u8()),
+            travel: Travel {
+                units: match r.u8() {
+                    1 => Units::Travel,
+                    _ => Units::Millivolts,
+                },
+                full_travel: r.u16(),
+                bottom_out_swing: r.u16(),
+                curve: SwitchCurve::Linear,
+            },
+            channels: [[ChannelSnapshot::default(); MUX_CHANNELS as usize]; NUM_MULTIPLEXERS],
+        };
+        let curve = r.u8();
+        let mut points = [0; CURVE_POINTS];
+        for point in points.iter_mut() {
+            *point = r.u16();
+        }
+        if curve == 1 {
+            snapshot.travel.curve = SwitchCurve::Table(points);
+        }
+        for channel in snapshot.channels.iter_mut().flatten() {
+            channel.default = r.u16();
+            channel.bottom_out = r.u16();
+            channel.peak = r.u16();
+            channel.actuation = r.u16();
+            channel.release = r.u16();
+            let flags = r.u8();
+            channel.pressed = flags & FLAG_PRESSED != 0;
+            channel.mode = if flags & FLAG_RAPID_TRIGGER != 0 {
+                TriggerMode::RapidTrigger
+            } else {
+                TriggerMode::Threshold
+            };
+        }
+        snapshot
+    }
+}
+
+/// Writes the frame record for *frame* (read on *tick*) into *buf* (which must be at least
+/// FRAME_SIZE bytes)
+pub fn encode_frame(tick: u32, frame: &Frame, buf: &mut [u8]) -> usize {
+    let mut w = Writer::record(buf, KIND_FRAME, FRAME_SIZE);
+    w.u32(tick);
+    for readings in frame.iter() {
+        for millivolts in readings.iter().take(MUX_CHANNELS as usize) {
+            w.u16(*millivolts);
+        }
+    }
+    FRAME_SIZE
+}
+
+fn decode_frame(payload: &[u8]) -> (u32, Frame) {
+    let mut r = Reader(payload);
+    let tick = r.u32();
+    let mut frame = [[0; MAX_CHANNELS]; NUM_MULTIPLEXERS];
+    for readings in frame.iter_mut() {
+        for millivolts in readings.iter_mut().take(MUX_CHANNELS as usize) {
+            *millivolts = r.u16();
+        }
+    }
+    (tick, frame)
+}
+
+/// A record read back from a trace
+#[allow(clippy::large_enum_variant)] // There's no Box without alloc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Snapshot(Snapshot),
+    Frame(u32, Frame),
+}
+
+/// Finds the next complete record in *bytes* (skipping anything that isn't one).  Returns it
+/// along with the number of bytes used up, or None if there isn't a whole record left.
+pub fn parse(bytes: &[u8]) -> Option<(Record, usize)> {
+    let mut start = 0;
+    loop {
+        start += bytes[start..].iter().position(|b| *b == SYNC)?;
+        let header = bytes.get(start..start + HEADER_SIZE)?;
+        let size = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let expected = match header[1] {
+            KIND_SNAPSHOT => SNAPSHOT_SIZE,
+            KIND_FRAME => FRAME_SIZE,
+            _ => 0,
+        };
+        if size != expected {
+            // Not really a record (e.g. a stray SYNC byte); keep looking
+            start += 1;
+            continue;
+        }
+        let payload = bytes.get(start + HEADER_SIZE..start + size)?;
+        let record = match header[1] {
+            KIND_SNAPSHOT => Record::Snapshot(Snapshot::decode(payload)),
+            _ => {
+                let (tick, frame) = decode_frame(payload);
+                Record::Frame(tick, frame)
+            }
+        };
+        return Some((record, start + size));
+    }
+}
+
+/// Keeps track of a running capture (on the keyboard)
+#[derive(Debug, Default, Clone)]
+pub struct Capture {
+    every: u32,     // Record every this many sweeps (0 = not capturing)
+    countdown: u32, // Sweeps left to skip before the next recording
+    tick: u32,      // Sweeps since the capture started
+    snapshot_needed: bool,
+}
+
+impl Capture {
+    /// Starts recording every *every* sweeps (beginning with a snapshot)
+    pub fn start(&mut self, every: u32) {
+        *self = Capture {
+            every: every.max(1),
+            snapshot_needed: true,
+            ..Default::default()
+        };
+    }
+
+    pub fn stop(&mut self) {
+        self.every = 0;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.every > 0
+    }
+
+    /// Sweeps between recordings
+    pub fn every(&self) -> u32 {
+        self.every
+    }
+
+    /// Something check_channel() depends on changed so a new snapshot has to go out before the
+    /// next frame
+    pub fn request_snapshot(&mut self) {
+        self.snapshot_needed = true;
+    }
+
+    /// Counts a sweep.  Returns its tick (and whether a snapshot has to go out first) if it
+    /// should be recorded.
+    pub fn sweep(&mut self) -> Option<(u32, bool)> {
+        if self.every == 0 {
+            return None;
+        }
+        let tick = self.tick;
+        self.tick = self.tick.wrapping_add(1);
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return None;
+        }
+        self.countdown = self.every - 1;
+        Some((tick, self.snapshot_needed))
+    }
+
+    /// The requested snapshot made it out
+    pub fn snapshot_sent(&mut self) {
+        self.snapshot_needed = false;
+    }
+}
+
+/// Runs a recorded trace through check_channel() the way the keyboard did
+pub struct Replay {
+    pub ch_states: [ChannelStates; NUM_MULTIPLEXERS],
+    pub thresholds: ThresholdTable,
+    /// The last snapshot (None until the first one shows up; frames before it get skipped)
+    pub snapshot: Option<Snapshot>,
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Replay {
+            ch_states: Default::default(),
+            thresholds: ThresholdTable::new(0, 0),
+            snapshot: None,
+        }
+    }
+}
+
+impl Replay {
+    /// Plays back a record, handing every Press()/Release() (and the tick it happened on) to
+    /// *on_event*
+    pub fn play(&mut self, record: &Record, mut on_event: impl FnMut(u32, Event)) {
+        match record {
+            Record::Snapshot(snapshot) => self.restore(snapshot),
+            Record::Frame(tick, frame) => {
+                let Some(snapshot) = &self.snapshot else {
+                    return;
+                };
+                multiplexers::scan(
+                    frame,
+                    &mut self.ch_states,
+                    &self.thresholds,
+                    snapshot.rapid_trigger_press_sensitivity,
+                    snapshot.rapid_trigger_release_sensitivity,
+                    &snapshot.travel,
+                    |event| on_event(*tick, event),
+                );
+            }
+        }
+    }
+
+    /// Puts every channel into the state the snapshot has
+    fn restore(&mut self, snapshot: &Snapshot) {
+        // The filter history can't be captured so it only gets reset when the settings change
+        let filter_changed = self.snapshot.as_ref().map(|s| s.filter) != Some(snapshot.filter);
+        let (kind, window, alpha) = snapshot.filter;
+        for (multi, row) in snapshot.channels.iter().enumerate() {
+            let states = &mut self.ch_states[multi];
+            for (chan, channel) in row.iter().enumerate() {
+                if filter_changed {
+                    states.update_filter_by_index(chan, Filter::from_config(kind, window, alpha));
+                }
+                if channel.pressed != states[chan].pressed {
+                    if channel.pressed {
+                        states.press(chan);
+                    } else {
+                        states.release(chan);
+                    }
+                }
+                states.update_default_by_index(chan, channel.default);
+                states.update_bottom_out_by_index(chan, channel.bottom_out);
+                if states[chan].mode != channel.mode {
+                    states.update_mode_by_index(chan, channel.mode);
+                }
+                states.update_peak_by_index(chan, channel.peak);
+                self.thresholds.set(
+                    multi,
+                    chan,
+                    KeyThresholds {
+                        actuation: Some(channel.actuation),
+                        release: Some(channel.release),
+                    },
+                );
+            }
+        }
+        self.snapshot = Some(snapshot.clone());
+    }
+}
+
+/// Writes a record into a buffer
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn record(buf: &'a mut [u8], kind: u8, size: usize) -> Writer<'a> {
+        buf[0] = SYNC;
+        buf[1] = kind;
+        buf[2..4].copy_from_slice(&(size as u16).to_le_bytes());
+        Writer {
+            buf,
+            pos: HEADER_SIZE,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+/// Reads a record's payload (whose size has already been checked)
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let (bytes, rest) = self.0.split_at(N);
+        self.0 = rest;
+        let mut out = [0; N];
+        out.copy_from_slice(bytes);
+        out
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+}
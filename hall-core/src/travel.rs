@@ -0,0 +1,151 @@
+//! Turning millivolts into key travel
+//!
+//! How many millivolts a key moves depends on the magnet, the sensor and how far apart they
+//! are so thresholds in millivolts mean something different for every switch type.  Every key
+//! knows its resting reading (`ChannelState::default`) and, once it's been pushed all the way
+//! down, its bottom-out reading (`ChannelState::bottom_out`).  Where a reading sits between the
+//! two gets mapped to travel (in 0.01mm) by the switch curve: magnets get a lot stronger the
+//! closer they get so the millivolts per millimetre go up towards the bottom of the travel.
+//!
+//! With `keyboard.threshold_units` set to 1 the actuation/release thresholds (global and per
+//! key) and the rapid trigger sensitivities are all in 0.01mm instead of millivolts.
+
+use crate::config_structs::KeyboardConfig;
+use crate::userconfig;
+
+/// Number of points in a switch curve lookup table
+pub const CURVE_POINTS: usize = 11;
+/// Curve values (and the fractions of the swing they're for) are out of this much
+pub const CURVE_SCALE: u32 = 1000;
+
+/// What thresholds and rapid trigger sensitivities are measured in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Units {
+    #[default]
+    Millivolts,
+    /// 0.01mm of key travel
+    Travel,
+}
+
+/// How travel relates to the fraction of its swing a key has moved
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchCurve {
+    /// Travel is proportional to the millivolts
+    #[default]
+    Linear,
+    /// Travel (out of CURVE_SCALE) at every 1/(CURVE_POINTS - 1) of the swing, linearly
+    /// interpolated in between
+    Table([u16; CURVE_POINTS]),
+}
+
+impl SwitchCurve {
+    /// Maps a fraction of the swing to a fraction of the travel (both out of CURVE_SCALE)
+    pub fn apply(&self, fraction: u32) -> u32 {
+        let fraction = fraction.min(CURVE_SCALE);
+        match self {
+            SwitchCurve::Linear => fraction,
+            SwitchCurve::Table(points) => {
+                let step = CURVE_SCALE / (CURVE_POINTS as u32 - 1);
+                let index = (fraction / step) as usize;
+                if index >= CURVE_POINTS - 1 {
+                    return points[CURVE_POINTS - 1] as u32;
+                }
+                let (low, high) = (points[index] as u32, points[index + 1] as u32);
+                let within = fraction % step;
+                if high >= low {
+                    low + (high - low) * within / step
+                } else {
+                    low - (low - high) * within / step
+                }
+            }
+        }
+    }
+}
+
+/// Everything needed to turn a key's millivolts into travel
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Travel {
+    pub units: Units,
+    pub curve: SwitchCurve,
+    /// Total travel of the switches (0.01mm)
+    pub full_travel: u16,
+    /// Millivolts between resting and bottomed out for keys that haven't been bottomed out yet
+    pub bottom_out_swing: u16,
+}
+
+impl Travel {
+    pub fn from_config(keyboard: &KeyboardConfig) -> Travel {
+        Travel {
+            units: match keyboard.threshold_units {
+                1 => Units::Travel,
+                _ => Units::Millivolts,
+            },
+            curve: match keyboard.switch_curve {
+                1 => SwitchCurve::Table(userconfig::SWITCH_CURVE),
+                _ => SwitchCurve::Linear,
+            },
+            full_travel: keyboard.full_travel,
+            bottom_out_swing: keyboard.bottom_out_swing,
+        }
+    }
+
+    /// Travel (0.01mm) of a key that has moved *difference* mV of its *swing* mV
+    pub fn travel(&self, difference: u16, swing: u16) -> u16 {
+        let swing = swing.max(1) as u32;
+        let fraction = (difference as u32).min(swing) * CURVE_SCALE / swing;
+        (self.curve.apply(fraction) * self.full_travel as u32 / CURVE_SCALE) as u16
+    }
+
+    /// How far a key that has moved *difference* mV of its *swing* mV is from resting, in
+    /// whatever units the thresholds are in
+    pub fn distance(&self, difference: u16, swing: u16) -> u16 {
+        match self.units {
+            Units::Millivolts => difference,
+            Units::Travel => self.travel(difference, swing),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn travel(curve: SwitchCurve) -> Travel {
+        Travel {
+            units: Units::Travel,
+            curve,
+            full_travel: 400,
+            bottom_out_swing: 1000,
+        }
+    }
+
+    #[test]
+    fn linear() {
+        let travel = travel(SwitchCurve::Linear);
+        assert_eq!(travel.travel(0, 1000), 0);
+        assert_eq!(travel.travel(250, 1000), 100);
+        assert_eq!(travel.travel(1000, 1000), 400);
+        // Past the bottom-out reading is still bottomed out
+        assert_eq!(travel.travel(1500, 1000), 400);
+        // A key without a swing doesn't divide by zero
+        assert_eq!(travel.travel(10, 0), 400);
+    }
+
+    #[test]
+    fn table_interpolates() {
+        let points = [0, 300, 500, 600, 700, 750, 800, 850, 900, 950, 1000];
+        let travel = travel(SwitchCurve::Table(points));
+        assert_eq!(travel.travel(100, 1000), 120);
+        // Halfway between 300 and 500
+        assert_eq!(travel.travel(150, 1000), 160);
+        assert_eq!(travel.travel(1000, 1000), 400);
+    }
+
+    #[test]
+    fn distance_units() {
+        let mut travel = travel(SwitchCurve::Linear);
+        assert_eq!(travel.distance(500, 1000), 200);
+        travel.units = Units::Millivolts;
+        assert_eq!(travel.distance(500, 1000), 500);
+    }
+}
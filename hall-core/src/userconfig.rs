@@ -0,0 +1,86 @@
+pub const NORTH_DOWN: u8 = 1; // (1 or 0) If the magnets in your stems are north side down or south side down
+// How far (in millivolts) a key needs to move down before it's considered pressed
+pub const ACTUATION_THRESHOLD: u16 = 20; // 10-80 or so.
+// How far it needs to move back up before it's considered released
+pub const RELEASE_THRESHOLD: u16 = 5; // 5-50 or so; Prevents bouncing by implementing some hysteresis
+// Per-key overrides for the above as (<multiplexer>, <channel>, <actuation>, <release>)
+pub const KEY_THRESHOLDS: &[(usize, usize, u16, u16)] = &[(4, 10, 60, 20)]; // Encoder press
+// Rapid trigger: how far (in millivolts) a key has to move down/up from its shallowest/deepest
+// point before it's pressed/released again (regardless of where it is in its travel)
+pub const RAPID_TRIGGER_PRESS_SENSITIVITY: u16 = 8; // 3-30 or so
+pub const RAPID_TRIGGER_RELEASE_SENSITIVITY: u16 = 8; // 3-30 or so
+// Keys (<multiplexer>, <channel>) that use rapid trigger instead of fixed thresholds
+pub const RAPID_TRIGGER_KEYS: &[(usize, usize)] = &[(0, 13), (0, 12), (0, 14), (1, 7)]; // WASD
+// SOCD pairs (<multiplexer>, <channel>, <multiplexer>, <channel>, <mode>): only one key of a pair
+// is down at a time.  When both are held: 0 = last pressed wins, 1 = first pressed wins,
+// 2 = neither, 3 = the one pressed further down.  E.g. A/D and W/S: (0, 12, 1, 7, 0), (0, 13, 0, 14, 0)
+pub const SOCD_PAIRS: &[(usize, usize, usize, usize, u8)] = &[];
+// Noise filtering of the raw ADC readings (0 = none, 1 = boxcar/moving average, 2 = IIR, 3 = median)
+pub const FILTER: u8 = 1;
+pub const FILTER_WINDOW: u8 = 4; // Readings averaged/considered by the boxcar and median filters (1-8)
+pub const FILTER_ALPHA: u8 = 64; // How much each new reading counts for with the IIR filter (out of 256)
+// What the thresholds and rapid trigger sensitivities above are in (0 = millivolts, 1 = 0.01mm
+// of key travel).  Travel needs to know the switches' total travel and how the mV relate to it.
+pub const THRESHOLD_UNITS: u8 = 0;
+pub const FULL_TRAVEL: u16 = 400; // Total travel of the switches (0.01mm)
+// Millivolts between resting and bottomed out for keys that haven't been bottomed out yet (better
+// too low than too high since keys learn their real bottom-out reading as they get pressed)
+pub const BOTTOM_OUT_SWING: u16 = 300;
+// How travel relates to the mV (0 = linear, 1 = the SWITCH_CURVE lookup table)
+pub const SWITCH_CURVE_TYPE: u8 = 1;
+// Travel (out of 1000) at every 10% of a key's mV swing.  Magnets get stronger the closer they
+// get so the first bit of the swing covers a lot more travel than the last.
+pub const SWITCH_CURVE: [u16; 11] = [0, 290, 460, 590, 690, 770, 840, 895, 940, 975, 1000];
+// Full-travel calibration ("calibrate" on the console or the QK_CALIBRATE key): keys that don't
+// move at least this many millivolts when pressed all the way down fail
+pub const CALIBRATION_MIN_SWING: u16 = 150;
+pub const CALIBRATION_TIMEOUT: u32 = 10; // Give up after this many seconds without a key passing
+// Analog gamepad (keys bound to GP_* codes in the keymap): travel (0.01mm) that doesn't move
+// the stick/trigger and how the rest maps to it (0 = linear, 1 = smooth/quadratic, 2 = fast)
+pub const GAMEPAD_DEADZONE: u16 = 30;
+pub const GAMEPAD_CURVE: u8 = 0;
+// Dynamic keystrokes (keys bound to DKS_* codes in the keymap): where the press/release and
+// bottom-out/lift points are (0.01mm of travel) and how long (ms) tapped keys stay down
+pub const DKS_ACTUATION: u16 = 100;
+pub const DKS_BOTTOM_OUT: u16 = 340;
+pub const DKS_TAP_TIME: u16 = 10;
+// What each DKS slot does at the press, bottom-out, lift and release points as (<op>, <HID
+// usage>) with 0 = nothing, 1 = press, 2 = release, 3 = tap.  E.g. hold W and tap Space when
+// bottomed out: [(1, 0x1A), (3, 0x2C), (0, 0), (0, 0)]
+pub const DKS_SLOTS: &[[(u8, u8); 4]] = &[];
+// Mod-tap by depth (keys bound to MT() codes in the keymap): travel (0.01mm) where the tap key
+// goes down and where the modifiers take over from it
+pub const MOD_TAP_TAP_POINT: u16 = 100;
+pub const MOD_TAP_HOLD_POINT: u16 = 300;
+// How long (ms) a tap-dance key (see layers.rs) waits for another tap
+pub const TAP_DANCE_WINDOW: u16 = 200;
+// Number of analog multiplexers on this keyboard
+pub const NUM_MULTIPLEXERS: usize = 5;
+// Maximum number of channels on each multiplexer/remote control
+pub const MAX_CHANNELS: usize = 21; // Multiplexers are always 16 but my remote has 21 buttons 🤷
+// Rotary encoder (<multiplexer> and the channels its two sensors and the press go to)
+pub const ENCODER_MUX: usize = 4;
+pub const ENCODER_CHANNEL1: usize = 8;
+pub const ENCODER_CHANNEL2: usize = 9;
+pub const ENCODER_PRESS_CHANNEL: usize = 10;
+pub const ENCODER_PRESS_THRESHOLD: u16 = 60; // Millivolts difference before we consider it an encoder press
+// USB identifiers (these are for a generic keyboard)
+pub const USB_VID: u16 = 0x16c0;
+pub const USB_PID: u16 = 0x27db;
+pub const USB_MANUFACTURER: &str = "SeanCo";
+pub const USB_PRODUCT: &str = "ShitBoardv1";
+pub const USB_SERIAL_NUMBER: &str = "0";
+// How often (Hz) the host polls for keyboard reports.  We also scan this often.  The USB PHY
+// (PB14/PB15) is full speed only so the host won't poll more than once a frame (1000Hz).
+pub const POLLING_RATE: u32 = 1_000;
+// 0 = six keys at a time (boot keyboard), 1 = N-key rollover (falls back to 6KRO for boot-only hosts)
+pub const REPORT_MODE: u8 = 1;
+// Set to false on boards without the SPI flash chip (on SPI1 with CS on PB0); settings changed
+// over the serial console then get saved to the end of the internal flash instead
+pub const SPI_FLASH: bool = true;
+// So we don't bother with disconnected pins, all mV values below this are ignored
+pub const IGNORE_BELOW: u16 = 60; // Probably leave this alone; just saves a smidge of CPU time
+// Don't touch keyboard stuff below this point unless you know what you're doing
+pub const RECALIBRATION_RATE: u32 = 1; // How often to recalibrate all switches (seconds; 0 = never)
+pub const RECALIBRATION_NOISE: u16 = 10; // Skip keys that wobbled more than this (mV) since the last recalibration
+pub const RECALIBRATION_MAX_STEP: u16 = 2; // Temperature drift is slow so only move the default a little at a time
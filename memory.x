@@ -6,7 +6,9 @@ MEMORY
   /* STM32H742xI/743xI/753xI       */
   /* STM32H745xI/747xI/755xI/757xI */
   /* STM32H7A3xI/7B3xI             */
-  FLASH  : ORIGIN = 0x08000000, LENGTH = 2M
+  /* The last two 128K sectors (of bank 2) are left out; they hold the settings when there's no
+     SPI flash chip (see flash_log.rs/internal_flash.rs) */
+  FLASH  : ORIGIN = 0x08000000, LENGTH = 2M - 256K
 
   /* STM32H742xG/743xG       */
   /* STM32H745xG/STM32H747xG */
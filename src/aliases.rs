@@ -1,10 +1,16 @@
 //! A collection of type aliases to cut down on code clutter
 
 use analog_multiplexer::{DummyPin, Multiplexer};
-use stm32h7xx_hal::gpio::gpioa::{PA0, PA1, PA2, PA3, PA4, PA5, PA6, PA7, PA8, PA9, PA10, PA15};
-use stm32h7xx_hal::gpio::gpiob::{PB0, PB1, PB3, PB4, PB5, PB8, PB9, PB10, PB12};
+use stm32h7xx_hal::gpio::gpioa::{PA0, PA1, PA2, PA3, PA4, PA15};
+use stm32h7xx_hal::gpio::gpiob::{PB0, PB8, PB12};
 use stm32h7xx_hal::gpio::gpioc::{PC13};
-use stm32h7xx_hal::gpio::{Alternate, Analog, Input, Output, PushPull, AF5, AF6};
+use stm32h7xx_hal::gpio::gpioe::{PE3};
+use stm32h7xx_hal::gpio::{Analog, Output, PushPull};
+use stm32h7xx_hal::adc::{Adc, Enabled};
+use stm32h7xx_hal::dma::dma::{Stream0, Stream1};
+use stm32h7xx_hal::dma::{DBTransfer, PeripheralToMemory, Transfer};
+use stm32h7xx_hal::pac::{ADC1, ADC2, DMA1, SPI1};
+use stm32h7xx_hal::spi::{self, Spi};
 
 // Handy type aliases to avoid a lot of long lines/typing later...
 pub type AnalogPins = (
@@ -21,15 +27,26 @@ pub type S2 = PB12<Output<PushPull>>;
 pub type S3 = PA15<Output<PushPull>>;
 pub type EN = DummyPin; // NOTE: We assume the enable pin goes to GND at all times
 
-// Power status pin (goes floating when power is connected)
-pub type POWER = PB10<Input>;
-
-// Relay pin
-pub type RELAY1 = PB1<Output<PushPull>>;
-pub type RELAY2 = PA9<Output<PushPull>>;
-pub type RELAY3 = PA10<Output<PushPull>>;
+// Status LED (the one on the WeAct H743 boards)
+pub type Led = PE3<Output<PushPull>>;
 
 // TODO: Add a proper DummyPin struct somewhere we can use with EN
 // NOTE: embedded_hal really needs a DummyPin feature for things like unused driver pins!
 pub type SelectPins = (S0, S1, S2, S3, EN);
 pub type Multiplex = Multiplexer<SelectPins>;
+
+// DMA transfers used by the scanner (see scanner.rs); ADC1 reads PA0-PA2 and ADC2 reads PA3-PA4
+pub type Adc1Buffer = &'static mut [u16; 3];
+pub type Adc2Buffer = &'static mut [u16; 2];
+pub type Adc1Transfer =
+    Transfer<Stream0<DMA1>, Adc<ADC1, Enabled>, PeripheralToMemory, Adc1Buffer, DBTransfer>;
+pub type Adc2Transfer =
+    Transfer<Stream1<DMA1>, Adc<ADC2, Enabled>, PeripheralToMemory, Adc2Buffer, DBTransfer>;
+
+// SPI NOR flash holding the settings (see storage.rs); SCK = PA5, MISO = PA6, MOSI = PA7
+pub type FlashSpi = Spi<SPI1, spi::Enabled>;
+pub type FlashCs = PB0<Output<PushPull>>;
+pub type SpiSettings = crate::storage::SpiStorage<FlashSpi, FlashCs>;
+// Builds without the SPI flash use the end of the internal flash instead (see flash_log.rs)
+pub type InternalSettings = crate::flash_log::LogStorage<crate::internal_flash::InternalFlash>;
+pub type SettingsStorage = crate::storage::Backend<SpiSettings, InternalSettings>;
@@ -0,0 +1,420 @@
+//! A line-oriented command shell on the USB serial (CDC) port
+//!
+//! Bytes coming in over the serial port get collected by a LineBuffer until a newline shows up
+//! and the finished line gets turned into a Command by parse().  Running the commands is up to
+//! the caller since they touch just about every resource the firmware has.
+
+use crate::multiplexers::TriggerMode;
+use crate::report::ReportMode;
+use crate::thresholds::KeyThresholds;
+use crate::{dks, socd};
+use heapless::{Deque, String, Vec};
+
+/// Longest line (in bytes) the console will accept
+pub const MAX_LINE: usize = 64;
+/// Longest field name/value accepted by get/set
+pub const MAX_ARG: usize = 48;
+/// Output waiting to be sent over the serial port
+pub type TxQueue = Deque<u8, 2048>;
+/// Most a single command can print
+pub const MAX_OUTPUT: usize = 2048;
+/// What gets printed once the console is ready for the next command
+pub const PROMPT: &str = "> ";
+/// Goes at the end of output that got cut short
+pub const TRUNCATED: &str = "(output truncated)\r\n";
+/// Room Output keeps for finishing the line it was on and TRUNCATED
+const OUTPUT_RESERVE: usize = 2 + TRUNCATED.len();
+
+pub const HELP: &str = "\
+Commands:\r
+  help                      Show this message\r
+  states [mux]              Dump the channel values of all (or one) multiplexer(s)\r
+  values [mux]              Same as states but one line per multiplexer (for scripts)\r
+  travel [mux]              Same as values but how far down each key is (0.01mm)\r
+  get [section[.field]]     Show config values (e.g. get keyboard.actuation_threshold)\r
+  set <section.field> <val> Change a config value\r
+  recal                     Use the current values of all released keys as their defaults\r
+  layer <n>                 Switch the default layer\r
+  key <layer> <mux> <chan> [code]\r
+                            Show (or change) a key's code (e.g. key 0 0 0 0x1D; see keymap.rs)\r
+  threshold <mux> <chan> [<act> <rel>|off]\r
+                            Show (or override/go back to the global) thresholds of a key\r
+  trigger <mux> <chan> [threshold|rapid]\r
+                            Show (or change) how a key triggers (rapid = rapid trigger)\r
+  mode <6kro|nkro>          Switch how keys get reported to the host\r
+  socd [<mux> <chan> <mux> <chan> <last|first|neutral|deeper|off>]\r
+                            Show the SOCD pairs (or pair up two keys/unpair them)\r
+  dks [<slot> <press|bottom|lift|release> <down|up|tap|none> [code]]\r
+                            Show the DKS slots (or change what one does at a point)\r
+  calibrate [done|cancel]   Full-travel calibration: press every key all the way down once\r
+  capture <n|off>           Stream the raw readings of every nth sweep (binary; see trace.rs)\r
+  bootloader                Reboot into the STM32 bootloader (for flashing)\r
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    States(Option<usize>),
+    /// Like States but as "<mux> <value> <value>..." lines
+    Values(Option<usize>),
+    /// Like Values but in 0.01mm of travel
+    Travel(Option<usize>),
+    Get(String<MAX_ARG>),
+    Set(String<MAX_ARG>, String<MAX_ARG>),
+    Recalibrate,
+    Layer(usize),
+    /// Show (or change when there's a code) the keymap entry at (layer, mux, channel)
+    Key(usize, usize, usize, Option<u16>),
+    /// Show (or change when there's a change) the thresholds of the key at (mux, channel).
+    /// Overrides of None go back to the global thresholds.
+    Threshold(usize, usize, Option<KeyThresholds>),
+    /// Show (or change when there's a mode) the trigger mode of the key at (mux, channel)
+    Trigger(usize, usize, Option<TriggerMode>),
+    Mode(ReportMode),
+    /// Show every SOCD pair (or change one)
+    Socd(Option<SocdChange>),
+    /// Show every DKS slot (or change one)
+    Dks(Option<DksChange>),
+    /// Start (recording every nth sweep) or stop (None) streaming raw readings
+    Capture(Option<u32>),
+    Calibrate(CalibrationStep),
+    Bootloader,
+}
+
+/// What to do with the full-travel calibration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationStep {
+    Start,
+    /// Finish early (keys that haven't been pressed yet fail)
+    Done,
+    /// Stop without changing anything
+    Cancel,
+}
+
+/// Pairs up two keys (multiplexer, channel) or unpairs them (no mode)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocdChange {
+    pub keys: [(usize, usize); 2],
+    pub mode: Option<socd::Mode>,
+}
+
+/// Changes what a DKS slot does at one point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DksChange {
+    pub slot: usize,
+    pub point: dks::Point,
+    pub step: dks::Step,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// Nothing but whitespace
+    Empty,
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument,
+}
+
+impl ParseError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ParseError::Empty => "",
+            ParseError::UnknownCommand => "Unknown command (try 'help')",
+            ParseError::MissingArgument => "Missing argument (try 'help')",
+            ParseError::InvalidArgument => "Invalid argument (try 'help')",
+        }
+    }
+}
+
+fn to_arg(arg: &str) -> Result<String<MAX_ARG>, ParseError> {
+    let mut s = String::new();
+    s.push_str(arg).map_err(|_| ParseError::InvalidArgument)?;
+    Ok(s)
+}
+
+fn to_number(arg: Option<&str>) -> Result<usize, ParseError> {
+    arg.ok_or(ParseError::MissingArgument)?
+        .parse()
+        .map_err(|_| ParseError::InvalidArgument)
+}
+
+/// Parses a keymap code (decimal or hex with a 0x prefix)
+fn to_code(arg: &str) -> Result<u16, ParseError> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+    .map_err(|_| ParseError::InvalidArgument)
+}
+
+/// Turns a line of input into a Command
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_whitespace();
+    let command = words.next().ok_or(ParseError::Empty)?;
+    match command {
+        "help" | "?" => Ok(Command::Help),
+        "states" => match words.next() {
+            Some(mux) => Ok(Command::States(Some(to_number(Some(mux))?))),
+            None => Ok(Command::States(None)),
+        },
+        "values" => match words.next() {
+            Some(mux) => Ok(Command::Values(Some(to_number(Some(mux))?))),
+            None => Ok(Command::Values(None)),
+        },
+        "travel" => match words.next() {
+            Some(mux) => Ok(Command::Travel(Some(to_number(Some(mux))?))),
+            None => Ok(Command::Travel(None)),
+        },
+        "get" => Ok(Command::Get(to_arg(words.next().unwrap_or(""))?)),
+        "set" => {
+            let name = words.next().ok_or(ParseError::MissingArgument)?;
+            let value = words.next().ok_or(ParseError::MissingArgument)?;
+            Ok(Command::Set(to_arg(name)?, to_arg(value)?))
+        }
+        "recal" => Ok(Command::Recalibrate),
+        "layer" => Ok(Command::Layer(to_number(words.next())?)),
+        "key" => {
+            let layer = to_number(words.next())?;
+            let mux = to_number(words.next())?;
+            let chan = to_number(words.next())?;
+            let code = words.next().map(to_code).transpose()?;
+            Ok(Command::Key(layer, mux, chan, code))
+        }
+        "threshold" => {
+            let mux = to_number(words.next())?;
+            let chan = to_number(words.next())?;
+            let change = match words.next() {
+                None => None,
+                Some("off") => Some(KeyThresholds::default()),
+                Some(actuation) => {
+                    let to_u16 = |n: usize| u16::try_from(n).map_err(|_| ParseError::InvalidArgument);
+                    let actuation = to_u16(to_number(Some(actuation))?)?;
+                    let release = to_u16(to_number(words.next())?)?;
+                    // The key has to be able to get back above the release point after actuating
+                    if release >= actuation {
+                        return Err(ParseError::InvalidArgument);
+                    }
+                    Some(KeyThresholds {
+                        actuation: Some(actuation),
+                        release: Some(release),
+                    })
+                }
+            };
+            Ok(Command::Threshold(mux, chan, change))
+        }
+        "trigger" => {
+            let mux = to_number(words.next())?;
+            let chan = to_number(words.next())?;
+            let mode = words
+                .next()
+                .map(|name| TriggerMode::from_name(name).ok_or(ParseError::InvalidArgument))
+                .transpose()?;
+            Ok(Command::Trigger(mux, chan, mode))
+        }
+        "mode" => match words.next() {
+            Some("6kro") => Ok(Command::Mode(ReportMode::Boot6Kro)),
+            Some("nkro") => Ok(Command::Mode(ReportMode::Nkro)),
+            Some(_) => Err(ParseError::InvalidArgument),
+            None => Err(ParseError::MissingArgument),
+        },
+        "socd" => {
+            let Some(mux) = words.next() else {
+                return Ok(Command::Socd(None));
+            };
+            let first = (to_number(Some(mux))?, to_number(words.next())?);
+            let second = (to_number(words.next())?, to_number(words.next())?);
+            let mode = match words.next().ok_or(ParseError::MissingArgument)? {
+                "off" => None,
+                name => Some(socd::Mode::from_name(name).ok_or(ParseError::InvalidArgument)?),
+            };
+            Ok(Command::Socd(Some(SocdChange {
+                keys: [first, second],
+                mode,
+            })))
+        }
+        "dks" => {
+            let Some(slot) = words.next() else {
+                return Ok(Command::Dks(None));
+            };
+            let slot = to_number(Some(slot))?;
+            let point = words.next().ok_or(ParseError::MissingArgument)?;
+            let point = dks::Point::from_name(point).ok_or(ParseError::InvalidArgument)?;
+            let op = words.next().ok_or(ParseError::MissingArgument)?;
+            let op = dks::Op::from_name(op).ok_or(ParseError::InvalidArgument)?;
+            let key = match op {
+                dks::Op::None => 0,
+                _ => match to_code(words.next().ok_or(ParseError::MissingArgument)?)? {
+                    code if dks::valid_key(code) => code as u8,
+                    _ => return Err(ParseError::InvalidArgument),
+                },
+            };
+            Ok(Command::Dks(Some(DksChange {
+                slot,
+                point,
+                step: dks::Step { op, key },
+            })))
+        }
+        "calibrate" => match words.next() {
+            None => Ok(Command::Calibrate(CalibrationStep::Start)),
+            Some("done") => Ok(Command::Calibrate(CalibrationStep::Done)),
+            Some("cancel") => Ok(Command::Calibrate(CalibrationStep::Cancel)),
+            Some(_) => Err(ParseError::InvalidArgument),
+        },
+        "capture" => match words.next() {
+            Some("off") => Ok(Command::Capture(None)),
+            Some(every) => match every.parse() {
+                Ok(every) if every > 0 => Ok(Command::Capture(Some(every))),
+                _ => Err(ParseError::InvalidArgument),
+            },
+            None => Err(ParseError::MissingArgument),
+        },
+        "bootloader" => Ok(Command::Bootloader),
+        _ => Err(ParseError::UnknownCommand),
+    }
+}
+
+/// Collects incoming bytes into lines (handling backspace along the way)
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    buf: Vec<u8, MAX_LINE>,
+    overflowed: bool,
+    after_cr: bool, // The last byte was a \r (so a \n right after it is part of the same line end)
+}
+
+impl LineBuffer {
+    /// Adds a byte to the line.  Returns the finished line when *byte* ends it (\r, \n or
+    /// \r\n).  Lines that were too long come back empty so they get ignored.
+    pub fn feed(&mut self, byte: u8) -> Option<String<MAX_LINE>> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match byte {
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                let mut line = String::new();
+                if !self.overflowed {
+                    if let Ok(s) = core::str::from_utf8(&self.buf) {
+                        let _ = line.push_str(s);
+                    }
+                }
+                self.buf.clear();
+                self.overflowed = false;
+                Some(line)
+            }
+            0x08 | 0x7f => {
+                // Backspace/delete
+                self.buf.pop();
+                None
+            }
+            _ => {
+                if self.buf.push(byte).is_err() {
+                    self.overflowed = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+/// What a command prints.  Once something doesn't fit nothing else gets added and finish()
+/// says the output got cut short (instead of it just stopping).
+#[derive(Debug, Default)]
+pub struct Output {
+    text: String<MAX_OUTPUT>,
+    truncated: bool,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Adds *s* if it fits (returning whether it did)
+    pub fn push_str(&mut self, s: &str) -> bool {
+        if self.truncated || self.text.len() + s.len() > MAX_OUTPUT - OUTPUT_RESERVE {
+            self.truncated = true;
+            return false;
+        }
+        self.text.push_str(s).is_ok()
+    }
+
+    /// Returns the output (with TRUNCATED at the end if some of it didn't fit)
+    pub fn finish(mut self) -> String<MAX_OUTPUT> {
+        if self.truncated {
+            if !self.text.is_empty() && !self.text.ends_with('\n') {
+                let _ = self.text.push_str("\r\n");
+            }
+            let _ = self.text.push_str(TRUNCATED);
+        }
+        self.text
+    }
+}
+
+impl core::fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        match self.push_str(s) {
+            true => Ok(()),
+            false => Err(core::fmt::Error),
+        }
+    }
+}
+
+/// Queues *text* to be sent.  If it doesn't all fit (because the host isn't reading or a
+/// capture is filling the queue up) as much as does gets queued followed by TRUNCATED.
+pub fn queue(tx: &mut TxQueue, text: &str) {
+    queue_leaving(tx, text, 0);
+}
+
+/// Queues the output of a command followed by the PROMPT.  The prompt is how the other end
+/// knows the answer is complete so it always gets queued (see queue() for the rest).
+pub fn queue_reply(tx: &mut TxQueue, text: &str) {
+    queue_leaving(tx, text, PROMPT.len());
+    for byte in PROMPT.bytes() {
+        let _ = tx.push_back(byte);
+    }
+}
+
+/// queue() but leaving room for *reserve* more bytes
+fn queue_leaving(tx: &mut TxQueue, text: &str, reserve: usize) {
+    let room = (tx.capacity() - tx.len()).saturating_sub(reserve);
+    let keep = if text.len() <= room {
+        text.len()
+    } else {
+        room.saturating_sub(TRUNCATED.len())
+    };
+    for byte in text.bytes().take(keep) {
+        let _ = tx.push_back(byte);
+    }
+    if keep < text.len() {
+        for byte in TRUNCATED.bytes() {
+            let _ = tx.push_back(byte);
+        }
+    }
+}
+
+/// Queues *bytes* to be sent if they all fit (and returns false if they don't)
+pub fn queue_all(tx: &mut TxQueue, bytes: &[u8]) -> bool {
+    if tx.capacity() - tx.len() < bytes.len() {
+        return false;
+    }
+    for byte in bytes {
+        let _ = tx.push_back(*byte);
+    }
+    true
+}
+
+/// Hands as much queued output to *write* as it will take.  *write* returns the number of
+/// bytes it accepted (0 when it's full).
+pub fn drain(tx: &mut TxQueue, mut write: impl FnMut(&[u8]) -> usize) {
+    while !tx.is_empty() {
+        let written = write(tx.as_slices().0);
+        if written == 0 {
+            break;
+        }
+        for _ in 0..written {
+            tx.pop_front();
+        }
+    }
+}
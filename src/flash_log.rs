@@ -0,0 +1,431 @@
+//! Log-structured settings storage for builds without an SPI flash chip
+//!
+//! A handful of flash sectors (the last ones of the internal flash on the STM32H743, see
+//! memory.x) get used as a ring.  Settings are stored as key/value records that only ever get
+//! appended; the newest committed record for each key wins:
+//!
+//! ```text
+//! Sector: [header: SECTOR_MAGIC, sequence] [ready: READY_MAGIC] [record] [record] ...
+//! Record: [header: RECORD_MAGIC, key, version, length, CRC] [payload...] [commit: COMMIT_MAGIC, CRC]
+//! ```
+//!
+//! Everything is written in whole `WORD`s (the STM32H7 programs 256 bits at a time and each
+//! word can only be programmed once between erases).
+//!
+//! * **Power-loss safety:** A record only counts once its commit word has been written (and the
+//!   CRC checks out) so a save that gets interrupted just leaves the previous record in charge.
+//! * **Wear leveling:** When the current sector fills up we move on to the next one in the ring
+//!   (erasing it first) and copy the newest record of every key over before marking the
+//!   new sector as ready.  Every sector gets erased the same number of times and a sector whose
+//!   copying got interrupted never becomes ready so the previous sector stays in charge.
+//! * **Only what changed:** A record only gets appended when its payload differs from the newest
+//!   record for its key, so saving after changing one setting doesn't rewrite the keymap.
+//!
+//! The ring only gets scanned once (the first time it's used); after that where the next record
+//! goes and the newest record of each key are kept track of as records get written.  Anything
+//! going wrong throws that away so the next save starts over from what's actually in flash.
+
+use crate::config_structs::Config;
+use crate::keymap;
+use crate::storage::{
+    crc32, crc32_update, Calibration, EncodedSettings, Settings, SettingsStore, StorageError,
+    MAX_PAYLOAD, SECTIONS, VERSION,
+};
+use embedded_storage::nor_flash::NorFlash;
+
+/// Unit everything gets written in
+pub const WORD: usize = 32;
+/// Marks the start of a sector that's in use ("HEKS")
+const SECTOR_MAGIC: u32 = 0x4845_4B53;
+/// Marks a sector that's finished being set up ("RDY!")
+const READY_MAGIC: u32 = 0x5244_5921;
+/// Marks the start of a record ("HEKR")
+const RECORD_MAGIC: u32 = 0x4845_4B52;
+/// Marks a record that was completely written ("DONE")
+const COMMIT_MAGIC: u32 = 0x444F_4E45;
+
+/// Keys of the records we store (the same as the sections of `EncodedSettings`)
+pub const KEY_CONFIG: u16 = 0;
+pub const KEY_CALIBRATION: u16 = 1;
+pub const KEY_KEYMAP: u16 = 2;
+pub const KEY_MACROS: u16 = 3;
+pub const KEY_SOCD: u16 = 4;
+pub const KEY_DKS: u16 = 5;
+const NUM_KEYS: usize = SECTIONS;
+
+/// Most sectors the ring can be made of
+pub const MAX_SECTORS: usize = 16;
+
+/// Offset of the first record in a sector (after the sector header and ready words)
+const FIRST_RECORD: u32 = 2 * WORD as u32;
+
+/// Where the newest copy of a record lives
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    offset: u32, // Offset of the record header
+    len: u32,    // Payload length
+    crc: u32,    // CRC of the payload
+}
+
+/// The state of the ring (found by scan() and kept up to date as records get written)
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    sector: usize,   // Sector currently being appended to
+    sequence: u32,   // Sequence number of the current sector
+    offset: u32,     // Where the next record goes in the current sector
+    latest: [Option<Location>; NUM_KEYS],
+}
+
+fn word_at(word: &[u8; WORD], index: usize) -> u32 {
+    u32::from_le_bytes([
+        word[index * 4],
+        word[index * 4 + 1],
+        word[index * 4 + 2],
+        word[index * 4 + 3],
+    ])
+}
+
+/// Builds a word out of the given u32s (the rest is left erased)
+fn make_word(values: &[u32]) -> [u8; WORD] {
+    let mut word = [0xFF; WORD];
+    for (i, value) in values.iter().enumerate() {
+        word[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    word
+}
+
+fn is_erased(word: &[u8; WORD]) -> bool {
+    word.iter().all(|&b| b == 0xFF)
+}
+
+/// Number of bytes a record with a payload of *len* bytes takes up
+fn record_size(len: u32) -> u32 {
+    let words = (len as usize).div_ceil(WORD);
+    ((1 + words + 1) * WORD) as u32
+}
+
+/// Settings storage in a ring of flash sectors
+pub struct LogStorage<F: NorFlash> {
+    flash: F,
+    base: u32,      // Offset (within *flash*) of the first sector
+    sectors: usize, // Number of sectors in the ring
+    buf: [u8; MAX_PAYLOAD],
+    scanned: bool,          // Whether *cursor* is up to date
+    cursor: Option<Cursor>, // None if nothing has been written yet
+}
+
+impl<F: NorFlash> LogStorage<F> {
+    /// Uses *sectors* erase-sized sectors of *flash* starting at *base* (which has to be on a
+    /// sector boundary).  At least two sectors are needed for wear leveling/power-loss safety
+    /// (and no more than `MAX_SECTORS`).
+    pub fn new(flash: F, base: u32, sectors: usize) -> Result<Self, StorageError> {
+        if !(2..=MAX_SECTORS).contains(&sectors)
+            || !WORD.is_multiple_of(F::WRITE_SIZE)
+            || !base.is_multiple_of(F::ERASE_SIZE as u32)
+        {
+            return Err(StorageError::Flash);
+        }
+        Ok(LogStorage {
+            flash,
+            base,
+            sectors,
+            buf: [0; MAX_PAYLOAD],
+            scanned: false,
+            cursor: None,
+        })
+    }
+
+    fn sector_start(&self, sector: usize) -> u32 {
+        self.base + (sector * F::ERASE_SIZE) as u32
+    }
+
+    fn read_word(&mut self, offset: u32) -> Result<[u8; WORD], StorageError> {
+        let mut word = [0; WORD];
+        self.flash
+            .read(offset, &mut word)
+            .map_err(|_| StorageError::Flash)?;
+        Ok(word)
+    }
+
+    fn write_word(&mut self, offset: u32, word: &[u8; WORD]) -> Result<(), StorageError> {
+        self.flash.write(offset, word).map_err(|_| StorageError::Flash)
+    }
+
+    /// Returns the sequence number of the given sector if it's ready to use
+    fn ready_sequence(&mut self, sector: usize) -> Result<Option<u32>, StorageError> {
+        let start = self.sector_start(sector);
+        let header = self.read_word(start)?;
+        let ready = self.read_word(start + WORD as u32)?;
+        if word_at(&header, 0) == SECTOR_MAGIC && word_at(&ready, 0) == READY_MAGIC {
+            Ok(Some(word_at(&header, 1)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Walks the records in the given sector, updating *latest* with every committed record.
+    /// Returns the offset where the next record can go (the end of the sector if there's
+    /// something unreadable in the way).
+    fn walk(
+        &mut self,
+        sector: usize,
+        latest: &mut [Option<Location>; NUM_KEYS],
+    ) -> Result<u32, StorageError> {
+        let start = self.sector_start(sector);
+        let end = start + F::ERASE_SIZE as u32;
+        let mut offset = start + FIRST_RECORD;
+        while offset + WORD as u32 <= end {
+            let header = self.read_word(offset)?;
+            if is_erased(&header) {
+                return Ok(offset);
+            }
+            let len = word_at(&header, 2);
+            if word_at(&header, 0) != RECORD_MAGIC || len as usize > MAX_PAYLOAD {
+                return Ok(end); // Torn header; don't write anything else here
+            }
+            let size = record_size(len);
+            if offset + size > end {
+                return Ok(end);
+            }
+            let commit = self.read_word(offset + size - WORD as u32)?;
+            let key = (word_at(&header, 1) & 0xFFFF) as usize;
+            let version = (word_at(&header, 1) >> 16) as u16;
+            let crc = word_at(&header, 3);
+            if word_at(&commit, 0) == COMMIT_MAGIC
+                && word_at(&commit, 1) == crc
+                && version == VERSION
+                && key < NUM_KEYS
+            {
+                let location = Location { offset, len, crc };
+                if self.payload_crc(location)? == crc {
+                    latest[key] = Some(location);
+                }
+            }
+            offset += size;
+        }
+        Ok(end)
+    }
+
+    /// CRC of a record's payload (read a word at a time so the buffer can hold something else)
+    fn payload_crc(&mut self, location: Location) -> Result<u32, StorageError> {
+        let mut crc = 0xFFFF_FFFF;
+        let mut remaining = location.len as usize;
+        let mut offset = location.offset + WORD as u32;
+        while remaining > 0 {
+            let word = self.read_word(offset)?;
+            let take = remaining.min(WORD);
+            crc = crc32_update(crc, &word[..take]);
+            remaining -= take;
+            offset += WORD as u32;
+        }
+        Ok(!crc)
+    }
+
+    /// Finds the current sector, where the next record goes and the newest record for each key
+    fn scan(&mut self) -> Result<Option<Cursor>, StorageError> {
+        let mut ready: heapless::Vec<(u32, usize), MAX_SECTORS> = heapless::Vec::new();
+        for sector in 0..self.sectors {
+            if let Some(sequence) = self.ready_sequence(sector)? {
+                let _ = ready.push((sequence, sector));
+            }
+        }
+        // Oldest first; the sequence numbers can wrap around but the sectors in use are never
+        // more than MAX_SECTORS apart
+        ready.sort_unstable_by(|a, b| (a.0.wrapping_sub(b.0) as i32).cmp(&0));
+        let Some(&(sequence, sector)) = ready.last() else {
+            return Ok(None);
+        };
+        // Older sectors still count; they hold the only copy of a record if a save got cut
+        // short before the current sector got its own copy
+        let mut latest = [None; NUM_KEYS];
+        let mut offset = 0;
+        for &(_, s) in ready.iter() {
+            offset = self.walk(s, &mut latest)?;
+        }
+        Ok(Some(Cursor { sector, sequence, offset, latest }))
+    }
+
+    /// The current cursor (only scanning the ring the first time around)
+    fn cursor(&mut self) -> Result<Option<Cursor>, StorageError> {
+        if !self.scanned {
+            self.cursor = self.scan()?;
+            self.scanned = true;
+        }
+        Ok(self.cursor)
+    }
+
+    /// Whether the record at *location* holds exactly *payload*
+    fn holds(&mut self, location: Location, payload: &[u8]) -> Result<bool, StorageError> {
+        if location.len as usize != payload.len() || location.crc != crc32(payload) {
+            return Ok(false);
+        }
+        let mut offset = location.offset + WORD as u32;
+        for chunk in payload.chunks(WORD) {
+            if self.read_word(offset)?[..chunk.len()] != *chunk {
+                return Ok(false);
+            }
+            offset += WORD as u32;
+        }
+        Ok(true)
+    }
+
+    /// Copies a whole record (header, payload and commit word) to *to*
+    fn copy_record(&mut self, from: Location, to: u32) -> Result<(), StorageError> {
+        for i in 0..record_size(from.len) / WORD as u32 {
+            let word = self.read_word(from.offset + i * WORD as u32)?;
+            self.write_word(to + i * WORD as u32, &word)?;
+        }
+        Ok(())
+    }
+
+    /// Erases the next sector in the ring and carries over the newest record of every key,
+    /// returning the new cursor.  The newest record of a key can be in the sector that's about
+    /// to be erased (when its newer copies turned out to be corrupt); those get held on to in
+    /// the buffer over the erase and if they don't all fit the sector doesn't get touched.
+    fn next_sector(&mut self, cursor: Option<Cursor>) -> Result<Cursor, StorageError> {
+        let (sector, sequence) = match cursor {
+            Some(c) => ((c.sector + 1) % self.sectors, c.sequence.wrapping_add(1)),
+            None => (0, 1),
+        };
+        let start = self.sector_start(sector);
+        let end = start + F::ERASE_SIZE as u32;
+        let live = cursor.map_or([None; NUM_KEYS], |c| c.latest);
+        let doomed = |location: &Location| (start..end).contains(&location.offset);
+        let mut stashed = 0;
+        for location in live.iter().flatten().filter(|l| doomed(l)) {
+            let size = record_size(location.len) as usize;
+            let Some(buf) = self.buf.get_mut(stashed..stashed + size) else {
+                return Err(StorageError::Encoding);
+            };
+            self.flash
+                .read(location.offset, buf)
+                .map_err(|_| StorageError::Flash)?;
+            stashed += size;
+        }
+        self.flash.erase(start, end).map_err(|_| StorageError::Flash)?;
+        self.write_word(start, &make_word(&[SECTOR_MAGIC, sequence]))?;
+        let mut latest = [None; NUM_KEYS];
+        let mut offset = start + FIRST_RECORD;
+        let mut unstashed = 0;
+        for (key, location) in live.iter().enumerate() {
+            let Some(location) = *location else {
+                continue;
+            };
+            let size = record_size(location.len);
+            if doomed(&location) {
+                let record = &self.buf[unstashed..unstashed + size as usize];
+                self.flash
+                    .write(offset, record)
+                    .map_err(|_| StorageError::Flash)?;
+                unstashed += size as usize;
+            } else {
+                self.copy_record(location, offset)?;
+            }
+            latest[key] = Some(Location { offset, ..location });
+            offset += size;
+        }
+        self.write_word(start + WORD as u32, &make_word(&[READY_MAGIC]))?;
+        Ok(Cursor { sector, sequence, offset, latest })
+    }
+
+    /// Appends a record for *key* (unless the newest one already holds *payload*)
+    fn write_record(&mut self, key: u16, payload: &[u8]) -> Result<(), StorageError> {
+        let len = payload.len();
+        let size = record_size(len as u32);
+        let cursor = self.cursor()?;
+        if let Some(location) = cursor.and_then(|c| c.latest[key as usize]) {
+            if self.holds(location, payload)? {
+                return Ok(());
+            }
+        }
+        let mut cursor = match cursor {
+            Some(c) if c.offset + size <= self.sector_start(c.sector) + F::ERASE_SIZE as u32 => c,
+            _ => self.next_sector(cursor)?,
+        };
+        if cursor.offset + size > self.sector_start(cursor.sector) + F::ERASE_SIZE as u32 {
+            return Err(StorageError::Encoding);
+        }
+        let crc = crc32(payload);
+        let mut offset = cursor.offset;
+        let header = make_word(&[
+            RECORD_MAGIC,
+            key as u32 | (VERSION as u32) << 16,
+            len as u32,
+            crc,
+        ]);
+        self.write_word(offset, &header)?;
+        offset += WORD as u32;
+        for chunk in 0..len.div_ceil(WORD) {
+            let mut word = [0xFF; WORD];
+            let data = &payload[chunk * WORD..len.min((chunk + 1) * WORD)];
+            word[..data.len()].copy_from_slice(data);
+            self.write_word(offset, &word)?;
+            offset += WORD as u32;
+        }
+        // Last of all; until this makes it to the flash the previous record stays in charge
+        self.write_word(offset, &make_word(&[COMMIT_MAGIC, crc]))?;
+        cursor.latest[key as usize] = Some(Location {
+            offset: cursor.offset,
+            len: len as u32,
+            crc,
+        });
+        cursor.offset += size;
+        self.cursor = Some(cursor);
+        Ok(())
+    }
+
+    /// Reads the payload of the newest record for *key* into the buffer
+    fn read_record(&mut self, cursor: &Cursor, key: u16) -> Option<usize> {
+        let location = cursor.latest[key as usize]?;
+        let len = location.len as usize;
+        self.flash
+            .read(location.offset + WORD as u32, &mut self.buf[..len])
+            .ok()?;
+        Some(len)
+    }
+}
+
+impl<F: NorFlash> SettingsStore for LogStorage<F> {
+    fn load(&mut self) -> Result<Settings, StorageError> {
+        let cursor = self.cursor()?.ok_or(StorageError::Blank)?;
+        let len = self.read_record(&cursor, KEY_CONFIG).ok_or(StorageError::Blank)?;
+        let config: Config =
+            postcard::from_bytes(&self.buf[..len]).map_err(|_| StorageError::Encoding)?;
+        // Calibration, the keymap, macros, SOCD pairs and DKS slots are optional; without them
+        // everything uses the defaults
+        let calibration = match self.read_record(&cursor, KEY_CALIBRATION) {
+            Some(len) => postcard::from_bytes(&self.buf[..len]).unwrap_or_default(),
+            None => Calibration::default(),
+        };
+        let keymap = self
+            .read_record(&cursor, KEY_KEYMAP)
+            .and_then(|len| postcard::from_bytes(&self.buf[..len]).ok())
+            .unwrap_or_else(keymap::default_codes);
+        let macros = self
+            .read_record(&cursor, KEY_MACROS)
+            .and_then(|len| postcard::from_bytes(&self.buf[..len]).ok())
+            .unwrap_or_default();
+        let socd = self
+            .read_record(&cursor, KEY_SOCD)
+            .and_then(|len| postcard::from_bytes(&self.buf[..len]).ok())
+            .unwrap_or_else(crate::socd::default_pairs);
+        let dks = self
+            .read_record(&cursor, KEY_DKS)
+            .and_then(|len| postcard::from_bytes(&self.buf[..len]).ok())
+            .unwrap_or_else(crate::dks::default_slots);
+        Ok(Settings { config, calibration, keymap, macros, socd, dks })
+    }
+
+    fn save(&mut self, settings: &EncodedSettings) -> Result<(), StorageError> {
+        if !settings.is_complete() {
+            return Err(StorageError::Encoding);
+        }
+        let result = (0..NUM_KEYS)
+            .try_for_each(|key| self.write_record(key as u16, settings.section(key)));
+        if result.is_err() {
+            // Whatever made it to the flash gets picked up by scanning it again
+            self.scanned = false;
+        }
+        result
+    }
+}
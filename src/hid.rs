@@ -0,0 +1,389 @@
+//! A small USB HID class with a configurable polling interval
+//!
+//! Keyberon's class is hard-wired to a boot keyboard polled every 10ms which throws away most of
+//! the latency advantage of scanning hall effect sensors at several kHz.  This class takes its
+//! report descriptor and polling rate from the caller so the same code can back every HID
+//! interface the keyboard exposes.
+
+use heapless::Vec;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::Result;
+
+pub const USB_CLASS_HID: u8 = 0x03;
+/// Interface subclass for devices that support the boot protocol
+pub const SUBCLASS_BOOT: u8 = 0x01;
+pub const SUBCLASS_NONE: u8 = 0x00;
+pub const PROTOCOL_KEYBOARD: u8 = 0x01;
+pub const PROTOCOL_NONE: u8 = 0x00;
+
+const DESC_TYPE_HID: u8 = 0x21;
+const DESC_TYPE_REPORT: u8 = 0x22;
+
+const REQ_GET_REPORT: u8 = 0x01;
+const REQ_GET_IDLE: u8 = 0x02;
+const REQ_GET_PROTOCOL: u8 = 0x03;
+const REQ_SET_REPORT: u8 = 0x09;
+const REQ_SET_IDLE: u8 = 0x0a;
+const REQ_SET_PROTOCOL: u8 = 0x0b;
+
+/// Largest report (in or out) any of our interfaces uses
+pub const MAX_REPORT_SIZE: usize = 64;
+
+/// Standard boot protocol keyboard report descriptor (8 byte reports: modifiers, reserved and
+/// six keycodes; 1 byte LED output report)
+#[rustfmt::skip]
+pub const BOOT_KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xE0, //   Usage Minimum (224)
+    0x29, 0xE7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute) ; Modifier byte
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant) ; Reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute) ; LED report
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant) ; LED report padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, // Logical Maximum (255)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x2A, 0xFF, 0x00, // Usage Maximum (255)
+    0x81, 0x00, //   Input (Data, Array) ; Key arrays (6 bytes)
+    0xC0,       // End Collection
+];
+
+/// NKRO keyboard report descriptor; one bit for every key so any number can be held at once
+#[rustfmt::skip]
+pub const NKRO_KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xE0, //   Usage Minimum (224)
+    0x29, 0xE7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute) ; Modifier byte
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xDF, //   Usage Maximum (223)
+    0x95, 0xE0, //   Report Count (224)
+    0x81, 0x02, //   Input (Data, Variable, Absolute) ; Key bitmap (28 bytes)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x95, 0x05, //   Report Count (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute) ; LED report
+    0x95, 0x03, //   Report Count (3)
+    0x91, 0x01, //   Output (Constant) ; LED report padding
+    0xC0,       // End Collection
+];
+
+/// Size (in bytes) of a gamepad report (see hall_core::gamepad)
+pub const GAMEPAD_REPORT_SIZE: usize = hall_core::gamepad::REPORT_SIZE;
+
+/// Gamepad report descriptor: two sticks (X/Y and Rx/Ry, centred at 0) and two triggers (Z and
+/// Rz, 0 when released), all 16 bits
+#[rustfmt::skip]
+pub const GAMEPAD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x05,       // Usage (Gamepad)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x01,       //   Usage (Pointer)
+    0xA1, 0x00,       //   Collection (Physical)
+    0x09, 0x30,       //     Usage (X)
+    0x09, 0x31,       //     Usage (Y)
+    0x09, 0x33,       //     Usage (Rx)
+    0x09, 0x34,       //     Usage (Ry)
+    0x16, 0x01, 0x80, //     Logical Minimum (-32767)
+    0x26, 0xFF, 0x7F, //     Logical Maximum (32767)
+    0x75, 0x10,       //     Report Size (16)
+    0x95, 0x04,       //     Report Count (4)
+    0x81, 0x02,       //     Input (Data, Variable, Absolute) ; Sticks
+    0xC0,             //   End Collection
+    0x09, 0x32,       //   Usage (Z)
+    0x09, 0x35,       //   Usage (Rz)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x7F, //   Logical Maximum (32767)
+    0x75, 0x10,       //   Report Size (16)
+    0x95, 0x02,       //   Report Count (2)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute) ; Triggers
+    0xC0,             // End Collection
+];
+
+/// Size (in bytes) of raw HID reports (in both directions; same as VIA uses)
+pub const RAW_HID_REPORT_SIZE: usize = 32;
+
+/// Vendor-defined report descriptor for exchanging arbitrary 32 byte packets with host software
+#[rustfmt::skip]
+pub const RAW_HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF, // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,       // Usage (0x61)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x62,       //   Usage (0x62)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20,       //   Report Count (32)
+    0x75, 0x08,       //   Report Size (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x09, 0x63,       //   Usage (0x63)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x95, 0x20,       //   Report Count (32)
+    0x75, 0x08,       //   Report Size (8)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0xC0,             // End Collection
+];
+
+/// Returns the bInterval for an interrupt endpoint that should be polled at *rate_hz*.
+/// We only run at full speed where intervals are in whole (1ms) frames so nothing gets polled
+/// more than 1000 times a second.
+pub fn interval_for(rate_hz: u32) -> u8 {
+    (1_000 / rate_hz.clamp(1, 1_000)).min(255) as u8
+}
+
+/// Which protocol the host has asked an interface to speak (see SET_PROTOCOL)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Boot,
+    Report,
+}
+
+pub struct HidClass<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: Option<EndpointOut<'a, B>>,
+    report_descriptor: &'static [u8],
+    subclass: u8,
+    protocol: u8,
+    host_protocol: Protocol,
+    idle: u8,
+    report: Vec<u8, MAX_REPORT_SIZE>, // Last report handed to write_report()
+    pending: bool,                    // The report still needs to be sent
+    output: Vec<u8, MAX_REPORT_SIZE>, // Last output report from the host (e.g. LEDs)
+    output_fresh: bool,
+}
+
+impl<'a, B: UsbBus> HidClass<'a, B> {
+    /// Creates a HID interface with an interrupt IN endpoint polled at *rate_hz*.
+    /// *report_size* is the size (in bytes) of the largest report the interface sends.
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        report_descriptor: &'static [u8],
+        subclass: u8,
+        protocol: u8,
+        report_size: u16,
+        rate_hz: u32,
+    ) -> HidClass<'a, B> {
+        HidClass {
+            interface: alloc.interface(),
+            ep_in: alloc.interrupt(report_size, interval_for(rate_hz)),
+            ep_out: None,
+            report_descriptor,
+            subclass,
+            protocol,
+            host_protocol: Protocol::Report,
+            idle: 0,
+            report: Vec::new(),
+            pending: false,
+            output: Vec::new(),
+            output_fresh: false,
+        }
+    }
+
+    /// Creates a HID interface that also has an interrupt OUT endpoint (e.g. for raw HID)
+    pub fn new_with_out(
+        alloc: &'a UsbBusAllocator<B>,
+        report_descriptor: &'static [u8],
+        report_size: u16,
+        rate_hz: u32,
+    ) -> HidClass<'a, B> {
+        let mut class = HidClass::new(
+            alloc,
+            report_descriptor,
+            SUBCLASS_NONE,
+            PROTOCOL_NONE,
+            report_size,
+            rate_hz,
+        );
+        class.ep_out = Some(alloc.interrupt(report_size, interval_for(rate_hz)));
+        class
+    }
+
+    /// Queues *report* to be sent to the host.  Reports identical to the last one are skipped.
+    /// Returns true if the report was new.
+    pub fn write_report(&mut self, report: &[u8]) -> bool {
+        if self.report.as_slice() == report {
+            return false;
+        }
+        self.report.clear();
+        let _ = self.report.extend_from_slice(&report[..report.len().min(MAX_REPORT_SIZE)]);
+        self.pending = true;
+        self.flush();
+        true
+    }
+
+    /// Queues *report* to be sent even if it's the same as the last one (for request/response
+    /// protocols like VIA where every request needs an answer)
+    pub fn send_report(&mut self, report: &[u8]) {
+        self.report.clear();
+        let _ = self.report.extend_from_slice(&report[..report.len().min(MAX_REPORT_SIZE)]);
+        self.pending = true;
+        self.flush();
+    }
+
+    /// Sends the queued report if there is one (and the endpoint will take it)
+    pub fn flush(&mut self) {
+        if self.pending && self.ep_in.write(&self.report).is_ok() {
+            self.pending = false;
+        }
+    }
+
+    /// Returns the most recent output report from the host (once)
+    pub fn read_output(&mut self) -> Option<&[u8]> {
+        if self.output_fresh {
+            self.output_fresh = false;
+            Some(&self.output)
+        } else {
+            None
+        }
+    }
+
+    /// The protocol the host asked for (hosts that only speak boot protocol, e.g. BIOSes,
+    /// will switch boot-capable interfaces to Protocol::Boot)
+    pub fn host_protocol(&self) -> Protocol {
+        self.host_protocol
+    }
+
+    fn store_output(&mut self, data: &[u8]) {
+        self.output.clear();
+        let _ = self.output.extend_from_slice(&data[..data.len().min(MAX_REPORT_SIZE)]);
+        self.output_fresh = true;
+    }
+
+    fn is_for_us(&self, req: &Request) -> bool {
+        req.recipient == Recipient::Interface && req.index == u8::from(self.interface) as u16
+    }
+
+    fn hid_descriptor(&self) -> [u8; 7] {
+        let len = self.report_descriptor.len() as u16;
+        [
+            0x11, 0x01, // bcdHID 1.11
+            0x00, // bCountryCode
+            0x01, // bNumDescriptors
+            DESC_TYPE_REPORT,
+            len as u8,
+            (len >> 8) as u8,
+        ]
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for HidClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.interface, USB_CLASS_HID, self.subclass, self.protocol)?;
+        writer.write(DESC_TYPE_HID, &self.hid_descriptor())?;
+        writer.endpoint(&self.ep_in)?;
+        if let Some(ep_out) = &self.ep_out {
+            writer.endpoint(ep_out)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.host_protocol = Protocol::Report;
+        self.idle = 0;
+        self.pending = !self.report.is_empty();
+    }
+
+    fn poll(&mut self) {
+        if let Some(ep_out) = &self.ep_out {
+            let mut buf = [0; MAX_REPORT_SIZE];
+            if let Ok(len) = ep_out.read(&mut buf) {
+                self.store_output(&buf[..len]);
+            }
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_in.address() {
+            self.flush();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_for_us(&req) {
+            return;
+        }
+        match (req.request_type, req.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => match (req.value >> 8) as u8 {
+                DESC_TYPE_REPORT => {
+                    let _ = xfer.accept_with_static(self.report_descriptor);
+                }
+                DESC_TYPE_HID => {
+                    let descriptor = self.hid_descriptor();
+                    let _ = xfer.accept_with(&descriptor);
+                }
+                _ => {}
+            },
+            (RequestType::Class, REQ_GET_REPORT) => {
+                let _ = xfer.accept_with(&self.report);
+            }
+            (RequestType::Class, REQ_GET_IDLE) => {
+                let _ = xfer.accept_with(&[self.idle]);
+            }
+            (RequestType::Class, REQ_GET_PROTOCOL) => {
+                let protocol = match self.host_protocol {
+                    Protocol::Boot => 0,
+                    Protocol::Report => 1,
+                };
+                let _ = xfer.accept_with(&[protocol]);
+            }
+            _ => {}
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_for_us(&req) || req.request_type != RequestType::Class {
+            return;
+        }
+        match req.request {
+            REQ_SET_IDLE => {
+                self.idle = (req.value >> 8) as u8;
+                let _ = xfer.accept();
+            }
+            REQ_SET_PROTOCOL => {
+                self.host_protocol = if req.value == 0 {
+                    Protocol::Boot
+                } else {
+                    Protocol::Report
+                };
+                let _ = xfer.accept();
+            }
+            REQ_SET_REPORT => {
+                self.store_output(xfer.data());
+                let _ = xfer.accept();
+            }
+            _ => {}
+        }
+    }
+}
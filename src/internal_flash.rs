@@ -0,0 +1,54 @@
+//! The STM32H743's own flash (bank 2) as a place to keep the settings (see flash_log.rs)
+//!
+//! The HAL only hands out an unlocked (writable) bank for as long as it's borrowed so this
+//! wrapper unlocks it for each erase/write and keeps it locked the rest of the time.
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use stm32h7xx_hal::flash::{LockedFlashBank, UnlockedFlashBank};
+
+/// Number of (128K) sectors at the end of bank 2 set aside for the settings (see memory.x)
+pub const SETTINGS_SECTORS: usize = 2;
+
+pub struct InternalFlash {
+    bank: LockedFlashBank,
+}
+
+impl InternalFlash {
+    pub fn new(bank: LockedFlashBank) -> InternalFlash {
+        InternalFlash { bank }
+    }
+
+    /// Offset (within the bank) of the first sector reserved for the settings
+    pub fn settings_base(&self) -> u32 {
+        (self.bank.len() - SETTINGS_SECTORS * Self::ERASE_SIZE) as u32
+    }
+}
+
+impl ErrorType for InternalFlash {
+    type Error = <LockedFlashBank as ErrorType>::Error;
+}
+
+impl ReadNorFlash for InternalFlash {
+    const READ_SIZE: usize = <LockedFlashBank as ReadNorFlash>::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.bank.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.bank.len()
+    }
+}
+
+impl NorFlash for InternalFlash {
+    const WRITE_SIZE: usize = <UnlockedFlashBank<'static> as NorFlash>::WRITE_SIZE;
+    const ERASE_SIZE: usize = <UnlockedFlashBank<'static> as NorFlash>::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.bank.unlocked().erase(from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.bank.unlocked().write(offset, bytes)
+    }
+}
@@ -1,125 +1,203 @@
 #![no_main]
 #![no_std]
 
-mod multiplexers;
-mod layers;
-mod layout;
-mod config_structs;
+mod scanner;
+mod storage;
+mod console;
+mod flash_log;
+mod hid;
+mod internal_flash;
 mod aliases;
-mod userconfig;
+mod via;
 
+use hall_core::{config, config_structs, dks, filter, keymap, layout, multiplexers, report, socd};
+use hall_core::{thresholds, userconfig};
+use hall_core::key_code::KeyCode;
+
+use core::fmt::Write;
 use core::mem::MaybeUninit;
 
 // set the panic handler
 use panic_halt as _;
 
-use keyberon::debounce::Debouncer;
-use keyberon::key_code::KbHidReport;
-use keyberon::layout::Layout;
-use rtic::app;
-use stm32h7xx_hal::gpio::{self, EPin, Input, Output, PushPull};
 use stm32h7xx_hal::prelude::*;
 use stm32h7xx_hal::usb_hs::{UsbBus, USB1};
-use usb_device::bus::UsbBusAllocator;
 
 type UsbDevice = usb_device::device::UsbDevice<'static, UsbBus<USB1>>;
 
+/// How often TIM3 fires the tick task (we scan and build a fresh report for every USB poll)
+const TICK_RATE_HZ: u32 = userconfig::POLLING_RATE;
+// USB runs at full speed (see hid::interval_for) so the host won't poll more than once a frame
+const _: () = assert!(
+    userconfig::POLLING_RATE > 0 && userconfig::POLLING_RATE <= 1_000,
+    "POLLING_RATE has to be between 1 and 1000"
+);
+/// Where the STM32H743's built-in (DFU) bootloader lives (see AN2606)
+const SYSTEM_BOOTLOADER: u32 = 0x1FF0_9800;
+/// How long to wait before rebooting into the bootloader so the host gets whatever asked for it
+/// answered (and any keys released)
+const BOOTLOADER_DELAY_TICKS: u32 = TICK_RATE_HZ / 10;
+
 static mut EP_MEMORY: MaybeUninit<[u32; 1024]> = MaybeUninit::uninit();
+// The actions the layout uses (built from the keymap in init; see keymap.rs)
+static mut LAYOUT_ACTIONS: MaybeUninit<keymap::Actions> = MaybeUninit::uninit();
+
+// DMA1 can't get at the DTCM (where RAM is) so the ADC results have to live in AXI SRAM
+#[link_section = ".axisram.adc"]
+static mut ADC1_BUFFER: MaybeUninit<[u16; 3]> = MaybeUninit::uninit();
+#[link_section = ".axisram.adc"]
+static mut ADC2_BUFFER: MaybeUninit<[u16; 2]> = MaybeUninit::uninit();
 
-#[rtic::app(device = stm32h7xx_hal::stm32, peripherals = true)]
+#[rtic::app(device = stm32h7xx_hal::stm32, peripherals = true, dispatchers = [EXTI0])]
 mod app {
     use analog_multiplexer::{DummyPin, Multiplexer};
-    use stm32h7xx_hal::{adc::{Adc, AdcSampleTime, Resolution}, delay::Delay, pac::{Peripherals, PWR, SYSCFG}, rcc::{rec::{AdcClkSel, UsbClkSel}, CoreClocks}, time::{Hertz, MegaHertz, MicroSeconds}, timer::{Event, Timer}, usb_hs::{Usb1BusType, UsbBus, USB1}};
+    use stm32h7xx_hal::{adc::{self, AdcSampleTime, Resolution}, delay::Delay, dma::{dma::{DmaConfig, StreamsTuple}, Transfer}, pac::{Peripherals, PWR}, spi, rcc::rec::{AdcClkSel, UsbClkSel}, time::Hertz, timer::Event, usb_hs::{Usb1BusType, UsbBus, USB1}};
     use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
     use usbd_serial::SerialPort;
 
-    use self::{aliases::SelectPins, layers::LAYERS};
-
     use super::*;
+    use storage::SettingsStore;
 
     #[shared]
     struct Shared {
+        config: config_structs::Config,
         usb_dev: UsbDevice,
-        usb_serial: SerialPort<'static, UsbBus<USB1>>
+        usb_keyboard: hid::HidClass<'static, UsbBus<USB1>>,
+        usb_nkro: hid::HidClass<'static, UsbBus<USB1>>,
+        usb_raw_hid: hid::HidClass<'static, UsbBus<USB1>>,
+        usb_gamepad: hid::HidClass<'static, UsbBus<USB1>>,
+        report_mode: report::ReportMode,
+        usb_serial: SerialPort<'static, UsbBus<USB1>>,
+        thresholds: thresholds::ThresholdTable,
+        ch_states: [multiplexers::ChannelStates; userconfig::NUM_MULTIPLEXERS],
+        scanner: scanner::Scanner,
+        console_tx: console::TxQueue,
+        pending_layer: Option<usize>, // Default layer change requested over the console
+        keymap: keymap::Keymap,
+        capture: hall_core::trace::Capture, // Raw readings being streamed out (see trace.rs)
+        calibration: hall_core::calibrate::TravelCalibration,
+        socd: socd::Socd, // Opposite keys that cancel out (between scan() and the layout)
+        dks: dks::Dks,    // Keys that do different things at different depths
+        mod_taps: hall_core::modtap::ModTaps, // Keys that are modifiers when pressed further
+        settings_out: storage::EncodedSettings, // Serialized by save_settings() for idle() to write
+        save_pending: bool,
+        bootloader_ticks: Option<u32>, // Ticks left until rebooting into the bootloader
     }
 
     #[local]
     struct Local {
-        debouncer: Debouncer<[[bool; 13]; 4]>,
-        layout: Layout<16, 5, 7, ()>,
+        layout: keymap::Layout,
         //bus: Option<Usb1BusType>,
         //ep_mem: [u32; 1024],
-        multiplexer: aliases::Multiplex,
+        recalibration_ticks: u32,
+        bindings: keymap::Bindings, // What keys do for the gamepad/DKS (the layout can't tell us)
+        console_line: console::LineBuffer,
+        storage: Option<aliases::SettingsStorage>, // None when there's nowhere to save settings
     }
 
     // todo power check?
     #[init]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
-        let keyboard_config = config_structs::KeyboardConfig {
-            north_down: userconfig::NORTH_DOWN,
-            actuation_threshold: userconfig::ACTUATION_THRESHOLD,
-            release_threshold: userconfig::RELEASE_THRESHOLD,
-            ignore_below: userconfig::IGNORE_BELOW,
-            recalibration_rate: userconfig::RECALIBRATION_RATE,
-            num_multiplexers: userconfig::NUM_MULTIPLEXERS,
-            max_channels: userconfig::MAX_CHANNELS,
-            usb_vid: userconfig::USB_VID,
-            usb_pid: userconfig::USB_PID,
-        };
-
-        let dp = Peripherals::take().expect("Cannot take peripherals");
-        //let pwr = ctx.device.PWR.constrain();
-        let pwr = dp.PWR.constrain();
+        let pwr = ctx.device.PWR.constrain();
         let pwrcfg = pwr.vos1().freeze();
         let rcc = ctx.device.RCC.constrain();
-        let ccdr = rcc.sys_ck(Hertz::from_raw(480_000_000)).freeze(pwrcfg, &ctx.device.SYSCFG);
-        let clocks = rcc.sysclk(Hertz::from_raw(480_000_000)).use_hse(Hertz::from_raw(25_000)).freeze(pwrcfg, &ctx.device.SYSCFG);
-        let mut ccdr = rcc.sys_ck(80.MHz()).freeze(pwrcfg, &ctx.device.SYSCFG);
+        // PLL2 P feeds the ADCs
+        let mut ccdr = rcc
+            .sys_ck(400.MHz())
+            .pll2_p_ck(100.MHz())
+            .freeze(pwrcfg, &ctx.device.SYSCFG);
+
+        // 48MHz CLOCK
+        let _ = ccdr.clocks.hsi48_ck().expect("HSI48 must run");
+        ccdr.peripheral.kernel_usb_clk_mux(UsbClkSel::Hsi48);
+        ccdr.peripheral.kernel_adc_clk_mux(AdcClkSel::Pll2P);
 
-        // 2khz
         let mut timer3 = ctx.device.TIM3.timer(
-            MicroSeconds::from_ticks(500).into_rate(),
+            Hertz::from_raw(TICK_RATE_HZ),
             ccdr.peripheral.TIM3,
             &ccdr.clocks,
         );
         timer3.listen(Event::TimeOut);
 
-        /*
-        // Schedule a task that occasionally checks if the default mV values need to be adjusted
-        ctx.schedule
-            .update_defaults(ctx.start+ config.keyboard.recalibration_rate.cycles())
-            .unwrap();
-
-        ctx.schedule
-            .tick_display(ctx.start + DISPLAY_TICK_RATE.cycles())
-            .unwrap();
-        */
-
-
-
-        // internal USB voltage regulator in ON mode
+        // internal USB voltage regulator in ON mode (PWR belongs to pwrcfg now but its supply
+        // bits can only be written once so this has to come after freeze())
         unsafe {
-            let pwr = *PWR::ptr();
+            let pwr = &*PWR::ptr();
             pwr.cr3.modify(|_, w| w.usbregen().set_bit());
             while pwr.cr3.read().usb33rdy().bit_is_clear() {}
         }
 
-        // 48MHz CLOCK
-        let _ = ccdr.clocks.hsi48_ck().expect("HSI48 must run");
-        ccdr.peripheral.kernel_usb_clk_mux(UsbClkSel::Hsi48);
-
         let gpioa = ctx.device.GPIOA.split(ccdr.peripheral.GPIOA);
         let gpiob = ctx.device.GPIOB.split(ccdr.peripheral.GPIOB);
         let gpioc = ctx.device.GPIOC.split(ccdr.peripheral.GPIOC);
+        let gpioe = ctx.device.GPIOE.split(ccdr.peripheral.GPIOE);
 
-        let mut led = gpioc.pc13.into_push_pull_output();
-        led.set_low();
+        // Lit for as long as the keyboard is powered (PC13 is the first mux select line)
+        let mut led: aliases::Led = gpioe.pe3.into_push_pull_output();
+        led.set_high();
 
-        // rm0433
-        let (pin_dm, pin_dp) = {
-            let gpiob = ctx.device.GPIOB.split(ccdr.peripheral.GPIOB);
-            (gpiob.pb14.into_alternate(), gpiob.pb15.into_alternate())
+        // Settings saved over the serial console live on the SPI flash (or the end of the
+        // internal flash on builds without one); anything missing or invalid means starting
+        // over from the defaults in userconfig.rs
+        let external = if userconfig::SPI_FLASH {
+            let flash_spi: aliases::FlashSpi = ctx.device.SPI1.spi(
+                (
+                    gpioa.pa5.into_alternate(),
+                    gpioa.pa6.into_alternate(),
+                    gpioa.pa7.into_alternate(),
+                ),
+                spi::MODE_0,
+                20.MHz(),
+                ccdr.peripheral.SPI1,
+                &ccdr.clocks,
+            );
+            let flash_cs: aliases::FlashCs = gpiob.pb0.into_push_pull_output();
+            storage::SpiStorage::new(flash_spi, flash_cs).ok()
+        } else {
+            None
+        };
+        let mut storage: Option<aliases::SettingsStorage> = match external {
+            Some(external) => Some(storage::Backend::External(external)),
+            None => {
+                let (_, bank2) = ctx.device.FLASH.split();
+                bank2
+                    .map(internal_flash::InternalFlash::new)
+                    .and_then(|flash| {
+                        let base = flash.settings_base();
+                        let sectors = internal_flash::SETTINGS_SECTORS;
+                        flash_log::LogStorage::new(flash, base, sectors).ok()
+                    })
+                    .map(storage::Backend::Internal)
+            }
+        };
+        let saved = storage.as_mut().and_then(|s| s.load().ok());
+        let (config, calibration, keymap, socd_pairs, dks_slots) = match saved {
+            Some(settings) => (
+                settings.config,
+                Some(settings.calibration),
+                keymap::Keymap::new(settings.keymap, settings.macros),
+                settings.socd,
+                settings.dks,
+            ),
+            None => (
+                config::from_userconfig(),
+                None,
+                keymap::Keymap::default(),
+                socd::default_pairs(),
+                dks::default_slots(),
+            ),
         };
+        let layout_actions =
+            unsafe { (*core::ptr::addr_of_mut!(LAYOUT_ACTIONS)).write(keymap.actions()) };
+        let bindings = keymap.bindings();
+
+        let mut thresholds = thresholds::ThresholdTable::new(
+            config.keyboard.actuation_threshold,
+            config.keyboard.release_threshold,
+        );
+
+        // rm0433
+        let (pin_dm, pin_dp) = (gpiob.pb14.into_alternate(), gpiob.pb15.into_alternate());
 
         let usb = USB1::new(
             ctx.device.OTG1_HS_GLOBAL,
@@ -141,27 +219,61 @@ mod app {
         }
         let usb_bus = cortex_m::singleton!(
             : usb_device::class_prelude::UsbBusAllocator<Usb1BusType> =
-                UsbBus::new(usb, unsafe { EP_MEMORY.assume_init_mut() })
+                UsbBus::new(usb, unsafe { (*core::ptr::addr_of_mut!(EP_MEMORY)).assume_init_mut() })
         )
         .unwrap();
-        let usb_keyboard = keyberon::new_class(usb_bus, ());
-        let usb_dev = keyberon::new_device(usb_bus);
+        // Composite device; the boot keyboard goes first since that's all some BIOSes look at
+        let usb_keyboard = hid::HidClass::new(
+            usb_bus,
+            hid::BOOT_KEYBOARD_REPORT_DESCRIPTOR,
+            hid::SUBCLASS_BOOT,
+            hid::PROTOCOL_KEYBOARD,
+            8,
+            config.keyboard.polling_rate,
+        );
+        let usb_nkro = hid::HidClass::new(
+            usb_bus,
+            hid::NKRO_KEYBOARD_REPORT_DESCRIPTOR,
+            hid::SUBCLASS_NONE,
+            hid::PROTOCOL_NONE,
+            report::NKRO_REPORT_SIZE as u16,
+            config.keyboard.polling_rate,
+        );
+        let usb_raw_hid = hid::HidClass::new_with_out(
+            usb_bus,
+            hid::RAW_HID_REPORT_DESCRIPTOR,
+            hid::RAW_HID_REPORT_SIZE as u16,
+            1_000,
+        );
+        let usb_gamepad = hid::HidClass::new(
+            usb_bus,
+            hid::GAMEPAD_REPORT_DESCRIPTOR,
+            hid::SUBCLASS_NONE,
+            hid::PROTOCOL_NONE,
+            hid::GAMEPAD_REPORT_SIZE as u16,
+            config.keyboard.polling_rate,
+        );
         let usb_serial = usbd_serial::SerialPort::new(usb_bus);
-        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(userconfig::USB_VID, userconfig::USB_PID))
+        // The CDC class brings its own interface association descriptor (IAD) for its two
+        // interfaces so the device has to announce itself as an IAD composite
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(config.keyboard.usb_vid, config.keyboard.usb_pid))
             .strings(&[usb_device::device::StringDescriptors::default()
-                .manufacturer("SeanCo")
-                .product("ShitBoardv1")
-                .serial_number("0")])
+                .manufacturer(userconfig::USB_MANUFACTURER)
+                .product(userconfig::USB_PRODUCT)
+                .serial_number(userconfig::USB_SERIAL_NUMBER)])
+            .unwrap()
+            .max_packet_size_0(64)
             .unwrap()
-            .device_class(usbd_serial::USB_CLASS_CDC)
+            .composite_with_iads()
             .build();
 
-        let mut pa0 = gpioa.pa0.into_analog();
-        let mut pa1 = gpioa.pa1.into_analog();
-        let mut pa2 = gpioa.pa2.into_analog();
-        let mut pa3 = gpioa.pa3.into_analog();
-        let mut pa4 = gpioa.pa4.into_analog();
-        let analog_pins = (pa0, pa1, pa2, pa3, pa4);
+        let mut analog_pins: aliases::AnalogPins = (
+            gpioa.pa0.into_analog(),
+            gpioa.pa1.into_analog(),
+            gpioa.pa2.into_analog(),
+            gpioa.pa3.into_analog(),
+            gpioa.pa4.into_analog(),
+        );
 
         let s0 = gpioc.pc13.into_push_pull_output();
         let s1 = gpiob.pb8.into_push_pull_output();
@@ -169,108 +281,991 @@ mod app {
         let s3 = gpioa.pa15.into_push_pull_output();
         let en = DummyPin; // Just run it to GND to keep always-enabled
         let select_pins = (s0, s1, s2, s3, en);
-        let mut multiplexer = Multiplexer::new(select_pins);
+        let mut multiplexer = scanner::SelectLines(Multiplexer::new(select_pins));
 
-        let mut ch_states0: multiplexers::ChannelStates = Default::default();
-        let mut ch_states1: multiplexers::ChannelStates = Default::default();
-        let mut ch_states2: multiplexers::ChannelStates = Default::default();
-        let mut ch_states3: multiplexers::ChannelStates = Default::default();
-        let mut ch_states4: multiplexers::ChannelStates = Default::default();
+        let mut ch_states: [multiplexers::ChannelStates; userconfig::NUM_MULTIPLEXERS] =
+            Default::default();
+        let channel_filter = filter::Filter::from_config(
+            config.keyboard.filter,
+            config.keyboard.filter_window,
+            config.keyboard.filter_alpha,
+        );
+        for states in ch_states.iter_mut() {
+            for chan in 0..userconfig::MAX_CHANNELS {
+                states.update_filter_by_index(chan, channel_filter);
+            }
+        }
 
-        ccdr.peripheral.kernel_adc_clk_mux(AdcClkSel::Pll2P);
-        let cp = cortex_m::Peripherals::take().unwrap();
-        let mut delay = Delay::new(cp.SYST, ccdr.clocks);
+        let mut delay = Delay::new(ctx.core.SYST, ccdr.clocks);
 
         // TODO double check clock settings
-        let mut adc = Adc::adc1(ctx.device.ADC1, Hertz::from_raw(50_000_000), &mut delay, ccdr.peripheral.ADC12, &ccdr.clocks).enable();
+        let (adc1, adc2) = adc::adc12(ctx.device.ADC1, ctx.device.ADC2, Hertz::from_raw(50_000_000), &mut delay, ccdr.peripheral.ADC12, &ccdr.clocks);
+        let mut adc = adc1.enable();
+        let mut adc2 = adc2.enable();
         adc.set_resolution(Resolution::SixteenBit);
+        adc2.set_resolution(Resolution::SixteenBit);
         let sample_time = AdcSampleTime::T_8;
         adc.set_sample_time(sample_time);
+        adc2.set_sample_time(sample_time);
 
         // Read in the initial millivolt values for all analog channels so we have
         // a default/resting state to evaluate against.  We'll set new defaults later
         // after we've captured a few values (controlled by DEFAULT_WAIT_MS).
-        for channel in 0..16 {
-            // This sets the channel on all multiplexers simultaneously
-            // (since they're all connected to the same S0,S1,S2,S3 pins).
-            multiplexer.set_channel(channel);
-
-            // Read the analog value of each channel/key and store it in our ChannelStates struct...
-            for multi in 0..userconfig::NUM_MULTIPLEXERS {
-                let millivolts = match multi {
-                    0 => {
-                        adc.start_conversion(&mut analog_pins.0);
-                        let value = adc.read(&mut analog_pins.0).unwrap_or(0);
-                        value / 4
-                    },
-                    1 => {
-                        adc.start_conversion(&mut analog_pins.1);
-                        adc.read(&mut analog_pins.1).unwrap_or(0) / 4
-                    },
-                    2 => {
-                        adc.start_conversion(&mut analog_pins.2);
-                        adc.read(&mut analog_pins.2).unwrap_or(0) / 4
-                    },
-                    3 => {
-                        adc.start_conversion(&mut analog_pins.3);
-                        adc.read(&mut analog_pins.3).unwrap_or(0) / 4
-                    },
-                    4 => {
-                        adc.start_conversion(&mut analog_pins.4);
-                        adc.read(&mut analog_pins.4).unwrap_or(0) / 4
-                    },
-                    _ => unreachable!(), // Riskeyboard 70 only has 5 multiplexers
-                };
-                match multi {
-                    0 => ch_states0[channel as usize].update_default(millivolts),
-                    1 => ch_states1[channel as usize].update_default(millivolts),
-                    2 => ch_states2[channel as usize].update_default(millivolts),
-                    3 => ch_states3[channel as usize].update_default(millivolts),
-                    4 => ch_states4[channel as usize].update_default(millivolts),
-                    _ => {}
-                };
+        let mut frame: scanner::Frame = [[0; userconfig::MAX_CHANNELS]; userconfig::NUM_MULTIPLEXERS];
+        hall_core::sensors::sweep(
+            &mut multiplexer,
+            &mut scanner::BlockingInputs { adc: &mut adc, pins: &mut analog_pins },
+            &mut frame,
+        );
+        for (multi, readings) in frame.iter().enumerate() {
+            for (chan, millivolts) in readings.iter().enumerate() {
+                ch_states[multi].update_default_by_index(chan, *millivolts);
             }
         }
 
+        // From here on the ADCs get driven by the DMA (see scanner.rs)
+        let streams = StreamsTuple::new(ctx.device.DMA1, ccdr.peripheral.DMA1);
+        let dma_config = DmaConfig::default()
+            .memory_increment(true)
+            .transfer_complete_interrupt(true);
+        let (adc1_buffer, adc2_buffer) = unsafe {
+            (
+                (*core::ptr::addr_of_mut!(ADC1_BUFFER)).write([0; 3]),
+                (*core::ptr::addr_of_mut!(ADC2_BUFFER)).write([0; 2]),
+            )
+        };
+        let mut adc1_transfer: aliases::Adc1Transfer =
+            Transfer::init(streams.0, adc, adc1_buffer, None, dma_config);
+        let mut adc2_transfer: aliases::Adc2Transfer = Transfer::init(
+            streams.1,
+            adc2,
+            adc2_buffer,
+            None,
+            DmaConfig::default().memory_increment(true),
+        );
+        // Arm both streams; conversions only start once the scanner triggers the ADCs
+        adc1_transfer.start(|_| {});
+        adc2_transfer.start(|_| {});
+        let scanner = scanner::Scanner::new(adc1_transfer, adc2_transfer, multiplexer);
+
+        match calibration {
+            Some(calibration) => calibration.apply(&mut thresholds, &mut ch_states),
+            None => {
+                thresholds.load(userconfig::KEY_THRESHOLDS);
+                // Switch the configured keys over to rapid trigger
+                for &(multi, chan) in userconfig::RAPID_TRIGGER_KEYS {
+                    ch_states[multi]
+                        .update_mode_by_index(chan, multiplexers::TriggerMode::RapidTrigger);
+                }
+            }
+        }
+        let socd = socd::Socd::new(socd_pairs, &ch_states);
+
         (
-            Shared { usb_dev, usb_serial },
+            Shared {
+                report_mode: report::ReportMode::from_config(config.keyboard.report_mode),
+                config,
+                usb_dev,
+                usb_keyboard,
+                usb_nkro,
+                usb_raw_hid,
+                usb_gamepad,
+                usb_serial,
+                thresholds,
+                ch_states,
+                scanner,
+                console_tx: console::TxQueue::new(),
+                pending_layer: None,
+                keymap,
+                capture: Default::default(),
+                calibration: Default::default(),
+                socd,
+                dks: dks::Dks::new(dks_slots),
+                mod_taps: Default::default(),
+                settings_out: storage::EncodedSettings::new(),
+                save_pending: false,
+                bootloader_ticks: None,
+            },
             Local {
-                debouncer: Debouncer::new([[false; 13]; 4], [[false; 13]; 4], 5),
-                layout: Layout::new(&LAYERS),
-                multiplexer,
+                layout: layout::Layout::new(layout_actions, TICK_RATE_HZ),
+                recalibration_ticks: 0,
+                bindings,
+                console_line: console::LineBuffer::default(),
+                storage,
             },
             init::Monotonics(),
         )
     }
 
-    #[task(binds = OTG_FS, priority = 2, shared = [usb_dev, usb_serial])]
+    #[task(
+        binds = OTG_HS,
+        priority = 2,
+        local = [console_line],
+        shared = [
+            usb_dev,
+            usb_keyboard,
+            usb_nkro,
+            usb_raw_hid,
+            usb_gamepad,
+            usb_serial,
+            console_tx
+        ]
+    )]
     fn usb_tx(c: usb_tx::Context) {
-        c.shared.usb_serial.lock(|usb_dev, usbd_serial| {
-            if usb_dev.poll(&mut [usb_serial]) {
-                usb_class.poll();
+        let console_line = c.local.console_line;
+        (
+            c.shared.usb_dev,
+            c.shared.usb_keyboard,
+            c.shared.usb_nkro,
+            c.shared.usb_raw_hid,
+            c.shared.usb_gamepad,
+            c.shared.usb_serial,
+            c.shared.console_tx,
+        )
+            .lock(|usb_dev, usb_keyboard, usb_nkro, usb_raw_hid, usb_gamepad, usb_serial, console_tx| {
+                usb_dev.poll(&mut [usb_keyboard, usb_nkro, usb_raw_hid, usb_gamepad, usb_serial]);
+                // Hand finished lines off to the console and send along any pending output
+                let mut buf = [0u8; 64];
+                if let Ok(count) = usb_serial.read(&mut buf) {
+                    for &byte in &buf[..count] {
+                        if let Some(line) = console_line.feed(byte) {
+                            let _ = run_command::spawn(line);
+                        }
+                    }
+                }
+                console::drain(console_tx, |bytes| usb_serial.write(bytes).unwrap_or(0));
+                if let Some(request) = usb_raw_hid.read_output() {
+                    let mut report = [0; hid::RAW_HID_REPORT_SIZE];
+                    let len = request.len().min(report.len());
+                    report[..len].copy_from_slice(&request[..len]);
+                    let _ = via_command::spawn(report);
+                }
+            })
+    }
+
+    /// Answers a request from the VIA configurator (see via.rs)
+    #[task(
+        priority = 1,
+        capacity = 4,
+        shared = [config, ch_states, thresholds, report_mode, keymap, usb_raw_hid, capture, bootloader_ticks]
+    )]
+    fn via_command(mut ctx: via_command::Context, mut report: via::Report) {
+        let effects = (
+            &mut ctx.shared.config,
+            &mut ctx.shared.ch_states,
+            &mut ctx.shared.thresholds,
+            &mut ctx.shared.report_mode,
+            &mut ctx.shared.keymap,
+        )
+            .lock(|config, ch_states, thresholds, report_mode, keymap| {
+                let effects = via::handle(
+                    &mut report,
+                    via::Context {
+                        keymap,
+                        config,
+                        ch_states,
+                    },
+                );
+                if let Some(name) = effects.changed {
+                    apply_config(config, thresholds, ch_states, report_mode, name);
+                }
+                effects
+            });
+        if effects.changed.is_some() {
+            ctx.shared.capture.lock(|capture| capture.request_snapshot());
+        }
+        ctx.shared.usb_raw_hid.lock(|hid| hid.send_report(&report));
+        if effects.save {
+            let _ = save_settings::spawn();
+        }
+        if effects.bootloader {
+            ctx.shared.bootloader_ticks.lock(|ticks| *ticks = Some(BOOTLOADER_DELAY_TICKS));
+        }
+    }
+
+    /// Runs a line typed into the serial console
+    #[task(
+        priority = 1,
+        capacity = 4,
+        shared = [
+            config,
+            ch_states,
+            thresholds,
+            report_mode,
+            pending_layer,
+            keymap,
+            console_tx,
+            usb_serial,
+            capture,
+            calibration,
+            socd,
+            dks,
+            bootloader_ticks
+        ]
+    )]
+    fn run_command(mut ctx: run_command::Context, line: heapless::String<{ console::MAX_LINE }>) {
+        use console::Command;
+        let mut out = console::Output::new();
+        let mut bootloader = false;
+        let mut changed = false;
+        match console::parse(&line) {
+            Ok(Command::Help) => {
+                let _ = out.push_str(console::HELP);
+            }
+            Ok(Command::States(mux)) => ctx.shared.ch_states.lock(|ch_states| {
+                for (multi, states) in ch_states.iter().enumerate() {
+                    if mux.is_none_or(|m| m == multi) {
+                        let _ = write!(out, "Multiplexer {}:\r\n{}\r\n", multi, states);
+                    }
+                }
+            }),
+            Ok(Command::Values(mux)) => ctx.shared.ch_states.lock(|ch_states| {
+                for (multi, states) in ch_states.iter().enumerate() {
+                    if mux.is_none_or(|m| m == multi) {
+                        let _ = write!(out, "{}", multi);
+                        for state in states.states.iter().take(scanner::MUX_CHANNELS as usize) {
+                            let _ = write!(out, " {}", state.value);
+                        }
+                        let _ = out.push_str("\r\n");
+                    }
+                }
+            }),
+            Ok(Command::Travel(mux)) => {
+                (&mut ctx.shared.config, &mut ctx.shared.ch_states).lock(|config, ch_states| {
+                    let travel = hall_core::travel::Travel::from_config(&config.keyboard);
+                    for (multi, states) in ch_states.iter().enumerate() {
+                        if mux.is_none_or(|m| m == multi) {
+                            let _ = write!(out, "{}", multi);
+                            for state in states.states.iter().take(scanner::MUX_CHANNELS as usize) {
+                                let _ = write!(out, " {}", state.travel(&travel));
+                            }
+                            let _ = out.push_str("\r\n");
+                        }
+                    }
+                })
+            }
+            Ok(Command::Get(name)) => ctx.shared.config.lock(|config| {
+                // "get" lists every section, "get <section>" lists one and "get <section.field>"
+                // shows a single field
+                for section in config_structs::Config::SECTIONS {
+                    for field in config_structs::Config::field_names(section) {
+                        let mut full: heapless::String<{ console::MAX_ARG }> = heapless::String::new();
+                        let _ = write!(full, "{}.{}", section, field);
+                        if name.is_empty() || name == *section || name == full {
+                            if let Some((_, _, field_type, value)) = config.get(&full) {
+                                let _ = write!(out, "{} = {} ({})\r\n", full, value.as_str(), field_type);
+                            }
+                        }
+                    }
+                }
+                if out.is_empty() {
+                    let _ = out.push_str("Unknown section/field\r\n");
+                }
+            }),
+            Ok(Command::Set(name, value)) => {
+                (
+                    &mut ctx.shared.config,
+                    &mut ctx.shared.thresholds,
+                    &mut ctx.shared.ch_states,
+                    &mut ctx.shared.report_mode,
+                )
+                    .lock(|config, thresholds, ch_states, report_mode| {
+                        match config.set(&name, &value) {
+                            Ok(()) => {
+                                apply_config(config, thresholds, ch_states, report_mode, &name);
+                                changed = true;
+                                let _ = write!(out, "{} = {}\r\n", name, value);
+                            }
+                            Err(config_structs::ConfigError::UnknownField) => {
+                                let _ = out.push_str("Unknown field\r\n");
+                            }
+                            Err(config_structs::ConfigError::InvalidValue) => {
+                                let _ = out.push_str("Invalid value\r\n");
+                            }
+                            Err(config_structs::ConfigError::OutOfRange) => {
+                                let (min, max) = config::limits(&name).unwrap_or_default();
+                                let _ = write!(out, "Out of range ({} to {})\r\n", min, max);
+                            }
+                        }
+                    })
+            }
+            Ok(Command::Recalibrate) => {
+                ctx.shared.ch_states.lock(|ch_states| {
+                    for states in ch_states.iter_mut() {
+                        states.reset_defaults();
+                    }
+                });
+                let _ = out.push_str("Recalibrated\r\n");
+            }
+            Ok(Command::Layer(layer)) => {
+                ctx.shared.pending_layer.lock(|pending| *pending = Some(layer));
+                let _ = write!(out, "Default layer: {}\r\n", layer);
+            }
+            Ok(Command::Key(layer, mux, chan, code)) => ctx.shared.keymap.lock(|keymap| {
+                match code {
+                    Some(code) if keymap.set(layer, mux, chan, code) => {
+                        changed = true;
+                        let _ = write!(out, "Key {} {} {} = 0x{:04X}\r\n", layer, mux, chan, code);
+                    }
+                    Some(_) => {
+                        let _ = out.push_str("Invalid key or code\r\n");
+                    }
+                    None => match keymap.get(layer, mux, chan) {
+                        Some(code) => {
+                            let _ = write!(out, "Key {} {} {} = 0x{:04X}\r\n", layer, mux, chan, code);
+                        }
+                        None => {
+                            let _ = out.push_str("Invalid key\r\n");
+                        }
+                    },
+                }
+            }),
+            Ok(Command::Threshold(mux, chan, change)) => ctx.shared.thresholds.lock(|thresholds| {
+                if let Some(change) = change {
+                    changed = thresholds.set(mux, chan, change);
+                }
+                match thresholds.get(mux, chan) {
+                    Some(_) if change.is_none() || changed => {
+                        write_threshold(mux, chan, thresholds, &mut out)
+                    }
+                    _ => {
+                        let _ = out.push_str("Invalid key\r\n");
+                    }
+                }
+            }),
+            Ok(Command::Trigger(mux, chan, mode)) => ctx.shared.ch_states.lock(|ch_states| {
+                match ch_states.get_mut(mux) {
+                    Some(states) if chan < userconfig::MAX_CHANNELS => {
+                        if let Some(mode) = mode {
+                            // Saved along with the calibration (see storage::Calibration)
+                            states.update_mode_by_index(chan, mode);
+                            changed = true;
+                        }
+                        let _ = write!(
+                            out,
+                            "Trigger {} {} = {}\r\n",
+                            mux,
+                            chan,
+                            states.states[chan].mode.name()
+                        );
+                    }
+                    _ => {
+                        let _ = out.push_str("Invalid key\r\n");
+                    }
+                }
+            }),
+            Ok(Command::Mode(mode)) => {
+                (&mut ctx.shared.config, &mut ctx.shared.report_mode).lock(|config, report_mode| {
+                    config.keyboard.report_mode = mode.to_config();
+                    *report_mode = mode;
+                });
+                changed = true;
+                let _ = write!(out, "Report mode: {:?}\r\n", mode);
+            }
+            Ok(Command::Socd(None)) => ctx.shared.socd.lock(|socd| {
+                for pair in socd.pairs().iter().flatten() {
+                    let [(mux1, chan1), (mux2, chan2)] = pair.keys;
+                    let _ = write!(
+                        out,
+                        "SOCD {} {} {} {} = {}\r\n",
+                        mux1,
+                        chan1,
+                        mux2,
+                        chan2,
+                        pair.mode.name()
+                    );
+                }
+                if out.is_empty() {
+                    let _ = out.push_str("No SOCD pairs\r\n");
+                }
+            }),
+            Ok(Command::Socd(Some(change))) => {
+                (&mut ctx.shared.socd, &mut ctx.shared.ch_states).lock(|socd, ch_states| {
+                    let [first, second] = change.keys;
+                    let result = match change.mode {
+                        Some(mode) => socd
+                            .set(socd::Pair { keys: change.keys, mode }, ch_states)
+                            .map_err(|e| match e {
+                                socd::SocdError::InvalidKey => "Invalid key",
+                                socd::SocdError::Full => "Invalid pair (no room for any more)",
+                            }),
+                        // Unpairing either key does it
+                        None if socd.remove(first) | socd.remove(second) => Ok(()),
+                        None => Err("Invalid pair (those keys aren't paired)"),
+                    };
+                    match result {
+                        Ok(()) => {
+                            changed = true;
+                            let mode = change.mode.map_or("off", |mode| mode.name());
+                            let _ = write!(
+                                out,
+                                "SOCD {} {} {} {} = {}\r\n",
+                                first.0, first.1, second.0, second.1, mode
+                            );
+                        }
+                        Err(message) => {
+                            let _ = write!(out, "{}\r\n", message);
+                        }
+                    }
+                })
+            }
+            Ok(Command::Dks(None)) => ctx.shared.dks.lock(|dks| {
+                for (slot, steps) in dks.slots().iter().enumerate() {
+                    if steps.iter().any(|step| step.op != dks::Op::None) {
+                        write_dks_slot(slot, steps, &mut out);
+                    }
+                }
+                if out.is_empty() {
+                    let _ = out.push_str("No DKS slots set up\r\n");
+                }
+            }),
+            Ok(Command::Dks(Some(change))) => ctx.shared.dks.lock(|dks| {
+                if dks.set(change.slot, change.point, change.step) {
+                    changed = true;
+                    write_dks_slot(change.slot, &dks.slots()[change.slot], &mut out);
+                } else {
+                    let _ = out.push_str("Invalid slot\r\n");
+                }
+            }),
+            Ok(Command::Capture(every)) => ctx.shared.capture.lock(|capture| match every {
+                Some(every) => {
+                    capture.start(every);
+                    let _ = write!(out, "Capturing every {} sweep(s)\r\n", every);
+                }
+                None => {
+                    capture.stop();
+                    let _ = out.push_str("Capture stopped\r\n");
+                }
+            }),
+            Ok(Command::Calibrate(step)) => {
+                (&mut ctx.shared.calibration, &mut ctx.shared.ch_states, &mut ctx.shared.config)
+                    .lock(|calibration, ch_states, config| match step {
+                        console::CalibrationStep::Start => {
+                            calibration.start(ch_states);
+                            let _ = out.push_str(CALIBRATION_STARTED);
+                        }
+                        _ if !calibration.is_running() => {
+                            let _ = out.push_str("No calibration running\r\n");
+                        }
+                        console::CalibrationStep::Done => {
+                            let min_swing = config.keyboard.calibration_min_swing;
+                            let report = calibration.finish(ch_states, min_swing);
+                            write_calibration_report(&report, &mut out);
+                            changed = true;
+                        }
+                        console::CalibrationStep::Cancel => {
+                            calibration.cancel();
+                            let _ = out.push_str("Calibration cancelled\r\n");
+                        }
+                    })
             }
-        })
+            Ok(Command::Bootloader) => {
+                let _ = out.push_str("Rebooting into the bootloader...\r\n");
+                bootloader = true;
+            }
+            Err(console::ParseError::Empty) => {}
+            Err(e) => {
+                let _ = write!(out, "{}\r\n", e.message());
+            }
+        }
+        let out = out.finish();
+        // Whatever the command was it might've changed something check_channel() depends on
+        ctx.shared.capture.lock(|capture| capture.request_snapshot());
+        (&mut ctx.shared.console_tx, &mut ctx.shared.usb_serial).lock(|console_tx, usb_serial| {
+            console::queue_reply(console_tx, &out);
+            console::drain(console_tx, |bytes| usb_serial.write(bytes).unwrap_or(0));
+        });
+        if changed {
+            let _ = save_settings::spawn();
+        }
+        if bootloader {
+            // Give the host a moment to pick up the message
+            ctx.shared.bootloader_ticks.lock(|ticks| *ticks = Some(BOOTLOADER_DELAY_TICKS));
+        }
     }
 
-    #[task(binds = TIM3, priority = 1, local = [debouncer, layout])]
-    fn tick(mut ctx: tick::Context) {
+    /// Serializes the current settings for idle() to write out to the SPI (or internal) flash.
+    /// Each lock only gets held for as long as it takes to serialize what it covers; the flash
+    /// takes milliseconds to write/erase and nothing gets held up by it.
+    #[task(
+        priority = 1,
+        shared = [config, ch_states, thresholds, keymap, socd, dks, console_tx, settings_out, save_pending]
+    )]
+    fn save_settings(mut ctx: save_settings::Context) {
+        let shared = &mut ctx.shared;
+        let result = shared.settings_out.lock(|out| {
+            out.clear();
+            shared.config.lock(|config| out.push(config))?;
+            let calibration = (&mut shared.thresholds, &mut shared.ch_states)
+                .lock(|thresholds, ch_states| storage::Calibration::capture(thresholds, ch_states));
+            out.push(&calibration)?;
+            shared.keymap.lock(|keymap| {
+                out.push(keymap.codes())?;
+                out.push(keymap.macros())
+            })?;
+            shared.socd.lock(|socd| out.push(socd.pairs()))?;
+            shared.dks.lock(|dks| out.push(dks.slots()))
+        });
+        match result {
+            Ok(()) => shared.save_pending.lock(|pending| *pending = true),
+            Err(_) => shared
+                .console_tx
+                .lock(|tx| console::queue(tx, "Saving settings failed\r\n")),
+        }
+    }
+
+    /// Writes out whatever save_settings() serialized last (everything else gets to go first)
+    #[idle(
+        local = [storage, settings: storage::EncodedSettings = storage::EncodedSettings::new()],
+        shared = [console_tx, settings_out, save_pending]
+    )]
+    fn idle(mut ctx: idle::Context) -> ! {
+        loop {
+            let settings = &mut *ctx.local.settings;
+            let pending = (&mut ctx.shared.settings_out, &mut ctx.shared.save_pending).lock(
+                |out, pending| {
+                    if *pending {
+                        settings.clone_from(out);
+                    }
+                    core::mem::replace(pending, false)
+                },
+            );
+            if !pending {
+                cortex_m::asm::wfi();
+                continue;
+            }
+            let message = match ctx.local.storage.as_mut() {
+                Some(flash) => flash.save(settings).err().map(|_| "Saving settings failed\r\n"),
+                None => Some("No storage; settings will be lost on reset\r\n"),
+            };
+            if let Some(message) = message {
+                ctx.shared.console_tx.lock(|tx| console::queue(tx, message));
+            }
+        }
+    }
+
+    /// Pushes (keyboard) settings that were changed at runtime out to everything that uses them
+    fn apply_config(
+        config: &config_structs::Config,
+        thresholds: &mut thresholds::ThresholdTable,
+        ch_states: &mut [multiplexers::ChannelStates],
+        report_mode: &mut report::ReportMode,
+        changed: &str,
+    ) {
+        let keyboard = &config.keyboard;
+        thresholds.set_global(keyboard.actuation_threshold, keyboard.release_threshold);
+        *report_mode = report::ReportMode::from_config(keyboard.report_mode);
+        // Changing the filter throws away its history so only do it when it actually changed
+        if changed.starts_with("keyboard.filter") {
+            let channel_filter = filter::Filter::from_config(
+                keyboard.filter,
+                keyboard.filter_window,
+                keyboard.filter_alpha,
+            );
+            for states in ch_states.iter_mut() {
+                for chan in 0..userconfig::MAX_CHANNELS {
+                    states.update_filter_by_index(chan, channel_filter);
+                }
+            }
+        }
+        // Rapid trigger's deepest/shallowest points are in the old units
+        if changed == "keyboard.threshold_units" {
+            for states in ch_states.iter_mut() {
+                for chan in 0..userconfig::MAX_CHANNELS {
+                    states.update_peak_by_index(chan, 0);
+                }
+            }
+        }
+    }
+
+    const CALIBRATION_STARTED: &str =
+        "Calibrating: press every key all the way down once (or 'calibrate done' to stop)\r\n";
+
+    /// Describes how a full-travel calibration went
+    fn write_calibration_report(
+        report: &hall_core::calibrate::Report,
+        out: &mut impl core::fmt::Write,
+    ) {
+        let _ = write!(
+            out,
+            "Calibration finished: {} key(s) calibrated\r\n",
+            report.calibrated_count()
+        );
+        let mut failed = report.failed_keys().peekable();
+        if failed.peek().is_some() {
+            let _ = out.write_str("Not calibrated (didn't move far enough; mux/chan):");
+            for (multi, chan) in failed {
+                let _ = write!(out, " {}/{}", multi, chan);
+            }
+            let _ = out.write_str("\r\n");
+        }
+    }
+
+    /// Writes out a key's thresholds the way the threshold command shows them
+    fn write_threshold(
+        mux: usize,
+        chan: usize,
+        thresholds: &thresholds::ThresholdTable,
+        out: &mut impl core::fmt::Write,
+    ) {
+        let _ = write!(
+            out,
+            "Threshold {} {} = {} {}",
+            mux,
+            chan,
+            thresholds.actuation(mux, chan),
+            thresholds.release(mux, chan)
+        );
+        if thresholds.get(mux, chan) == Some(&Default::default()) {
+            let _ = out.write_str(" (global)");
+        }
+        let _ = out.write_str("\r\n");
+    }
 
-        for event in c.local.debouncer.events(c.local.matrix.get().unwrap()) {
-            ctx.local.layout.event(event);
+    /// Describes what a DKS slot does at each point (e.g. "DKS 0: press=down 0x1A bottom=none...")
+    fn write_dks_slot(slot: usize, steps: &dks::Slot, out: &mut impl core::fmt::Write) {
+        let _ = write!(out, "DKS {}:", slot);
+        for point in dks::Point::all() {
+            let _ = write!(out, " {}={}", point.name(), steps[point as usize]);
         }
-        match ctx.local.layout.tick() {
-            keyberon::layout::CustomEvent::Release(()) => unsafe {
-                cortex_m::asm::bootload(0x1FFF0000 as _)
+        let _ = out.write_str("\r\n");
+    }
+
+    /// Queues up the sweep (preceded by a snapshot if one is needed) to go out over the serial
+    /// port if the capture wants it.  Whatever doesn't fit gets dropped.
+    fn record_frame(
+        frame: &scanner::Frame,
+        capture: &mut hall_core::trace::Capture,
+        config: &config_structs::Config,
+        ch_states: &[multiplexers::ChannelStates],
+        thresholds: &thresholds::ThresholdTable,
+        console_tx: &mut console::TxQueue,
+    ) {
+        use hall_core::trace;
+        let Some((tick, needs_snapshot)) = capture.sweep() else {
+            return;
+        };
+        let mut frame_buf = [0; trace::FRAME_SIZE];
+        let frame_len = trace::encode_frame(tick, frame, &mut frame_buf);
+        if needs_snapshot {
+            // Frames are useless without the snapshot in front of them so they go out together
+            if console_tx.capacity() - console_tx.len() < trace::SNAPSHOT_SIZE + frame_len {
+                return;
+            }
+            let snapshot = trace::Snapshot::capture(
+                tick,
+                TICK_RATE_HZ,
+                capture.every(),
+                ch_states,
+                thresholds,
+                &config.keyboard,
+                config::ENCODER_PRESS_THRESHOLD,
+            );
+            let mut snapshot_buf = [0; trace::SNAPSHOT_SIZE];
+            let snapshot_len = snapshot.encode(&mut snapshot_buf);
+            console::queue_all(console_tx, &snapshot_buf[..snapshot_len]);
+            capture.snapshot_sent();
+        }
+        console::queue_all(console_tx, &frame_buf[..frame_len]);
+    }
+
+    /// Nudges the default (resting) mV value of every un-pressed key towards what it has been
+    /// reading lately so temperature drift doesn't slowly turn into phantom presses
+    #[task(priority = 1, shared = [config, ch_states, thresholds, capture])]
+    fn update_defaults(mut ctx: update_defaults::Context) {
+        (ctx.shared.config, ctx.shared.ch_states, ctx.shared.thresholds).lock(
+            |config, ch_states, thresholds| {
+                for (multi, states) in ch_states.iter_mut().enumerate() {
+                    // Keys that are anywhere near actuating are left alone
+                    states.recalibrate(
+                        |chan| thresholds.actuation(multi, chan) / 2,
+                        config.keyboard.recalibration_noise,
+                        config.keyboard.recalibration_max_step,
+                        &hall_core::travel::Travel::from_config(&config.keyboard),
+                    );
+                }
             },
-            _ => (),
+        );
+        ctx.shared.capture.lock(|capture| capture.request_snapshot());
+    }
+
+    /// Hands the chip over to the built-in (DFU) bootloader.  It expects the chip to be the way
+    /// it comes out of reset so everything that could get in its way gets turned off first.
+    #[task(priority = 1)]
+    fn bootloader(_: bootloader::Context) {
+        cortex_m::interrupt::disable();
+        unsafe {
+            let dp = Peripherals::steal();
+            // Drop off the bus (so the host notices the bootloader showing up) and reset the USB
+            // core
+            dp.OTG1_HS_DEVICE.dctl.modify(|_, w| w.sdis().set_bit());
+            dp.RCC.ahb1rstr.modify(|_, w| w.usb1otgrst().set_bit());
+            dp.RCC.ahb1rstr.modify(|_, w| w.usb1otgrst().clear_bit());
+            dp.RCC.ahb1enr.modify(|_, w| w.usb1otgen().clear_bit().usb1ulpien().clear_bit());
+            let mut cp = cortex_m::Peripherals::steal();
+            cp.SYST.disable_interrupt();
+            cp.SYST.disable_counter();
+            for (icer, icpr) in cp.NVIC.icer.iter().zip(cp.NVIC.icpr.iter()) {
+                icer.write(u32::MAX);
+                icpr.write(u32::MAX);
+            }
+            cp.SCB.disable_icache();
+            cp.SCB.disable_dcache(&mut cp.CPUID);
+            // Nothing can fire anymore and the bootloader needs interrupts (for USB)
+            cortex_m::interrupt::enable();
+            cortex_m::asm::bootload(SYSTEM_BOOTLOADER as *const u32)
+        }
+    }
+
+    /// ADC1's DMA transfer finished; store the readings and move on to the next mux channel
+    #[task(binds = DMA1_STR0, priority = 3, shared = [scanner])]
+    fn adc_dma(mut ctx: adc_dma::Context) {
+        ctx.shared.scanner.lock(|scanner| scanner.on_transfer_complete());
+    }
+
+    #[task(
+        binds = TIM3,
+        priority = 1,
+        local = [layout, recalibration_ticks, bindings],
+        shared = [
+            config,
+            scanner,
+            ch_states,
+            thresholds,
+            usb_keyboard,
+            usb_nkro,
+            usb_gamepad,
+            report_mode,
+            pending_layer,
+            keymap,
+            capture,
+            calibration,
+            socd,
+            dks,
+            mod_taps,
+            console_tx,
+            usb_serial,
+            bootloader_ticks
+        ]
+    )]
+    fn tick(mut ctx: tick::Context) {
+        // Grab the readings from the last sweep and start sweeping all the multiplexers again
+        let frame = ctx.shared.scanner.lock(|scanner| {
+            let frame = scanner.take_frame().copied();
+            scanner.start();
+            frame
+        });
+
+        let (
+            recalibration_rate,
+            press_sensitivity,
+            release_sensitivity,
+            travel,
+            gamepad,
+            dks_settings,
+            mod_tap_settings,
+            tap_dance_window,
+        ) = ctx.shared.config.lock(|config| {
+            (
+                config.keyboard.recalibration_rate,
+                config.keyboard.rapid_trigger_press_sensitivity,
+                config.keyboard.rapid_trigger_release_sensitivity,
+                hall_core::travel::Travel::from_config(&config.keyboard),
+                hall_core::gamepad::Settings::from_config(
+                    &config.gamepad,
+                    config.keyboard.full_travel,
+                ),
+                dks::Settings::from_config(&config.dks, TICK_RATE_HZ),
+                hall_core::modtap::Settings::from_config(&config.mod_tap),
+                config.tap_dance.window,
+            )
+        });
+
+        // A recalibration rate of 0 turns recalibration off
+        if recalibration_rate > 0 {
+            *ctx.local.recalibration_ticks += 1;
+            if *ctx.local.recalibration_ticks >= recalibration_rate.saturating_mul(TICK_RATE_HZ) {
+                *ctx.local.recalibration_ticks = 0;
+                let _ = update_defaults::spawn();
+            }
+        }
+        // Reboot into the bootloader once the host has had its moment (see BOOTLOADER_DELAY_TICKS)
+        let reboot = ctx.shared.bootloader_ticks.lock(|ticks| match ticks {
+            Some(0) => true,
+            Some(left) => {
+                *left -= 1;
+                false
+            }
+            None => false,
+        });
+        if reboot {
+            let _ = bootloader::spawn();
         }
 
-        let report: KbHidReport = c.local.layout.keycodes().collect();
-        if ctx.shared
-            .usb_class
-            .lock(|k| k.device_mut().set_keyboard_report(report.clone()))
-        {
-            while let Ok(0) = ctx.shared.usb_serial.lock(|k| k.write(report.as_bytes())) {}
+        let layout = ctx.local.layout;
+        let bindings = ctx.local.bindings;
+        layout.set_tap_dance_window(tap_dance_window);
+        if let Some(layer) = ctx.shared.pending_layer.lock(|pending| pending.take()) {
+            layout.set_default_layer(layer);
         }
+        ctx.shared.keymap.lock(|keymap| {
+            if keymap.take_reload() {
+                for layer in 0..keymap::NUM_LAYERS {
+                    for mux in 0..userconfig::NUM_MULTIPLEXERS {
+                        for chan in 0..keymap::NUM_COLUMNS {
+                            let action = keymap.action(layer, mux, chan);
+                            layout.change_action((mux, chan), layer, action);
+                        }
+                    }
+                }
+                *bindings = keymap.bindings();
+            }
+            while let Some(change) = keymap.take_change() {
+                let coord = (change.mux, change.chan);
+                layout.change_action(coord, change.layer, change.action);
+                bindings[change.layer][change.mux][change.chan] =
+                    keymap::Binding::of(&change.action);
+            }
+        });
+        // Keys get pressed for the full-travel calibration, not to type anything (but keys
+        // that were already down still get released)
+        let calibrating = ctx.shared.calibration.lock(|c| c.is_running());
+        if let Some(frame) = frame {
+            if ctx.shared.capture.lock(|capture| capture.is_running()) {
+                (
+                    &mut ctx.shared.capture,
+                    &mut ctx.shared.config,
+                    &mut ctx.shared.ch_states,
+                    &mut ctx.shared.thresholds,
+                    &mut ctx.shared.console_tx,
+                    &mut ctx.shared.usb_serial,
+                )
+                    .lock(|capture, config, ch_states, thresholds, console_tx, usb_serial| {
+                        record_frame(&frame, capture, config, ch_states, thresholds, console_tx);
+                        console::drain(console_tx, |bytes| usb_serial.write(bytes).unwrap_or(0));
+                    });
+            }
+            (&mut ctx.shared.ch_states, &mut ctx.shared.thresholds, &mut ctx.shared.socd).lock(
+                |ch_states, thresholds, socd| {
+                    multiplexers::scan(
+                        &frame,
+                        ch_states,
+                        thresholds,
+                        press_sensitivity,
+                        release_sensitivity,
+                        &travel,
+                        |event| {
+                            if calibrating && matches!(event, multiplexers::Event::Press(..)) {
+                                return;
+                            }
+                            // Paired keys wait for the SOCD resolution below
+                            if let Some(event) = socd.filter(event) {
+                                layout.event(event);
+                            }
+                        },
+                    );
+                    socd.resolve(ch_states, &travel, |event| {
+                        layout.event(event);
+                    });
+                },
+            );
+            if calibrating {
+                let (min_swing, timeout) = ctx.shared.config.lock(|config| {
+                    let keyboard = &config.keyboard;
+                    (keyboard.calibration_min_swing, keyboard.calibration_timeout)
+                });
+                let report = (&mut ctx.shared.calibration, &mut ctx.shared.ch_states).lock(
+                    |calibration, ch_states| {
+                        calibration
+                            .sweep(ch_states, min_swing, timeout * TICK_RATE_HZ)
+                            .then(|| calibration.finish(ch_states, min_swing))
+                    },
+                );
+                if let Some(report) = report {
+                    let mut out = console::Output::new();
+                    write_calibration_report(&report, &mut out);
+                    let out = out.finish();
+                    (&mut ctx.shared.console_tx, &mut ctx.shared.usb_serial).lock(
+                        |console_tx, usb_serial| {
+                            console::queue(console_tx, &out);
+                            console::drain(console_tx, |bytes| usb_serial.write(bytes).unwrap_or(0));
+                        },
+                    );
+                    ctx.shared.capture.lock(|capture| capture.request_snapshot());
+                    let _ = save_settings::spawn();
+                }
+            }
+        }
+        match layout.tick() {
+            layout::CustomEvent::Press(keymap::CustomAction::Calibrate) => {
+                (&mut ctx.shared.calibration, &mut ctx.shared.ch_states)
+                    .lock(|calibration, ch_states| calibration.start(ch_states));
+                (&mut ctx.shared.console_tx, &mut ctx.shared.usb_serial).lock(
+                    |console_tx, usb_serial| {
+                        console::queue(console_tx, CALIBRATION_STARTED);
+                        console::drain(console_tx, |bytes| usb_serial.write(bytes).unwrap_or(0));
+                    },
+                );
+            }
+            layout::CustomEvent::Release(keymap::CustomAction::Bootloader) => {
+                ctx.shared.bootloader_ticks.lock(|ticks| *ticks = Some(BOOTLOADER_DELAY_TICKS));
+            }
+            _ => (),
+        }
+
+        // Keys bound to the gamepad push its sticks/triggers as far as they're pressed (see
+        // hall_core::gamepad) on whichever layer is active
+        let layer = layout.current_layer();
+        let default_layer = layout.default_layer();
+        let gamepad_report = ctx.shared.ch_states.lock(|ch_states| {
+            let keys = keymap::gamepad_keys(bindings, layer, default_layer)
+                .filter(|_| !calibrating)
+                .map(|(mux, chan, input)| (input, ch_states[mux].states[chan].travel(&travel)));
+            hall_core::gamepad::Report::from_travel(keys, &gamepad)
+        });
+        ctx.shared.usb_gamepad.lock(|g| g.write_report(gamepad_report.as_bytes()));
+
+        // Keys bound to DKS slots press/release/tap whatever their slot says at each point of
+        // their travel (see hall_core::dks) and mod-tap keys press their tap key or modifiers
+        // depending on how far down they are (see hall_core::modtap).  The keys they press get
+        // reported along with the layout's.
+        (&mut ctx.shared.dks, &mut ctx.shared.mod_taps, &mut ctx.shared.ch_states).lock(
+            |dks, mod_taps, ch_states| {
+                dks.sweep(ch_states, &travel, &dks_settings, |mux, chan| {
+                    keymap::dks_slot(bindings, layer, default_layer, mux, chan)
+                        .filter(|_| !calibrating)
+                });
+                mod_taps.sweep(ch_states, &travel, &mod_tap_settings, |mux, chan| {
+                    keymap::mod_tap(bindings, layer, default_layer, mux, chan)
+                        .filter(|_| !calibrating)
+                });
+            },
+        );
+
+        // Hosts that only speak the boot protocol (e.g. BIOSes) can only be sent 6KRO reports.
+        // Whichever interface isn't in use gets an empty report so keys don't show up twice.
+        let boot_only = ctx
+            .shared
+            .usb_keyboard
+            .lock(|k| k.host_protocol() == hid::Protocol::Boot);
+        let mode = if boot_only {
+            report::ReportMode::Boot6Kro
+        } else {
+            ctx.shared.report_mode.lock(|m| *m)
+        };
+        let (boot_report, nkro_report): (report::BootReport, report::NkroReport) =
+            (&mut ctx.shared.dks, &mut ctx.shared.mod_taps).lock(|dks, mod_taps| {
+                let keycodes = || {
+                    let analog = dks.keys().chain(mod_taps.keys());
+                    layout.keycodes().chain(analog.filter_map(KeyCode::from_usage))
+                };
+                match mode {
+                    report::ReportMode::Boot6Kro => (keycodes().collect(), Default::default()),
+                    report::ReportMode::Nkro => (Default::default(), keycodes().collect()),
+                }
+            });
+        ctx.shared.usb_nkro.lock(|k| k.write_report(nkro_report.as_bytes()));
+        // Only changed reports get queued; the HID class sends them on the next poll
+        ctx.shared.usb_keyboard.lock(|k| k.write_report(boot_report.as_bytes()));
     }
 }
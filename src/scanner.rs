@@ -0,0 +1,194 @@
+//! DMA-driven scanning of every analog multiplexer
+//!
+//! Rather than reading the five analog pins one-by-one with blocking conversions we program
+//! ADC1 (PA0, PA1, PA2) and ADC2 (PA3, PA4) with a regular sequence and let the DMA collect the
+//! results.  Every mux channel gets sampled on all five multiplexers in one shot:
+//!
+//! 1. tick() (TIM3) calls Scanner::start() which selects mux channel 0 and triggers both ADCs
+//! 2. When ADC1's DMA transfer completes the readings get stored, the next mux channel gets
+//!    selected and both ADCs are triggered again
+//! 3. After the last mux channel the finished frame gets swapped to the front where
+//!    Scanner::take_frame() can get at it while the next sweep fills the back
+//!
+//! The one exception is the very first sweep in init() (for the resting values) which reads
+//! the ADC one pin at a time through `BlockingInputs`.
+
+use crate::aliases::{Adc1Transfer, Adc2Transfer, AnalogPins, Multiplex};
+use crate::userconfig::{MAX_CHANNELS, NUM_MULTIPLEXERS};
+use embedded_hal::adc::{Channel, OneShot};
+use hall_core::sensors::{AnalogInputs, Multiplexers};
+use stm32h7xx_hal::adc::{Adc, Enabled};
+use stm32h7xx_hal::pac::{self, ADC1};
+
+pub use hall_core::sensors::{Frame, MUX_CHANNELS};
+/// Regular sequence for ADC1: PA0 = INP16, PA1 = INP17, PA2 = INP14
+pub const ADC1_SEQUENCE: [u8; 3] = [16, 17, 14];
+/// Regular sequence for ADC2: PA3 = INP15, PA4 = INP18
+pub const ADC2_SEQUENCE: [u8; 2] = [15, 18];
+/// CPU cycles to wait for the multiplexer outputs to settle after switching channels (~1us)
+const MUX_SETTLE_CYCLES: u32 = 480;
+
+/// Converts a raw 16-bit ADC reading into the (pseudo) millivolt scale the rest of the firmware uses
+pub fn to_millivolts(raw: u16) -> u16 {
+    raw / 4
+}
+
+/// The multiplexer select lines
+pub struct SelectLines(pub Multiplex);
+
+impl Multiplexers for SelectLines {
+    fn select(&mut self, channel: u8) {
+        self.0.set_channel(channel);
+        cortex_m::asm::delay(MUX_SETTLE_CYCLES);
+    }
+}
+
+/// ADC1 reading the multiplexer outputs one at a time (before the DMA takes over)
+pub struct BlockingInputs<'a> {
+    pub adc: &'a mut Adc<ADC1, Enabled>,
+    pub pins: &'a mut AnalogPins,
+}
+
+/// Converts *pin* and returns the result in millivolts
+fn read_pin<PIN: Channel<ADC1, ID = u8>>(adc: &mut Adc<ADC1, Enabled>, pin: &mut PIN) -> u16 {
+    let raw: u32 = adc.read(pin).unwrap_or(0);
+    to_millivolts(raw as u16)
+}
+
+impl AnalogInputs for BlockingInputs<'_> {
+    fn read(&mut self, millivolts: &mut [u16; NUM_MULTIPLEXERS]) {
+        millivolts[0] = read_pin(self.adc, &mut self.pins.0);
+        millivolts[1] = read_pin(self.adc, &mut self.pins.1);
+        millivolts[2] = read_pin(self.adc, &mut self.pins.2);
+        millivolts[3] = read_pin(self.adc, &mut self.pins.3);
+        millivolts[4] = read_pin(self.adc, &mut self.pins.4);
+    }
+}
+
+/// Sets up the given ADC to convert *channels* (in order) as a regular sequence, handing each
+/// result to the DMA (one-shot mode so the DMA stream can be restarted for every mux channel)
+fn configure_sequence(adc: &pac::adc1::RegisterBlock, channels: &[u8]) {
+    adc.sqr1.modify(|_, w| w.l().bits(channels.len() as u8 - 1));
+    for (i, &chan) in channels.iter().enumerate() {
+        adc.sqr1.modify(|_, w| unsafe {
+            match i {
+                0 => w.sq1().bits(chan),
+                1 => w.sq2().bits(chan),
+                2 => w.sq3().bits(chan),
+                _ => w.sq4().bits(chan),
+            }
+        });
+    }
+    let preselect = channels.iter().fold(0, |bits, chan| bits | 1 << chan);
+    adc.pcsel.modify(|r, w| unsafe { w.pcsel().bits(r.pcsel().bits() | preselect) });
+    adc.cfgr.modify(|_, w| w.dmngt().dma_one_shot().cont().single());
+}
+
+/// Starts a conversion of the regular sequence on the given ADC
+fn start_conversion(adc: &pac::adc1::RegisterBlock) {
+    adc.cr.modify(|_, w| w.adstart().start_conversion());
+}
+
+pub struct Scanner {
+    adc1: Adc1Transfer,
+    adc2: Adc2Transfer,
+    multiplexer: SelectLines,
+    frames: [Frame; 2],
+    back: usize,       // Index of the frame the DMA results are going into
+    channel: u8,       // Mux channel currently being converted
+    scanning: bool,    // A sweep is in progress
+    fresh: bool,       // The front frame hasn't been taken yet
+    pub overruns: u32, // Number of times tick() wanted a new sweep before the last one finished
+}
+
+impl Scanner {
+    /// Takes ownership of the ADC DMA transfers (already started/armed) and the multiplexer
+    /// select pins
+    pub fn new(adc1: Adc1Transfer, adc2: Adc2Transfer, multiplexer: SelectLines) -> Scanner {
+        unsafe {
+            configure_sequence(&*pac::ADC1::ptr(), &ADC1_SEQUENCE);
+            configure_sequence(&*pac::ADC2::ptr(), &ADC2_SEQUENCE);
+        }
+        Scanner {
+            adc1,
+            adc2,
+            multiplexer,
+            frames: [[[0; MAX_CHANNELS]; NUM_MULTIPLEXERS]; 2],
+            back: 0,
+            channel: 0,
+            scanning: false,
+            fresh: false,
+            overruns: 0,
+        }
+    }
+
+    /// Kicks off a sweep of every mux channel.  Returns false (and does nothing) if the
+    /// previous sweep is still running.
+    pub fn start(&mut self) -> bool {
+        if self.scanning {
+            self.overruns = self.overruns.wrapping_add(1);
+            return false;
+        }
+        self.scanning = true;
+        self.channel = 0;
+        self.select_and_convert();
+        true
+    }
+
+    /// Selects self.channel on all multiplexers and triggers both ADCs
+    fn select_and_convert(&mut self) {
+        self.multiplexer.select(self.channel);
+        unsafe {
+            start_conversion(&*pac::ADC1::ptr());
+            start_conversion(&*pac::ADC2::ptr());
+        }
+    }
+
+    /// Must be called from ADC1's DMA transfer complete interrupt.  Stores the readings for
+    /// the current mux channel and moves on to the next one.  Returns true when a sweep has
+    /// finished and a new frame is available.
+    pub fn on_transfer_complete(&mut self) -> bool {
+        self.adc1.clear_transfer_complete_interrupt();
+        // ADC2 only has two conversions to do so it's practically always done by now
+        while !self.adc2.get_transfer_complete_flag() {}
+        self.adc2.clear_transfer_complete_interrupt();
+
+        // Copy out the readings; this also re-arms both DMA streams for the next conversion
+        let chan = self.channel as usize;
+        let frame = &mut self.frames[self.back];
+        let _ = self.adc1.next_transfer_with(|buf, _, _| {
+            for (multi, raw) in buf.iter().enumerate() {
+                frame[multi][chan] = to_millivolts(*raw);
+            }
+            (buf, ())
+        });
+        let _ = self.adc2.next_transfer_with(|buf, _, _| {
+            for (i, raw) in buf.iter().enumerate() {
+                frame[ADC1_SEQUENCE.len() + i][chan] = to_millivolts(*raw);
+            }
+            (buf, ())
+        });
+
+        self.channel += 1;
+        if self.channel < MUX_CHANNELS {
+            self.select_and_convert();
+            false
+        } else {
+            // Sweep complete; swap the frames
+            self.back ^= 1;
+            self.scanning = false;
+            self.fresh = true;
+            true
+        }
+    }
+
+    /// Returns the most recently completed frame if it hasn't been taken yet
+    pub fn take_frame(&mut self) -> Option<&Frame> {
+        if self.fresh {
+            self.fresh = false;
+            Some(&self.frames[self.back ^ 1])
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Persistent settings
+//!
+//! Everything that can be changed at runtime (the whole `Config`, the per-key calibration, the
+//! keymap, the SOCD pairs and the DKS slots) gets saved through a `SettingsStore`.  There are two
+//! backends:
+//!
+//! * `SpiStorage` (below) for builds with an external SPI NOR flash chip
+//! * `LogStorage` (see flash_log.rs) which uses the last sectors of the internal flash
+//!
+//! `SpiStorage` serializes the settings with postcard and writes them to the first sector of
+//! the flash chip behind a small header:
+//!
+//! | Offset | Size | Contents                                  |
+//! |--------|------|-------------------------------------------|
+//! | 0      | 4    | `MAGIC`                                   |
+//! | 4      | 2    | `VERSION` (bumped when the layout changes)|
+//! | 6      | 2    | Reserved (0)                              |
+//! | 8      | 4    | Length of the payload                     |
+//! | 12     | 4    | CRC-32 of the payload                     |
+//! | 16     | ...  | Payload                                   |
+//!
+//! Anything that doesn't check out (blank chip, old version, bad CRC) means we fall back to the
+//! defaults from userconfig.rs.
+
+use crate::config_structs::Config;
+use crate::keymap::{Codes, MacroBuffer};
+use crate::multiplexers::{ChannelStates, TriggerMode};
+use crate::thresholds::{KeyThresholds, ThresholdTable};
+use crate::userconfig::{MAX_CHANNELS, NUM_MULTIPLEXERS};
+use crate::{dks, socd};
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use serde::{Deserialize, Serialize};
+use spi_memory::prelude::*;
+use spi_memory::series25::Flash;
+
+/// Identifies our settings ("HEKB")
+pub const MAGIC: u32 = 0x4845_4B42;
+/// Version of the stored layout; settings saved by any other version get ignored
+pub const VERSION: u16 = 10;
+/// Size of the header in front of the payload
+pub const HEADER_SIZE: usize = 16;
+/// Largest payload we'll read/write
+pub const MAX_PAYLOAD: usize = SECTOR_SIZE as usize - HEADER_SIZE;
+/// Where the settings live on the flash chip
+pub const SETTINGS_ADDRESS: u32 = 0;
+/// Smallest erasable unit of (just about) every 25-series chip
+pub const SECTOR_SIZE: u32 = 4096;
+
+// The settings have to fit in the one sector that gets erased before saving
+const _: () = assert!((HEADER_SIZE + MAX_PAYLOAD) as u32 <= SECTOR_SIZE);
+
+/// Per-key settings that don't live in `Config`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Per-key threshold overrides
+    pub thresholds: [[KeyThresholds; MAX_CHANNELS]; NUM_MULTIPLEXERS],
+    /// Whether each key uses a fixed threshold or rapid trigger
+    pub modes: [[TriggerMode; MAX_CHANNELS]; NUM_MULTIPLEXERS],
+    /// Millivolts between each key's resting and bottom-out readings (0 = never bottomed out).
+    /// The resting values get measured at power up so the bottom-out readings get saved
+    /// relative to them.
+    pub swings: [[u16; MAX_CHANNELS]; NUM_MULTIPLEXERS],
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            thresholds: [[KeyThresholds::default(); MAX_CHANNELS]; NUM_MULTIPLEXERS],
+            modes: [[TriggerMode::Threshold; MAX_CHANNELS]; NUM_MULTIPLEXERS],
+            swings: [[0; MAX_CHANNELS]; NUM_MULTIPLEXERS],
+        }
+    }
+}
+
+impl Calibration {
+    /// Grabs the current per-key settings
+    pub fn capture(thresholds: &ThresholdTable, ch_states: &[ChannelStates]) -> Calibration {
+        let mut calibration = Calibration {
+            thresholds: thresholds.keys,
+            ..Default::default()
+        };
+        for (modes, states) in calibration.modes.iter_mut().zip(ch_states) {
+            for (mode, state) in modes.iter_mut().zip(states.states.iter()) {
+                *mode = state.mode;
+            }
+        }
+        for (swings, states) in calibration.swings.iter_mut().zip(ch_states) {
+            for (swing, state) in swings.iter_mut().zip(states.states.iter()) {
+                *swing = state.calibrated_swing();
+            }
+        }
+        calibration
+    }
+
+    /// Puts the saved per-key settings into effect (after the resting values have been read)
+    pub fn apply(&self, thresholds: &mut ThresholdTable, ch_states: &mut [ChannelStates]) {
+        thresholds.keys = self.thresholds;
+        for ((modes, swings), states) in self.modes.iter().zip(&self.swings).zip(ch_states) {
+            for (chan, (&mode, &swing)) in modes.iter().zip(swings).enumerate() {
+                states.update_mode_by_index(chan, mode);
+                states[chan].set_swing(swing);
+            }
+        }
+    }
+}
+
+/// Everything that gets saved
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Settings {
+    pub config: Config,
+    pub calibration: Calibration,
+    /// See keymap.rs
+    pub keymap: Codes,
+    pub macros: MacroBuffer,
+    /// See socd.rs in hall-core
+    pub socd: socd::Pairs,
+    /// See dks.rs in hall-core
+    pub dks: dks::Slots,
+}
+
+/// Number of sections the settings get serialized in (the fields of `Settings`, in order)
+pub const SECTIONS: usize = 6;
+
+/// The settings serialized with postcard one section (field of `Settings`) at a time.  postcard
+/// doesn't put anything between the fields of a struct so the sections back to back are the
+/// encoding of the whole `Settings`.
+///
+/// Serializing only takes a moment so it can be done with the settings locked; writing the
+/// result out to flash takes milliseconds and gets done later without holding anything up.
+#[derive(Clone)]
+pub struct EncodedSettings {
+    buf: [u8; MAX_PAYLOAD],
+    ends: [usize; SECTIONS], // Where each section ends in *buf*
+    count: usize,            // Number of sections serialized so far
+}
+
+impl EncodedSettings {
+    pub const fn new() -> Self {
+        EncodedSettings {
+            buf: [0; MAX_PAYLOAD],
+            ends: [0; SECTIONS],
+            count: 0,
+        }
+    }
+
+    /// Throws away every section (to start serializing the settings over again)
+    pub fn clear(&mut self) {
+        self.count = 0;
+    }
+
+    /// Serializes the next section
+    pub fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), StorageError> {
+        if self.count == SECTIONS {
+            return Err(StorageError::Encoding);
+        }
+        let start = self.payload().len();
+        let len = postcard::to_slice(value, &mut self.buf[start..])
+            .map_err(|_| StorageError::Encoding)?
+            .len();
+        self.ends[self.count] = start + len;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Whether every section has been serialized
+    pub fn is_complete(&self) -> bool {
+        self.count == SECTIONS
+    }
+
+    /// The bytes of the given section
+    pub fn section(&self, section: usize) -> &[u8] {
+        let start = match section {
+            0 => 0,
+            _ => self.ends[section - 1],
+        };
+        &self.buf[start..self.ends[section]]
+    }
+
+    /// Every section serialized so far
+    pub fn payload(&self) -> &[u8] {
+        match self.count {
+            0 => &[],
+            count => &self.buf[..self.ends[count - 1]],
+        }
+    }
+}
+
+impl Default for EncodedSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageError {
+    /// Talking to the flash chip failed
+    Flash,
+    /// Nothing (of ours) has been saved yet
+    Blank,
+    /// The settings were saved by a different firmware version
+    Version,
+    /// The payload is corrupt
+    Crc,
+    /// The settings couldn't be (de)serialized (e.g. too big)
+    Encoding,
+}
+
+/// Something that can hold on to the settings across resets
+pub trait SettingsStore {
+    /// Reads back whatever was saved last
+    fn load(&mut self) -> Result<Settings, StorageError>;
+    /// Saves the given settings (replacing whatever was there before)
+    fn save(&mut self, settings: &EncodedSettings) -> Result<(), StorageError>;
+}
+
+/// Whichever backend this build ended up with
+pub enum Backend<E, I> {
+    External(E),
+    Internal(I),
+}
+
+impl<E: SettingsStore, I: SettingsStore> SettingsStore for Backend<E, I> {
+    fn load(&mut self) -> Result<Settings, StorageError> {
+        match self {
+            Backend::External(store) => store.load(),
+            Backend::Internal(store) => store.load(),
+        }
+    }
+
+    fn save(&mut self, settings: &EncodedSettings) -> Result<(), StorageError> {
+        match self {
+            Backend::External(store) => store.save(settings),
+            Backend::Internal(store) => store.save(settings),
+        }
+    }
+}
+
+/// CRC-32 (IEEE 802.3, same as zlib) of *data*
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xFFFF_FFFF, data)
+}
+
+/// Feeds *data* into a running CRC-32 (start with 0xFFFF_FFFF and invert the result)
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
+
+/// Puts the serialized settings (header included) into *buf*, returning the number of bytes used
+pub fn encode(
+    settings: &EncodedSettings,
+    buf: &mut [u8; HEADER_SIZE + MAX_PAYLOAD],
+) -> Result<usize, StorageError> {
+    if !settings.is_complete() {
+        return Err(StorageError::Encoding);
+    }
+    let (header, payload) = buf.split_at_mut(HEADER_SIZE);
+    let len = settings.payload().len();
+    payload[..len].copy_from_slice(settings.payload());
+    let crc = crc32(&payload[..len]);
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    header[6..8].copy_from_slice(&0u16.to_le_bytes());
+    header[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+    header[12..16].copy_from_slice(&crc.to_le_bytes());
+    Ok(HEADER_SIZE + len)
+}
+
+/// Checks the header at the start of *header* and returns the payload length
+fn check_header(header: &[u8]) -> Result<usize, StorageError> {
+    let word = |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+    if word(0) != MAGIC {
+        return Err(StorageError::Blank);
+    }
+    if u16::from_le_bytes([header[4], header[5]]) != VERSION {
+        return Err(StorageError::Version);
+    }
+    let len = word(8) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(StorageError::Crc);
+    }
+    Ok(len)
+}
+
+/// Parses settings (header included) that were written by encode()
+pub fn decode(buf: &[u8]) -> Result<Settings, StorageError> {
+    if buf.len() < HEADER_SIZE {
+        return Err(StorageError::Blank);
+    }
+    let len = check_header(&buf[..HEADER_SIZE])?;
+    let payload = buf.get(HEADER_SIZE..HEADER_SIZE + len).ok_or(StorageError::Crc)?;
+    let crc = u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]);
+    if crc32(payload) != crc {
+        return Err(StorageError::Crc);
+    }
+    postcard::from_bytes(payload).map_err(|_| StorageError::Encoding)
+}
+
+/// Settings storage on a 25-series SPI NOR flash chip
+pub struct SpiStorage<SPI: Transfer<u8>, CS: OutputPin> {
+    flash: Flash<SPI, CS>,
+    buf: [u8; HEADER_SIZE + MAX_PAYLOAD],
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> SpiStorage<SPI, CS> {
+    /// Probes the flash chip on the given bus
+    pub fn new(spi: SPI, cs: CS) -> Result<Self, StorageError> {
+        let flash = Flash::init(spi, cs).map_err(|_| StorageError::Flash)?;
+        Ok(SpiStorage {
+            flash,
+            buf: [0; HEADER_SIZE + MAX_PAYLOAD],
+        })
+    }
+}
+
+impl<SPI: Transfer<u8>, CS: OutputPin> SettingsStore for SpiStorage<SPI, CS> {
+    fn load(&mut self) -> Result<Settings, StorageError> {
+        let (header, payload) = self.buf.split_at_mut(HEADER_SIZE);
+        self.flash
+            .read(SETTINGS_ADDRESS, header)
+            .map_err(|_| StorageError::Flash)?;
+        let len = check_header(header)?;
+        self.flash
+            .read(SETTINGS_ADDRESS + HEADER_SIZE as u32, &mut payload[..len])
+            .map_err(|_| StorageError::Flash)?;
+        decode(&self.buf[..HEADER_SIZE + len])
+    }
+
+    /// Erases the settings sector and writes out the given settings
+    fn save(&mut self, settings: &EncodedSettings) -> Result<(), StorageError> {
+        let len = encode(settings, &mut self.buf)?;
+        self.flash
+            .erase_sectors(SETTINGS_ADDRESS, 1)
+            .map_err(|_| StorageError::Flash)?;
+        self.flash
+            .write_bytes(SETTINGS_ADDRESS, &mut self.buf[..len])
+            .map_err(|_| StorageError::Flash)
+    }
+}
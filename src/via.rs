@@ -0,0 +1,213 @@
+//! The VIA configurator protocol (over the raw HID interface)
+//!
+//! VIA sends 32-byte reports where the first byte is the command; the answer is the same report
+//! with the requested values filled in.  VIA's (row, column) is our (multiplexer, channel) so a
+//! VIA keyboard definition has 5 rows of 16 columns (see layers.rs for which key is where).
+//!
+//! Our own settings show up as VIA "custom values" on the `CHANNEL_CUSTOM` channel (see the
+//! `VALUE_*` constants) so they can be given a menu in the keyboard definition.
+
+use crate::config_structs::Config;
+use crate::hid::RAW_HID_REPORT_SIZE;
+use crate::keymap::{self, Keymap, MACRO_BUFFER_SIZE, MACRO_COUNT, NUM_COLUMNS, NUM_LAYERS};
+use crate::multiplexers::ChannelStates;
+use crate::userconfig::NUM_MULTIPLEXERS;
+
+/// VIA protocol version we speak
+pub const PROTOCOL_VERSION: u16 = 0x000C;
+
+pub const GET_PROTOCOL_VERSION: u8 = 0x01;
+pub const GET_KEYBOARD_VALUE: u8 = 0x02;
+pub const SET_KEYBOARD_VALUE: u8 = 0x03;
+pub const DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+pub const DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+pub const DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+pub const CUSTOM_SET_VALUE: u8 = 0x07;
+pub const CUSTOM_GET_VALUE: u8 = 0x08;
+pub const CUSTOM_SAVE: u8 = 0x09;
+pub const EEPROM_RESET: u8 = 0x0A;
+pub const BOOTLOADER_JUMP: u8 = 0x0B;
+pub const DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+pub const DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+pub const DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+pub const DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+pub const DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+pub const DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+pub const DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+pub const DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+/// What we answer with when we don't know the command
+pub const UNHANDLED: u8 = 0xFF;
+
+// Keyboard values (GET_KEYBOARD_VALUE/SET_KEYBOARD_VALUE)
+pub const LAYOUT_OPTIONS: u8 = 0x02;
+pub const SWITCH_MATRIX_STATE: u8 = 0x03;
+
+/// Channel our custom values live on
+pub const CHANNEL_CUSTOM: u8 = 0;
+pub const VALUE_ACTUATION_THRESHOLD: u8 = 1;
+pub const VALUE_RELEASE_THRESHOLD: u8 = 2;
+pub const VALUE_RAPID_TRIGGER_PRESS: u8 = 3;
+pub const VALUE_RAPID_TRIGGER_RELEASE: u8 = 4;
+
+/// Most bytes a buffer request can carry (what's left of the report after the header)
+const MAX_CHUNK: usize = RAW_HID_REPORT_SIZE - 4;
+
+pub type Report = [u8; RAW_HID_REPORT_SIZE];
+
+/// What the caller needs to do once a request has been handled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Effects {
+    /// A config field changed (by its "section.field" name) and needs to be put into effect
+    pub changed: Option<&'static str>,
+    /// The settings need to be saved
+    pub save: bool,
+    /// Reboot into the bootloader (after the answer has been sent)
+    pub bootloader: bool,
+}
+
+/// Everything a request might need to look at or change
+pub struct Context<'a> {
+    pub keymap: &'a mut Keymap,
+    pub config: &'a mut Config,
+    pub ch_states: &'a [ChannelStates],
+}
+
+fn be16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+/// Splits a byte offset into the dynamic keymap buffer into (layer, mux, channel, high byte)
+fn keymap_position(offset: usize) -> (usize, usize, usize, bool) {
+    let index = offset / 2;
+    let layer = index / (NUM_MULTIPLEXERS * NUM_COLUMNS);
+    let mux = index / NUM_COLUMNS % NUM_MULTIPLEXERS;
+    let chan = index % NUM_COLUMNS;
+    (layer, mux, chan, offset.is_multiple_of(2))
+}
+
+/// Returns the config field behind a custom value (and its name for apply_config())
+fn custom_value(config: &mut Config, id: u8) -> Option<(&mut u16, &'static str)> {
+    let keyboard = &mut config.keyboard;
+    match id {
+        VALUE_ACTUATION_THRESHOLD => {
+            Some((&mut keyboard.actuation_threshold, "keyboard.actuation_threshold"))
+        }
+        VALUE_RELEASE_THRESHOLD => {
+            Some((&mut keyboard.release_threshold, "keyboard.release_threshold"))
+        }
+        VALUE_RAPID_TRIGGER_PRESS => Some((
+            &mut keyboard.rapid_trigger_press_sensitivity,
+            "keyboard.rapid_trigger_press_sensitivity",
+        )),
+        VALUE_RAPID_TRIGGER_RELEASE => Some((
+            &mut keyboard.rapid_trigger_release_sensitivity,
+            "keyboard.rapid_trigger_release_sensitivity",
+        )),
+        _ => None,
+    }
+}
+
+/// Handles the request in *report*, replacing it with the answer
+pub fn handle(report: &mut Report, ctx: Context) -> Effects {
+    let mut effects = Effects::default();
+    let (command, data) = report.split_at_mut(1);
+    match command[0] {
+        GET_PROTOCOL_VERSION => data[..2].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes()),
+        GET_KEYBOARD_VALUE => match data[0] {
+            LAYOUT_OPTIONS => data[1..5].fill(0),
+            SWITCH_MATRIX_STATE => {
+                // One 16-bit (big endian) bitmap of pressed channels per multiplexer
+                for (mux, states) in ctx.ch_states.iter().enumerate() {
+                    let bits = states
+                        .states
+                        .iter()
+                        .take(NUM_COLUMNS)
+                        .enumerate()
+                        .filter(|(_, state)| state.pressed)
+                        .fold(0u16, |bits, (chan, _)| bits | 1 << chan);
+                    data[1 + mux * 2..3 + mux * 2].copy_from_slice(&bits.to_be_bytes());
+                }
+            }
+            _ => command[0] = UNHANDLED,
+        },
+        // There's only the one layout so there's nothing to set
+        SET_KEYBOARD_VALUE if data[0] == LAYOUT_OPTIONS => {}
+        DYNAMIC_KEYMAP_GET_KEYCODE => {
+            let (layer, mux, chan) = (data[0] as usize, data[1] as usize, data[2] as usize);
+            let code = ctx.keymap.get(layer, mux, chan).unwrap_or(keymap::KC_NO);
+            data[3..5].copy_from_slice(&code.to_be_bytes());
+        }
+        DYNAMIC_KEYMAP_SET_KEYCODE => {
+            let (layer, mux, chan) = (data[0] as usize, data[1] as usize, data[2] as usize);
+            effects.save = ctx.keymap.set(layer, mux, chan, be16(&data[3..5]));
+        }
+        DYNAMIC_KEYMAP_RESET => {
+            ctx.keymap.reset();
+            effects.save = true;
+        }
+        CUSTOM_GET_VALUE if data[0] == CHANNEL_CUSTOM => match custom_value(ctx.config, data[1]) {
+            Some((value, _)) => data[2..4].copy_from_slice(&value.to_be_bytes()),
+            None => command[0] = UNHANDLED,
+        },
+        CUSTOM_SET_VALUE if data[0] == CHANNEL_CUSTOM => match custom_value(ctx.config, data[1]) {
+            Some((value, name)) => {
+                *value = be16(&data[2..4]);
+                effects.changed = Some(name);
+            }
+            None => command[0] = UNHANDLED,
+        },
+        CUSTOM_SAVE if data[0] == CHANNEL_CUSTOM => effects.save = true,
+        EEPROM_RESET => {
+            ctx.keymap.reset();
+            ctx.keymap.reset_macros();
+            effects.save = true;
+        }
+        BOOTLOADER_JUMP => effects.bootloader = true,
+        DYNAMIC_KEYMAP_MACRO_GET_COUNT => data[0] = MACRO_COUNT,
+        DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+            data[..2].copy_from_slice(&(MACRO_BUFFER_SIZE as u16).to_be_bytes())
+        }
+        DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+            let offset = be16(&data[0..2]) as usize;
+            let size = (data[2] as usize).min(MAX_CHUNK);
+            for (i, byte) in data[3..3 + size].iter_mut().enumerate() {
+                *byte = ctx.keymap.macro_byte(offset + i).unwrap_or(0);
+            }
+        }
+        DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+            let offset = be16(&data[0..2]) as usize;
+            let size = (data[2] as usize).min(MAX_CHUNK);
+            for (i, &byte) in data[3..3 + size].iter().enumerate() {
+                ctx.keymap.set_macro_byte(offset + i, byte);
+            }
+            effects.save = true;
+        }
+        DYNAMIC_KEYMAP_MACRO_RESET => {
+            ctx.keymap.reset_macros();
+            effects.save = true;
+        }
+        DYNAMIC_KEYMAP_GET_LAYER_COUNT => data[0] = NUM_LAYERS as u8,
+        DYNAMIC_KEYMAP_GET_BUFFER => {
+            let offset = be16(&data[0..2]) as usize;
+            let size = (data[2] as usize).min(MAX_CHUNK);
+            for (i, byte) in data[3..3 + size].iter_mut().enumerate() {
+                let (layer, mux, chan, high) = keymap_position(offset + i);
+                let code = ctx.keymap.get(layer, mux, chan).unwrap_or(keymap::KC_NO);
+                let [hi, lo] = code.to_be_bytes();
+                *byte = if high { hi } else { lo };
+            }
+        }
+        DYNAMIC_KEYMAP_SET_BUFFER => {
+            // VIA always sends whole keycodes so odd offsets/sizes don't need handling
+            let offset = be16(&data[0..2]) as usize;
+            let size = (data[2] as usize).min(MAX_CHUNK);
+            for (i, code) in data[3..3 + size].chunks_exact(2).enumerate() {
+                let (layer, mux, chan, _) = keymap_position(offset + i * 2);
+                ctx.keymap.set(layer, mux, chan, be16(code));
+            }
+            effects.save = true;
+        }
+        _ => command[0] = UNHANDLED,
+    }
+    effects
+}